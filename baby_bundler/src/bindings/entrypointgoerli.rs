@@ -48345,7 +48345,7 @@ pub mod entrypointgoerli {
         ///Calls the contract's `simulateValidation` (0xee219423) function
         pub fn simulate_validation(
             &self,
-            user_op: UserOperation,
+            user_op: aa_bundler_primitives::UserOperation,
         ) -> ::ethers::contract::builders::ContractCall<M, ()> {
             self.0
                 .method_hash([238, 33, 148, 35], (user_op,))
@@ -0,0 +1,6 @@
+ethers::contract::abigen!(
+    Erc1271,
+    r#"[
+        function isValidSignature(bytes32 hash, bytes signature) external view returns (bytes4 magicValue)
+    ]"#
+);
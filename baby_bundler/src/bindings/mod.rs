@@ -1,3 +1,4 @@
 pub mod entrypointgoerli;
+pub mod erc1271;
 pub mod uniswap_v2_router_1;
 pub mod weth;
@@ -0,0 +1,485 @@
+use crate::bundler::{
+    rate_limit::LIMIT_EXCEEDED_CODE,
+    server::{error_response, is_websocket_upgrade, parse_calls},
+};
+use aa_bundler_primitives::UserOperation;
+use async_trait::async_trait;
+use ethers::types::U256;
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
+use jsonrpsee::{core::RpcResult, proc_macros::rpc, Methods};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::future::Future;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tower::{Layer, Service};
+
+/// Header carrying the API key, the key may also be passed as the URL path
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// JSON-RPC error code for a missing or unknown API key
+pub const UNAUTHORIZED_CODE: i32 = -32001;
+/// EIP-1474 error code for a method the API key may not call
+pub const METHOD_NOT_SUPPORTED_CODE: i32 = -32004;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// An API key handed to a partner dapp
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyConfig {
+    pub key: String,
+    /// Name the usage of the key is reported under
+    pub name: String,
+    /// Methods the key may call, every method but the `admin` namespace if not set
+    #[serde(default)]
+    pub allowed_methods: Option<HashSet<String>>,
+    /// User operations the key may submit per UTC day
+    #[serde(default)]
+    pub daily_op_quota: Option<u64>,
+    /// Wei of gas the key may have sponsored by a paymaster, counted at the
+    /// maximum cost of each user operation
+    #[serde(default)]
+    pub sponsored_gas_budget: Option<U256>,
+    /// Whether the key may call the `admin` namespace
+    #[serde(default)]
+    pub admin: bool,
+}
+
+impl ApiKeyConfig {
+    /// Whether the calls of the key have to be checked one by one, which is
+    /// only done for calls made over HTTP
+    fn is_restricted(&self) -> bool {
+        self.allowed_methods.is_some()
+            || self.daily_op_quota.is_some()
+            || self.sponsored_gas_budget.is_some()
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiKeyUsage {
+    pub name: String,
+    pub requests: u64,
+    pub ops: u64,
+    /// Day since the Unix epoch `ops_today` is counted for
+    pub day: u64,
+    pub ops_today: u64,
+    pub sponsored_gas: U256,
+}
+
+#[derive(Debug, Default)]
+struct ApiKeysState {
+    keys: HashMap<String, ApiKeyConfig>,
+    usage: HashMap<String, ApiKeyUsage>,
+}
+
+/// API keys loaded from config along with their usage counters
+#[derive(Clone, Debug, Default)]
+pub struct ApiKeys {
+    state: Arc<Mutex<ApiKeysState>>,
+}
+
+impl ApiKeys {
+    pub fn new(keys: Vec<ApiKeyConfig>) -> Self {
+        let state = ApiKeysState {
+            keys: keys.into_iter().map(|key| (key.key.clone(), key)).collect(),
+            usage: HashMap::new(),
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Reads the keys from a JSON file holding a list of `ApiKeyConfig`
+    pub fn from_file(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let keys: Vec<ApiKeyConfig> = serde_json::from_slice(&std::fs::read(path)?)?;
+        Ok(Self::new(keys))
+    }
+
+    pub fn usage(&self) -> Vec<ApiKeyUsage> {
+        self.state
+            .lock()
+            .map(|state| state.usage.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Checks that `key` may open a WebSocket connection. The calls made over
+    /// it are not checked one by one, so keys restricted to some methods or
+    /// with a quota or budget may not, and the admin namespace is not served.
+    fn authorize_upgrade(&self, key: Option<&str>) -> Result<(), (i32, String)> {
+        let state = self
+            .state
+            .lock()
+            .map_err(|_| (UNAUTHORIZED_CODE, "API keys unavailable".to_string()))?;
+        let config = key
+            .and_then(|key| state.keys.get(key))
+            .ok_or((UNAUTHORIZED_CODE, "Missing or unknown API key".to_string()))?;
+        if config.is_restricted() {
+            return Err((
+                METHOD_NOT_SUPPORTED_CODE,
+                "WebSocket is not available to API keys with restricted methods, a quota or a budget"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the calls against the permissions, quota and budget of `key` and
+    /// records them, returning the JSON-RPC error code and message otherwise
+    fn authorize(&self, key: Option<&str>, calls: &[Value]) -> Result<(), (i32, String)> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| (UNAUTHORIZED_CODE, "API keys unavailable".to_string()))?;
+        let config = key
+            .and_then(|key| state.keys.get(key))
+            .cloned()
+            .ok_or((UNAUTHORIZED_CODE, "Missing or unknown API key".to_string()))?;
+
+        let mut ops = 0;
+        let mut sponsored_gas = U256::zero();
+        for call in calls {
+            let method = call["method"].as_str().unwrap_or_default();
+            let allowed = match &config.allowed_methods {
+                _ if method.starts_with("admin_") => config.admin,
+                Some(methods) => methods.contains(method),
+                None => true,
+            };
+            if !allowed {
+                return Err((
+                    METHOD_NOT_SUPPORTED_CODE,
+                    format!("Method {method} is not allowed for this API key"),
+                ));
+            }
+
+            for user_operation in submitted_operations(method, &call["params"]) {
+                ops += 1;
+                if !user_operation.paymaster_and_data.is_empty() {
+                    sponsored_gas += max_gas_cost(&user_operation);
+                }
+            }
+        }
+
+        let today = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs() / SECONDS_PER_DAY)
+            .unwrap_or_default();
+        let usage = state
+            .usage
+            .entry(config.name.clone())
+            .or_insert_with(|| ApiKeyUsage {
+                name: config.name.clone(),
+                ..Default::default()
+            });
+        if usage.day != today {
+            usage.day = today;
+            usage.ops_today = 0;
+        }
+
+        if matches!(config.daily_op_quota, Some(quota) if usage.ops_today + ops > quota) {
+            return Err((
+                LIMIT_EXCEEDED_CODE,
+                "Daily user operation quota exceeded".to_string(),
+            ));
+        }
+        if matches!(config.sponsored_gas_budget, Some(budget) if usage.sponsored_gas + sponsored_gas > budget)
+        {
+            return Err((
+                LIMIT_EXCEEDED_CODE,
+                "Sponsored gas budget exceeded".to_string(),
+            ));
+        }
+
+        usage.requests += calls.len() as u64;
+        usage.ops += ops;
+        usage.ops_today += ops;
+        usage.sponsored_gas += sponsored_gas;
+        Ok(())
+    }
+}
+
+/// User operations submitted by a call, a schedule counting as all of its ops
+fn submitted_operations(method: &str, params: &Value) -> Vec<UserOperation> {
+    match method {
+        "eth_sendUserOperation" | "bundler_sendConditionalUserOperation" => {
+            serde_json::from_value(params[0].clone())
+                .map(|user_operation| vec![user_operation])
+                .unwrap_or_default()
+        }
+        "bundler_submitSchedule" => serde_json::from_value(params[0].clone()).unwrap_or_default(),
+        _ => vec![],
+    }
+}
+
+/// Most the entry point can charge the paymaster for a user operation
+fn max_gas_cost(user_operation: &UserOperation) -> U256 {
+    (user_operation.call_gas_limit
+        + user_operation.verification_gas_limit
+        + user_operation.pre_verification_gas)
+        * user_operation.max_fee_per_gas
+}
+
+/// Admin API to inspect the usage of the API keys
+#[rpc(server, namespace = "admin")]
+pub trait AdminApi {
+    #[method(name = "apiKeyUsage")]
+    async fn api_key_usage(&self) -> RpcResult<Vec<ApiKeyUsage>>;
+}
+
+#[async_trait]
+impl AdminApiServer for ApiKeys {
+    async fn api_key_usage(&self) -> RpcResult<Vec<ApiKeyUsage>> {
+        Ok(self.usage())
+    }
+}
+
+/// Rejects requests without a known API key, read from the `X-Api-Key` header
+/// or the URL path (`POST /<key>`). WebSocket connections are authorized on
+/// upgrade for unrestricted keys only, the calls made over them are not
+/// counted and may not reach the admin namespace.
+#[derive(Clone, Debug)]
+pub struct AuthLayer {
+    api_keys: ApiKeys,
+}
+
+impl AuthLayer {
+    pub fn new(api_keys: ApiKeys) -> Self {
+        Self { api_keys }
+    }
+
+    /// Methods served over WebSocket, all but the admin namespace
+    pub fn ws_methods(&self, methods: &Methods) -> Result<Methods, jsonrpsee::core::Error> {
+        let mut ws_methods = Methods::new();
+        for name in methods.method_names() {
+            if name.starts_with("admin_") {
+                continue;
+            }
+            if let Some(callback) = methods.method(name) {
+                ws_methods.verify_and_insert(name, callback.clone())?;
+            }
+        }
+        Ok(ws_methods)
+    }
+}
+
+impl<S> Layer<S> for AuthLayer {
+    type Service = AuthRequest<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AuthRequest {
+            inner,
+            api_keys: self.api_keys.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct AuthRequest<S> {
+    inner: S,
+    api_keys: ApiKeys,
+}
+
+impl<S> Service<Request<Body>> for AuthRequest<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Error: Into<Box<dyn Error + Send + Sync>> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Box<dyn Error + Send + Sync + 'static>;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let api_keys = self.api_keys.clone();
+
+        Box::pin(async move {
+            let upgrade = is_websocket_upgrade(&req);
+            if req.method() != Method::POST && !upgrade {
+                return inner.call(req).await.map_err(Into::into);
+            }
+
+            let key = api_key(&req);
+            // the server only answers on the root path
+            *req.uri_mut() = Uri::from_static("/");
+
+            if upgrade {
+                if let Err((code, message)) = api_keys.authorize_upgrade(key.as_deref()) {
+                    let status = if code == UNAUTHORIZED_CODE {
+                        StatusCode::UNAUTHORIZED
+                    } else {
+                        StatusCode::FORBIDDEN
+                    };
+                    return Ok(error_response(status, &[], code, &message)?);
+                }
+                return inner.call(req).await.map_err(Into::into);
+            }
+
+            let (req_h, req_b) = req.into_parts();
+            let req_bb = hyper::body::to_bytes(req_b).await?;
+            let calls = parse_calls(&req_bb);
+
+            if let Err((code, message)) = api_keys.authorize(key.as_deref(), &calls) {
+                let status = if code == UNAUTHORIZED_CODE {
+                    StatusCode::UNAUTHORIZED
+                } else {
+                    StatusCode::OK
+                };
+                return Ok(error_response(status, &calls, code, &message)?);
+            }
+
+            inner
+                .call(Request::from_parts(req_h, Body::from(req_bb)))
+                .await
+                .map_err(Into::into)
+        })
+    }
+}
+
+fn api_key(req: &Request<Body>) -> Option<String> {
+    req.headers()
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| Some(req.uri().path().trim_matches('/')).filter(|path| !path.is_empty()))
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn api_keys() -> ApiKeys {
+        let partner = ApiKeyConfig {
+            key: "partner-key".to_string(),
+            name: "partner".to_string(),
+            allowed_methods: Some(HashSet::from(["eth_sendUserOperation".to_string()])),
+            daily_op_quota: Some(2),
+            sponsored_gas_budget: None,
+            admin: false,
+        };
+        let open = ApiKeyConfig {
+            key: "open-key".to_string(),
+            name: "open".to_string(),
+            allowed_methods: None,
+            daily_op_quota: None,
+            sponsored_gas_budget: None,
+            admin: false,
+        };
+        let admin = ApiKeyConfig {
+            key: "admin-key".to_string(),
+            name: "admin".to_string(),
+            admin: true,
+            ..open.clone()
+        };
+        ApiKeys::new(vec![partner, open, admin])
+    }
+
+    fn send_user_operation() -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_sendUserOperation",
+            "params": [UserOperation::default(), "0x5ff137d4b0fdcd49dca30c7cf57e578a026d2789"],
+        })
+    }
+
+    fn call(method: &str) -> Value {
+        json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": [] })
+    }
+
+    fn usage(api_keys: &ApiKeys, name: &str) -> ApiKeyUsage {
+        api_keys
+            .usage()
+            .into_iter()
+            .find(|usage| usage.name == name)
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn authorizes_and_counts_allowed_calls() {
+        let api_keys = api_keys();
+        assert_eq!(
+            api_keys.authorize(Some("partner-key"), &[send_user_operation()]),
+            Ok(())
+        );
+
+        let usage = usage(&api_keys, "partner");
+        assert_eq!((usage.requests, usage.ops, usage.ops_today), (1, 1, 1));
+    }
+
+    #[test]
+    fn rejects_unknown_key_and_denied_method() {
+        let api_keys = api_keys();
+        assert_eq!(
+            api_keys
+                .authorize(Some("unknown"), &[call("eth_chainId")])
+                .map_err(|(code, _)| code),
+            Err(UNAUTHORIZED_CODE)
+        );
+        assert_eq!(
+            api_keys
+                .authorize(Some("partner-key"), &[call("eth_chainId")])
+                .map_err(|(code, _)| code),
+            Err(METHOD_NOT_SUPPORTED_CODE)
+        );
+        assert_eq!(usage(&api_keys, "partner").requests, 0);
+    }
+
+    #[test]
+    fn rejects_calls_over_daily_quota() {
+        let api_keys = api_keys();
+        let batch = [send_user_operation(), send_user_operation()];
+        assert_eq!(api_keys.authorize(Some("partner-key"), &batch), Ok(()));
+        assert_eq!(
+            api_keys
+                .authorize(Some("partner-key"), &[send_user_operation()])
+                .map_err(|(code, _)| code),
+            Err(LIMIT_EXCEEDED_CODE)
+        );
+        assert_eq!(usage(&api_keys, "partner").ops_today, 2);
+    }
+
+    #[test]
+    fn keeps_admin_namespace_to_admin_keys() {
+        let api_keys = api_keys();
+        assert_eq!(
+            api_keys
+                .authorize(Some("open-key"), &[call("admin_apiKeyUsage")])
+                .map_err(|(code, _)| code),
+            Err(METHOD_NOT_SUPPORTED_CODE)
+        );
+        assert_eq!(
+            api_keys.authorize(Some("admin-key"), &[call("admin_apiKeyUsage")]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn opens_websocket_for_unrestricted_keys_only() {
+        let api_keys = api_keys();
+        assert_eq!(api_keys.authorize_upgrade(Some("open-key")), Ok(()));
+        assert_eq!(
+            api_keys
+                .authorize_upgrade(Some("partner-key"))
+                .map_err(|(code, _)| code),
+            Err(METHOD_NOT_SUPPORTED_CODE)
+        );
+        assert_eq!(
+            api_keys.authorize_upgrade(None).map_err(|(code, _)| code),
+            Err(UNAUTHORIZED_CODE)
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use crate::bundler::server::error_response;
+use hyper::{body::HttpBody, Body, Method, Request, Response, StatusCode};
+use jsonrpsee::types::error::ErrorCode;
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+/// Largest request body served by default, the jsonrpsee default
+pub const DEFAULT_MAX_REQUEST_BODY_SIZE: u32 = 10 * 1024 * 1024;
+
+/// Reads the body of every POST request up to `max_size` bytes before any
+/// other layer sees it, answering 413 to larger ones. The layers that buffer
+/// the body to look at the calls are placed after this one.
+#[derive(Clone, Copy, Debug)]
+pub struct BodyLimitLayer {
+    max_size: u32,
+}
+
+impl BodyLimitLayer {
+    pub fn new(max_size: u32) -> Self {
+        Self { max_size }
+    }
+}
+
+impl<S> Layer<S> for BodyLimitLayer {
+    type Service = BodyLimitRequest<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        BodyLimitRequest {
+            inner,
+            max_size: self.max_size,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct BodyLimitRequest<S> {
+    inner: S,
+    max_size: u32,
+}
+
+impl<S> Service<Request<Body>> for BodyLimitRequest<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Error: Into<Box<dyn Error + Send + Sync>> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Box<dyn Error + Send + Sync + 'static>;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let max_size = self.max_size as usize;
+
+        Box::pin(async move {
+            if req.method() != Method::POST {
+                return inner.call(req).await.map_err(Into::into);
+            }
+
+            let (req_h, mut req_b) = req.into_parts();
+            let mut req_bb = Vec::new();
+            let mut too_large = req_b.size_hint().lower() as usize > max_size;
+            while !too_large {
+                match req_b.data().await {
+                    Some(chunk) => {
+                        req_bb.extend_from_slice(&chunk?);
+                        too_large = req_bb.len() > max_size;
+                    }
+                    None => break,
+                }
+            }
+            if too_large {
+                return Ok(error_response(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    &[],
+                    ErrorCode::InvalidRequest.code(),
+                    &format!("Request body exceeds {max_size} bytes"),
+                )?);
+            }
+
+            inner
+                .call(Request::from_parts(req_h, Body::from(req_bb)))
+                .await
+                .map_err(Into::into)
+        })
+    }
+}
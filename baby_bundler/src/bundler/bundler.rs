@@ -1,31 +1,221 @@
-use crate::bindings::entrypointgoerli::entrypointgoerli;
-use aa_bundler_primitives::{UserOperation, UserOperationHash, UserOperationReceipt, Wallet};
+use crate::bindings::entrypointgoerli::{
+    entrypointgoerli, FailedOp, UserOperationEventFilter, UserOperationRevertReasonFilter,
+    ValidationResult,
+};
+use crate::bundler::{
+    cancel,
+    events::{UserOperationEvent, UserOperationStatus, EVENTS_CAPACITY},
+    intent::{self, Condition, LogCondition},
+    mempool::{Mempool, MempoolEntry},
+    metrics::Metrics,
+    reputation::Reputation,
+    schedule::{FailurePolicy, Schedule, ScheduledOperationState, Schedules},
+    signer_pool::SignerPool,
+    store::{Changes, Store, SubmittedBundle},
+};
+use crate::sdk::gat_tx;
+use aa_bundler_primitives::{UserOperation, UserOperationHash, UserOperationReceipt};
 use async_trait::async_trait;
-use dotenv::dotenv;
 use ethers::{
+    contract::parse_log,
     prelude::LocalWallet,
-    providers::{Middleware, Provider, Ws},
-    signers::Signer,
-    types::{transaction::eip2718::TypedTransaction, Address, H160, U256, U64},
+    providers::Middleware,
+    types::{
+        transaction::eip2718::TypedTransaction, Address, BlockNumber, Bytes, Filter,
+        TransactionRequest, H160, H256, U256, U64,
+    },
 };
 use ethers_flashbots::BundleRequest;
 use jsonrpsee::http_client::{transport::Error as HttpError, HttpClientBuilder};
-use jsonrpsee::{core::RpcResult, proc_macros::rpc, tracing::info};
+use jsonrpsee::types::error::{CallError, ErrorCode, ErrorObject};
+use jsonrpsee::{
+    core::{Error as RpcError, RpcResult},
+    proc_macros::rpc,
+    tracing::info,
+};
 use mev_share_rpc_api::{
     BundleItem, FlashbotsSignerLayer, MevApiClient, Privacy, PrivacyHint, SendBundleRequest,
 };
 use serde::{Deserialize, Serialize};
-use std::env;
+use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::{
+    sync::{broadcast, Mutex, Notify},
+    task::JoinHandle,
+};
 use tower::ServiceBuilder;
+use tracing::{field, Instrument};
+
+/// How often parked user operations are checked against the latest block
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Seconds before `validUntil` after which a user operation is no longer bundled
+const VALID_UNTIL_MARGIN: u64 = 30;
+
+/// Seconds a cancellation is remembered, the same user operation is accepted
+/// again afterwards
+const CANCELLATION_RETENTION: u64 = 7 * 24 * 60 * 60;
+
+/// Seconds after which the reputation of an entity in good standing that was
+/// not seen again is forgotten
+const REPUTATION_RETENTION: u64 = 24 * 60 * 60;
+
+/// Failed executions of a due user operation before it is dropped
+const MAX_EXECUTION_ATTEMPTS: u32 = 3;
+
+/// ERC-4337 error code for user operations rejected by `simulateValidation`
+const VALIDATION_FAILED_CODE: i32 = -32500;
+/// ERC-4337 error code for user operations that are expired or expire too soon
+const OUT_OF_TIME_RANGE_CODE: i32 = -32503;
+/// ERC-4337 error code for user operations of a banned or throttled entity
+const BANNED_OR_THROTTLED_CODE: i32 = -32504;
+/// ERC-4337 error code for a wallet signature check that failed
+const SIGNATURE_CHECK_FAILED_CODE: i32 = -32507;
+
+/// Entry point the bundler submits user operations to
+pub const ENTRY_POINT_ADDRESS: &str = "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789";
+
+/// Flashbots relay bundles are sent to
+pub(crate) const FLASHBOTS_RELAY_URL: &str = "https://relay.flashbots.net:443";
+
+/// Blocks after which a bundle that has not landed is no longer tracked
+const INCLUSION_TIMEOUT_BLOCKS: u64 = 25;
+
+/// Blocks searched back from the head for the `UserOperationEvent` of an op
+const RECEIPT_LOOKBACK_BLOCKS: u64 = 10_000;
+
+pub(crate) fn rpc_error(code: i32, message: impl Into<String>) -> RpcError {
+    RpcError::Call(CallError::Custom(ErrorObject::owned(
+        code,
+        message.into(),
+        None::<()>,
+    )))
+}
+
+/// Label of the `ops_rejected` metric for an error returned on submission
+fn rejection_reason(err: &RpcError) -> &'static str {
+    match err {
+        RpcError::Call(CallError::Custom(error)) => match error.code() {
+            VALIDATION_FAILED_CODE => "validation_failed",
+            OUT_OF_TIME_RANGE_CODE => "out_of_time_range",
+            BANNED_OR_THROTTLED_CODE => "banned_or_throttled",
+            _ => "other",
+        },
+        _ => "other",
+    }
+}
+
+/// Work that takes user operations out of the mempool to bundle them, waited
+/// for on shutdown so that no operation is lost mid-submission
+#[derive(Debug, Default)]
+struct InFlight {
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+impl InFlight {
+    fn enter(self: &Arc<Self>) -> InFlightGuard {
+        self.count.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(self.clone())
+    }
+
+    async fn wait_idle(&self) {
+        loop {
+            let idle = self.idle.notified();
+            if self.count.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+}
+
+struct InFlightGuard(Arc<InFlight>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// State left behind by a shutdown
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShutdownSummary {
+    /// Whether every in-flight submission finished before the timeout
+    pub drained: bool,
+    /// Submissions abandoned at the timeout
+    pub abandoned: usize,
+    pub parked: usize,
+    pub conditional: usize,
+    pub ready: usize,
+    pub scheduled: usize,
+    /// Bundles submitted but not seen on chain yet
+    pub pending_bundles: usize,
+}
+
+/// A `handleOps` transaction sent to a relay and not seen on chain yet
+#[derive(Clone, Debug)]
+struct PendingBundle {
+    tx_hash: H256,
+    /// Key the `handleOps` transaction was signed with
+    signer: Address,
+    relay: &'static str,
+    submitted_block: U64,
+    origin: BundleOrigin,
+}
+
+/// Where the user operations of a bundle were taken from, to put them back if
+/// the bundle never lands
+#[derive(Clone, Debug, Default)]
+pub(crate) struct BundleOrigin {
+    /// Mempool entries, parked again
+    entries: Vec<(UserOperationHash, MempoolEntry)>,
+    /// Schedules whose next user operation is in the bundle
+    schedules: Vec<H256>,
+}
+
+/// Where the `handleOps` transactions are sent
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Relay {
+    /// Private bundle to the Flashbots relay, signed with the Flashbots identity
+    #[default]
+    Flashbots,
+    /// Raw transaction to the node the bundler is connected to, for local chains
+    Node,
+}
+
+impl Relay {
+    /// Label of the relay in the metrics and traces
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Relay::Flashbots => "flashbots",
+            Relay::Node => "node",
+        }
+    }
+}
+
+/// Whether executable user operations are bundled right away or only on
+/// `debug_bundler_sendBundleNow`
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum BundlingMode {
+    #[default]
+    Auto,
+    Manual,
+}
 
 /// A simplified bundler implementation based on AA-Bundler
 /// https://github.com/Vid201/aa-bundler
 pub struct BabyBundler<M: Middleware> {
     /// The Provider that connects to Goerli
     pub eth_provider: Arc<M>,
-    /// Goerli Chain ID
+    /// Chain the user operations are hashed and the bundles signed for
     pub eth_chain_id: U64,
     /// Entry point address
     pub entry_point: Address,
@@ -33,8 +223,70 @@ pub struct BabyBundler<M: Middleware> {
     pub max_verification_gas: U256,
     /// Call gas Limit
     pub call_gas_limit: U256,
-    /// Bundler wallet
-    pub wallet: Wallet,
+    /// Keys of the bundler EOAs sending the bundles
+    pub signers: Arc<SignerPool>,
+    /// Key authenticating the bundles with the Flashbots relay
+    pub flashbots_identity: Option<LocalWallet>,
+    /// Where the bundles are sent
+    pub relay: Relay,
+    /// Account the entry point pays the bundle fees to, the bundler EOA by default
+    pub beneficiary: Address,
+    /// User operations parked until they become executable
+    pub mempool: Arc<Mutex<Mempool>>,
+    /// Entries taken out of the mempool for a bundle being sent, saved along
+    /// with the mempool until the bundle is submitted or they are put back
+    releasing: Arc<Mutex<HashMap<UserOperationHash, MempoolEntry>>>,
+    /// Schedules of pre-signed user operations released one by one
+    pub schedules: Arc<Mutex<Schedules>>,
+    /// Reputation of the entities of the user operations seen
+    pub reputation: Arc<Mutex<Reputation>>,
+    /// Current bundling mode
+    pub bundling_mode: Arc<Mutex<BundlingMode>>,
+    /// Store the state is saved to so that it survives restarts
+    pub store: Option<Arc<Store>>,
+    /// Held while the state is snapshotted and saved, so that an older
+    /// snapshot is never written over a newer one
+    persisting: Arc<Mutex<()>>,
+    /// Prometheus metrics
+    pub metrics: Arc<Metrics>,
+    /// Balance below which the bundler EOA is reported as not ready
+    pub min_balance: U256,
+    /// Bundles waiting to be seen on chain
+    pending_bundles: Arc<Mutex<Vec<PendingBundle>>>,
+    /// Status changes of the user operations, pushed to `eth_subscribe` subscribers
+    pub events: broadcast::Sender<UserOperationEvent>,
+    /// Set on shutdown, no user operation is accepted or bundled afterwards
+    draining: Arc<AtomicBool>,
+    in_flight: Arc<InFlight>,
+}
+
+impl<M: Middleware> Clone for BabyBundler<M> {
+    fn clone(&self) -> Self {
+        Self {
+            eth_provider: self.eth_provider.clone(),
+            eth_chain_id: self.eth_chain_id,
+            entry_point: self.entry_point,
+            max_verification_gas: self.max_verification_gas,
+            call_gas_limit: self.call_gas_limit,
+            signers: self.signers.clone(),
+            flashbots_identity: self.flashbots_identity.clone(),
+            relay: self.relay,
+            beneficiary: self.beneficiary,
+            mempool: self.mempool.clone(),
+            releasing: self.releasing.clone(),
+            schedules: self.schedules.clone(),
+            reputation: self.reputation.clone(),
+            bundling_mode: self.bundling_mode.clone(),
+            store: self.store.clone(),
+            persisting: self.persisting.clone(),
+            metrics: self.metrics.clone(),
+            min_balance: self.min_balance,
+            pending_bundles: self.pending_bundles.clone(),
+            events: self.events.clone(),
+            draining: self.draining.clone(),
+            in_flight: self.in_flight.clone(),
+        }
+    }
 }
 
 impl<M> BabyBundler<M>
@@ -46,136 +298,1091 @@ where
         eth_provider: Arc<M>,
         max_verification_gas: U256,
         call_gas_limit: U256,
-        wallet: Wallet,
+        signers: SignerPool,
     ) -> Self {
-        // let bundle_signer = env::var("FLASHBOTS_IDENTIFIER").unwrap_or_else(|e| {
-        //     panic!("Please set the FLASHBOTS_IDENTIFIER environment variable");
-        // });
         Self {
             eth_provider,
-            eth_chain_id: U64::from(5),
-            entry_point: H160::from_str("0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789").unwrap(),
+            eth_chain_id: U64::from(80001),
+            entry_point: H160::from_str(ENTRY_POINT_ADDRESS).unwrap(),
             max_verification_gas,
             call_gas_limit,
-            wallet,
+            beneficiary: signers.primary().address(),
+            signers: Arc::new(signers),
+            flashbots_identity: None,
+            relay: Relay::default(),
+            mempool: Arc::new(Mutex::new(Mempool::default())),
+            releasing: Arc::new(Mutex::new(HashMap::new())),
+            schedules: Arc::new(Mutex::new(Schedules::default())),
+            reputation: Arc::new(Mutex::new(Reputation::default())),
+            bundling_mode: Arc::new(Mutex::new(BundlingMode::default())),
+            store: None,
+            persisting: Arc::new(Mutex::new(())),
+            metrics: Arc::new(Metrics::new()),
+            min_balance: U256::zero(),
+            pending_bundles: Arc::new(Mutex::new(vec![])),
+            events: broadcast::channel(EVENTS_CAPACITY).0,
+            draining: Arc::new(AtomicBool::new(false)),
+            in_flight: Arc::new(InFlight::default()),
         }
     }
-}
 
-/// Eth API trait ported from AA-Bundler
-///  https://github.com/Vid201/aa-bundler/blob/main/crates/rpc/src/eth_api.rs
-#[derive(Serialize, Deserialize, Clone)]
-pub struct EstimateUserOperationGasResponse {
-    pub pre_verification_gas: U256,
-    pub verification_gas_limit: U256,
-    pub call_gas_limit: U256,
-}
+    /// Chain the node is on, as returned by `eth_chainId`, Mumbai if not set
+    pub fn with_chain_id(mut self, chain_id: U64) -> Self {
+        self.eth_chain_id = chain_id;
+        self
+    }
 
-#[rpc(server, namespace = "eth")]
-pub trait EthApi {
-    #[method(name = "chainId")]
-    async fn chain_id(&self) -> RpcResult<U64>;
-    #[method(name = "supportedEntryPoints")]
-    async fn supported_entry_points(&self) -> RpcResult<Vec<Address>>;
-    #[method(name = "sendUserOperation")]
-    async fn send_user_operation(
-        &self,
-        user_operation: UserOperation,
-        entry_point: Address,
-    ) -> RpcResult<UserOperationHash>;
-    #[method(name = "estimateUserOperationGas")]
-    async fn estimate_user_operation_gas(
-        &self,
-        user_operation: UserOperation,
-        entry_point: Address,
-    ) -> RpcResult<EstimateUserOperationGasResponse>;
-    #[method(name = "getUserOperationReceipt")]
-    async fn get_user_operation_receipt(
+    pub fn with_min_balance(mut self, min_balance: U256) -> Self {
+        self.min_balance = min_balance;
+        self
+    }
+
+    pub fn with_flashbots_identity(mut self, flashbots_identity: LocalWallet) -> Self {
+        self.flashbots_identity = Some(flashbots_identity);
+        self
+    }
+
+    pub fn with_relay(mut self, relay: Relay) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    /// Submits to the entry point at `entry_point` instead of `ENTRY_POINT_ADDRESS`
+    pub fn with_entry_point(mut self, entry_point: Address) -> Self {
+        self.entry_point = entry_point;
+        self
+    }
+
+    /// Pays the bundle fees to `beneficiary` instead of the bundler EOA
+    pub fn with_beneficiary(mut self, beneficiary: Address) -> Self {
+        self.beneficiary = beneficiary;
+        self
+    }
+
+    pub fn with_store(mut self, store: Store) -> Self {
+        self.store = Some(Arc::new(store));
+        self
+    }
+
+    /// Reloads the state saved in the store and re-validates every parked user
+    /// operation and schedule against the latest block, dropping what no longer
+    /// passes. Must run before the server accepts traffic.
+    pub async fn restore(&self) -> anyhow::Result<()> {
+        let Some(store) = self.store.clone() else {
+            return Ok(());
+        };
+        self.reputation.lock().await.set(store.reputation()?);
+        self.mempool
+            .lock()
+            .await
+            .set_cancellations(store.cancellations()?);
+
+        // user operations already sent to the relay must not be bundled again
+        let bundled: HashMap<UserOperationHash, H256> = store
+            .bundles()?
+            .into_iter()
+            .flat_map(|bundle| {
+                let bundle_hash = bundle.bundle_hash;
+                bundle
+                    .user_operations
+                    .into_iter()
+                    .map(move |hash| (hash, bundle_hash))
+            })
+            .collect();
+
+        let timestamp = self.latest_timestamp().await?;
+        self.mempool
+            .lock()
+            .await
+            .expire_cancellations(timestamp.saturating_sub(CANCELLATION_RETENTION));
+        let mut parked = 0;
+        for (hash, entry) in store.mempool()? {
+            // written back if parked again, removed from the store otherwise
+            self.mempool.lock().await.touch(hash);
+            if bundled.contains_key(&hash) || entry.expires_before(timestamp + VALID_UNTIL_MARGIN) {
+                continue;
+            }
+            if let Err(err) = self.simulate_validation(&entry.user_operation).await {
+                log::warn!("Dropping stored user operation {:?}: {:?}", hash, err);
+                continue;
+            }
+            self.mempool.lock().await.park(hash, entry);
+            parked += 1;
+        }
+
+        let schedules = store.schedules()?;
+        let schedule_count = schedules.len();
+        for mut schedule in schedules {
+            // catch up with the user operations bundled right before the restart,
+            // the inclusion of the last one sent is not tracked across restarts
+            schedule.roll_back();
+            while let Some(bundle_hash) = schedule
+                .due(u64::MAX)
+                .and_then(|operation| bundled.get(&operation.hash))
+                .copied()
+            {
+                schedule.advance(ScheduledOperationState::Submitted { bundle_hash });
+            }
+
+            let failure = match schedule.due(u64::MAX) {
+                Some(operation) => self
+                    .simulate_validation(&operation.user_operation)
+                    .await
+                    .err(),
+                None => None,
+            };
+            if let Some(err) = failure {
+                schedule.advance(ScheduledOperationState::Failed {
+                    reason: err.to_string(),
+                });
+            }
+            self.schedules.lock().await.insert(schedule);
+        }
+
+        log::info!(
+            "Restored {} parked user operations and {} schedules",
+            parked,
+            schedule_count
+        );
+        self.persist().await;
+        Ok(())
+    }
+
+    /// Saves the mempool entries, schedules and reputation changed since the
+    /// last save to the store, if any
+    pub(crate) async fn persist(&self) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        let _persisting = self.persisting.lock().await;
+        let (entries, cancellations) = {
+            let mut mempool = self.mempool.lock().await;
+            let (mut entries, cancellations) = mempool.take_changes();
+            // restored on restart if the bundle never made it to the relay
+            let releasing = self.releasing.lock().await;
+            for (hash, entry) in entries.iter_mut() {
+                if entry.is_none() {
+                    *entry = releasing.get(hash).cloned();
+                }
+            }
+            (entries, cancellations)
+        };
+        let changes = Changes {
+            entries,
+            cancellations,
+            schedules: self.schedules.lock().await.take_changes(),
+            reputation: self.reputation.lock().await.take_changes(),
+        };
+
+        if changes.is_empty() {
+            return;
+        }
+        if let Err(err) = store.save(&changes) {
+            log::warn!("Failed to persist bundler state: {:?}", err);
+        }
+    }
+
+    /// Spawns the task that checks parked user operations on every new block
+    /// and releases the ones whose good-after time or condition is met
+    pub fn spawn_scheduler(&self) -> JoinHandle<()> {
+        let bundler = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SCHEDULER_INTERVAL);
+            let mut last_seen = None;
+            let mut last_block = None;
+            loop {
+                interval.tick().await;
+                let _in_flight = bundler.in_flight.enter();
+                if bundler.draining.load(Ordering::SeqCst) {
+                    break;
+                }
+                match bundler.latest_block().await {
+                    Ok((number, timestamp)) if last_seen != Some(number) => {
+                        last_seen = Some(number);
+                        // bundles sent in manual mode land too
+                        if let Err(err) = bundler.track_inclusion(number).await {
+                            log::warn!("Failed to track bundle inclusion: {:?}", err);
+                        }
+
+                        if *bundler.bundling_mode.lock().await == BundlingMode::Auto {
+                            // cover every block since the last successful release, the
+                            // blocks of a failed one are checked for logs again
+                            let from_block = last_block.map_or(number, |last: U64| last + 1);
+                            match bundler
+                                .release_due_operations(timestamp, from_block, number)
+                                .await
+                            {
+                                Ok(_) => last_block = Some(number),
+                                Err(err) => log::warn!(
+                                    "Failed to release parked user operations: {:?}",
+                                    err
+                                ),
+                            }
+                            bundler.persist().await;
+                        }
+                        if let Err(err) = bundler.update_metrics().await {
+                            log::warn!("Failed to update metrics: {:?}", err);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => log::warn!("Failed to fetch latest block: {:?}", err),
+                }
+            }
+        })
+    }
+
+    /// Stops accepting and bundling user operations, waits up to `timeout` for
+    /// the bundles being submitted and saves what is left to the store. A
+    /// bundle still in flight at the timeout is abandoned, its operations
+    /// are saved with the mempool and validated again on restart.
+    pub async fn shutdown(&self, timeout: Duration) -> ShutdownSummary {
+        self.draining.store(true, Ordering::SeqCst);
+        let drained = tokio::time::timeout(timeout, self.in_flight.wait_idle())
+            .await
+            .is_ok();
+        self.persist().await;
+        if let Some(store) = &self.store {
+            if let Err(err) = store.flush() {
+                log::warn!("Failed to flush the store: {:?}", err);
+            }
+        }
+
+        let (parked, conditional, ready) = self.mempool.lock().await.counts();
+        ShutdownSummary {
+            drained,
+            abandoned: self.in_flight.count.load(Ordering::SeqCst),
+            parked,
+            conditional,
+            ready,
+            scheduled: self.schedules.lock().await.pending_count(),
+            pending_bundles: self.pending_bundles.lock().await.len(),
+        }
+    }
+
+    /// Rejects any entry point other than the one the bundles are sent to,
+    /// whose address the user operation hashes are computed with
+    pub(crate) fn check_entry_point(&self, entry_point: Address) -> RpcResult<()> {
+        if entry_point != self.entry_point {
+            return Err(rpc_error(
+                ErrorCode::InvalidParams.code(),
+                format!("Unsupported entry point {:?}", entry_point),
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_accepting(&self) -> RpcResult<()> {
+        if self.draining.load(Ordering::SeqCst) {
+            return Err(rpc_error(
+                ErrorCode::ServerIsBusy.code(),
+                "Bundler is shutting down",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Refreshes the mempool and balance gauges
+    async fn update_metrics(&self) -> anyhow::Result<()> {
+        let (parked, conditional, ready) = self.mempool.lock().await.counts();
+        let scheduled = self.schedules.lock().await.pending_count();
+        for (state, count) in [
+            ("parked", parked),
+            ("conditional", conditional),
+            ("ready", ready),
+            ("scheduled", scheduled),
+        ] {
+            self.metrics
+                .mempool_size
+                .with_label_values(&[state])
+                .set(count as i64);
+        }
+
+        let balances = self
+            .signers
+            .refresh_balances(self.eth_provider.as_ref())
+            .await?;
+        let mut total_balance = U256::zero();
+        for (address, balance) in balances {
+            total_balance += balance;
+            self.metrics
+                .signer_balance
+                .with_label_values(&[&format!("{address:?}")])
+                .set(wei_to_f64(balance));
+        }
+        self.metrics.eoa_balance.set(wei_to_f64(total_balance));
+        Ok(())
+    }
+
+    /// Records the bundles that landed on chain since the last block, settling
+    /// the lease of their key, moving their schedules on and notifying the
+    /// subscribers of their user operations. Bundles that time out free their
+    /// key's nonce again and their user operations are released again.
+    async fn track_inclusion(&self, block_number: U64) -> anyhow::Result<()> {
+        let pending = std::mem::take(&mut *self.pending_bundles.lock().await);
+        let mut still_pending = vec![];
+        let mut failure = None;
+        for bundle in pending {
+            let receipt = match self
+                .eth_provider
+                .get_transaction_receipt(bundle.tx_hash)
+                .await
+            {
+                Ok(receipt) => receipt,
+                Err(err) => {
+                    // looked up again on the next block
+                    still_pending.push(bundle);
+                    failure = Some(err);
+                    continue;
+                }
+            };
+            let Some(receipt) = receipt else {
+                if bundle.submitted_block + INCLUSION_TIMEOUT_BLOCKS > block_number {
+                    still_pending.push(bundle);
+                } else {
+                    log::warn!("Bundle {:?} timed out", bundle.tx_hash);
+                    self.signers.settle(bundle.signer, false).await;
+                    self.requeue(bundle.origin).await;
+                }
+                continue;
+            };
+            self.signers.settle(bundle.signer, true).await;
+            let mut schedules = self.schedules.lock().await;
+            for id in bundle.origin.schedules.iter() {
+                if let Some(schedule) = schedules.get_mut(id) {
+                    schedule.confirm();
+                }
+            }
+            drop(schedules);
+
+            self.metrics
+                .bundles_included
+                .with_label_values(&[bundle.relay])
+                .inc();
+            if let Some(included_block) = receipt.block_number {
+                self.metrics.inclusion_latency.observe(
+                    included_block
+                        .saturating_sub(bundle.submitted_block)
+                        .as_u64() as f64,
+                );
+            }
+            let gas_cost = receipt.gas_used.unwrap_or_default()
+                * receipt.effective_gas_price.unwrap_or_default();
+            self.metrics.gas_spent.inc_by(wei_to_f64(gas_cost));
+
+            for log in receipt.logs {
+                if let Ok(event) = parse_log::<UserOperationEventFilter>(log) {
+                    self.metrics.ops_included.inc();
+                    self.metrics
+                        .beneficiary_revenue
+                        .inc_by(wei_to_f64(event.actual_gas_cost));
+                    self.emit(
+                        UserOperationHash(H256::from(event.user_op_hash)),
+                        event.sender,
+                        UserOperationStatus::Included {
+                            transaction_hash: receipt.transaction_hash,
+                            success: event.success,
+                        },
+                    );
+                }
+            }
+        }
+        self.pending_bundles.lock().await.extend(still_pending);
+        match failure {
+            Some(err) => Err(anyhow::anyhow!("Failed to fetch bundle receipt: {err}")),
+            None => Ok(()),
+        }
+    }
+
+    /// Parks the mempool entries of a bundle that never landed again, unless they
+    /// were cancelled meanwhile, and makes the user operations of its schedules
+    /// due again
+    async fn requeue(&self, origin: BundleOrigin) {
+        let mut mempool = self.mempool.lock().await;
+        for (hash, entry) in origin.entries {
+            if mempool.is_cancelled(&hash) {
+                continue;
+            }
+            self.emit(
+                hash,
+                entry.user_operation.sender,
+                UserOperationStatus::Parked {
+                    good_after: entry.good_after,
+                },
+            );
+            mempool.park(hash, entry);
+        }
+        drop(mempool);
+
+        let mut schedules = self.schedules.lock().await;
+        for id in origin.schedules {
+            if let Some(schedule) = schedules.get_mut(&id) {
+                schedule.roll_back();
+            }
+        }
+    }
+
+    /// Looks up the `UserOperationEvent` of a user operation in the last
+    /// `RECEIPT_LOOKBACK_BLOCKS` blocks and the receipt of the bundle it landed
+    /// in, none if it is not on chain yet. The logs of the op are those emitted
+    /// after the event of the previous op of the bundle.
+    async fn user_operation_receipt(
         &self,
         user_operation_hash: UserOperationHash,
-    ) -> RpcResult<Option<UserOperationReceipt>>;
-}
+    ) -> anyhow::Result<Option<UserOperationReceipt>> {
+        let head = self.eth_provider.get_block_number().await?;
+        let filter = Filter::new()
+            .address(self.entry_point)
+            .topic0(UserOperationEventFilter::signature())
+            .topic1(user_operation_hash.0)
+            .from_block(head.saturating_sub(RECEIPT_LOOKBACK_BLOCKS.into()));
+        let Some(event_log) = self.eth_provider.get_logs(&filter).await?.pop() else {
+            return Ok(None);
+        };
+        let Some(tx_hash) = event_log.transaction_hash else {
+            return Ok(None);
+        };
+        let Some(tx_receipt) = self.eth_provider.get_transaction_receipt(tx_hash).await? else {
+            return Ok(None);
+        };
+        let event = parse_log::<UserOperationEventFilter>(event_log.clone())?;
 
-#[async_trait]
-impl<M> EthApiServer for BabyBundler<M>
-where
-    M: Middleware + 'static,
-    M::Provider: Send + Sync,
-{
-    async fn chain_id(&self) -> RpcResult<U64> {
-        Ok(U64::from(80001))
+        let event_index = event_log.log_index.unwrap_or_default();
+        let start = tx_receipt
+            .logs
+            .iter()
+            .filter(|log| log.log_index.unwrap_or_default() < event_index)
+            .filter(|log| log.topics.first() == Some(&UserOperationEventFilter::signature()))
+            .filter_map(|log| log.log_index)
+            .max();
+        let logs: Vec<_> = tx_receipt
+            .logs
+            .iter()
+            .filter(|log| {
+                let index = log.log_index.unwrap_or_default();
+                index < event_index && start.is_none_or(|start| index > start)
+            })
+            .cloned()
+            .collect();
+        let reason = logs
+            .iter()
+            .find_map(|log| parse_log::<UserOperationRevertReasonFilter>(log.clone()).ok())
+            .map(|revert| revert.revert_reason.to_string())
+            .unwrap_or_default();
+
+        Ok(Some(UserOperationReceipt {
+            user_operation_hash,
+            sender: event.sender,
+            nonce: event.nonce,
+            paymaster: event.paymaster,
+            actual_gas_cost: event.actual_gas_cost,
+            actual_gas_used: event.actual_gas_used,
+            success: event.success,
+            reason,
+            logs,
+            tx_receipt,
+        }))
     }
 
-    async fn supported_entry_points(&self) -> RpcResult<Vec<Address>> {
-        Ok(vec![H160::from_str(
-            "0x5FF137D4b0FDCD49DcA30c7CF57E578a026d2789",
+    /// Returns the number and timestamp of the latest block
+    pub(crate) async fn latest_block(&self) -> anyhow::Result<(U64, u64)> {
+        let block = self
+            .eth_provider
+            .get_block(BlockNumber::Latest)
+            .await?
+            .ok_or(anyhow::anyhow!("Latest block not found"))?;
+        Ok((block.number.unwrap_or_default(), block.timestamp.as_u64()))
+    }
+
+    async fn latest_timestamp(&self) -> anyhow::Result<u64> {
+        Ok(self.latest_block().await?.1)
+    }
+
+    /// Re-simulates every parked user operation that is due or whose condition
+    /// holds and bundles the ones that now execute successfully together with
+    /// the ones queued in manual bundling mode, returning the bundle hash
+    #[tracing::instrument(skip(self))]
+    pub(crate) async fn release_due_operations(
+        &self,
+        timestamp: u64,
+        from_block: U64,
+        to_block: U64,
+    ) -> anyhow::Result<Option<H256>> {
+        // the logs of every log condition not latched yet are fetched in a single
+        // query before anything is taken out of the mempool, a failure leaves the
+        // blocks to the next release
+        let conditional = self.mempool.lock().await.conditional();
+        let log_conditions: Vec<&LogCondition> = conditional
+            .iter()
+            .filter(|(_, entry)| !entry.condition_met)
+            .filter_map(|(_, entry)| match &entry.condition {
+                Some(Condition::Log(condition)) => Some(condition),
+                _ => None,
+            })
+            .collect();
+        let logs = intent::fetch_logs(
+            self.eth_provider.clone(),
+            &log_conditions,
+            from_block,
+            to_block,
         )
-        .unwrap()])
+        .await?;
+
+        let mut mempool = self.mempool.lock().await;
+        for (hash, entry) in mempool.evict_expired(timestamp + VALID_UNTIL_MARGIN) {
+            log::info!("Evicted expired user operation {:?}", hash);
+            self.emit(
+                hash,
+                entry.user_operation.sender,
+                UserOperationStatus::Failed {
+                    reason: "Expired".to_string(),
+                },
+            );
+        }
+        mempool.expire_cancellations(timestamp.saturating_sub(CANCELLATION_RETENTION));
+        let ready = mempool.take_ready();
+        let due = mempool.take_due(timestamp);
+        let conditional: Vec<_> = conditional
+            .into_iter()
+            .filter(|(hash, _)| mempool.get(hash).is_some())
+            .collect();
+        self.releasing
+            .lock()
+            .await
+            .extend(ready.iter().chain(due.iter()).cloned());
+        drop(mempool);
+        self.reputation
+            .lock()
+            .await
+            .prune(timestamp.saturating_sub(REPUTATION_RETENTION));
+
+        // entries taken out of the mempool, put back if the bundle is not sent
+        let mut released = vec![];
+        for (hash, mut entry) in due {
+            match self
+                .simulate_execution(&entry.user_operation, entry.entry_point)
+                .await
+            {
+                Ok(()) => {
+                    tracing::info!(user_operation_hash = ?hash, "Releasing parked user operation");
+                    released.push((hash, entry));
+                }
+                // the bundler holds the op until `good_after` itself, the deployed
+                // `GatTx.preHook` does not read the trailer (see `sdk::gat_tx`), so a
+                // failure here is usually a lagging block timestamp or state the op
+                // depends on, retry on the next blocks before giving up
+                Err(err) if entry.failed_attempts + 1 < MAX_EXECUTION_ATTEMPTS => {
+                    log::info!(
+                        "Parked user operation {:?} failed to execute, retrying: {:?}",
+                        hash,
+                        err
+                    );
+                    entry.failed_attempts += 1;
+                    let mut mempool = self.mempool.lock().await;
+                    self.releasing.lock().await.remove(&hash);
+                    mempool.park(hash, entry);
+                }
+                Err(err) => {
+                    log::warn!("Dropping parked user operation {:?}: {:?}", hash, err);
+                    let mut mempool = self.mempool.lock().await;
+                    self.releasing.lock().await.remove(&hash);
+                    mempool.touch(hash);
+                    drop(mempool);
+                    self.emit(
+                        hash,
+                        entry.user_operation.sender,
+                        UserOperationStatus::Failed {
+                            reason: err.to_string(),
+                        },
+                    );
+                }
+            }
+        }
+
+        for (hash, entry) in conditional {
+            let Some(condition) = &entry.condition else {
+                continue;
+            };
+            // a log is only looked up in the blocks it lands in, so latching
+            // conditions are evaluated before the op is due too
+            let due = entry.good_after < timestamp;
+            if !due && !condition.latches() {
+                continue;
+            }
+            let met = entry.condition_met
+                || match condition.is_met(self.eth_provider.clone(), &logs).await {
+                    Ok(met) => met,
+                    Err(err) => {
+                        log::warn!("Failed to evaluate condition of {:?}: {:?}", hash, err);
+                        false
+                    }
+                };
+            if !met {
+                continue;
+            }
+            if condition.latches() && !entry.condition_met {
+                tracing::info!(user_operation_hash = ?hash, "Condition latched");
+                self.mempool.lock().await.latch_condition(&hash);
+            }
+
+            // keep the op parked if it is not due or does not execute yet, a
+            // price condition is checked again on the next block until its deadline
+            if due
+                && self
+                    .simulate_execution(&entry.user_operation, entry.entry_point)
+                    .await
+                    .is_ok()
+            {
+                let mut mempool = self.mempool.lock().await;
+                if let Some(entry) = mempool.remove(&hash) {
+                    tracing::info!(user_operation_hash = ?hash, "Condition met");
+                    self.releasing.lock().await.insert(hash, entry.clone());
+                    released.push((hash, entry));
+                }
+            }
+        }
+
+        let mut user_operations: Vec<UserOperation> = ready
+            .iter()
+            .chain(released.iter())
+            .map(|(_, entry)| entry.user_operation.clone())
+            .collect();
+        let mut scheduled = vec![];
+        let due_schedules = self.schedules.lock().await.due(timestamp);
+        for schedule in due_schedules {
+            let Some(operation) = schedule.due(timestamp) else {
+                continue;
+            };
+            let user_operation = &operation.user_operation;
+
+            // an op that fails validation can never be included, whatever the policy
+            if let Err(err) = self.simulate_validation(user_operation).await {
+                self.advance_schedule(
+                    schedule.id,
+                    ScheduledOperationState::Failed {
+                        reason: err.to_string(),
+                    },
+                )
+                .await;
+                continue;
+            }
+
+            let skip_reason = match self
+                .simulate_execution(user_operation, schedule.entry_point)
+                .await
+            {
+                Ok(()) => None,
+                Err(err) if schedule.policy == FailurePolicy::Skip => Some(err.to_string()),
+                Err(err) => {
+                    self.advance_schedule(
+                        schedule.id,
+                        ScheduledOperationState::Failed {
+                            reason: err.to_string(),
+                        },
+                    )
+                    .await;
+                    continue;
+                }
+            };
+            user_operations.push(user_operation.clone());
+            scheduled.push((schedule.id, skip_reason));
+        }
+
+        if user_operations.is_empty() {
+            return Ok(None);
+        }
+
+        let origin = BundleOrigin {
+            entries: ready.iter().chain(released.iter()).cloned().collect(),
+            schedules: scheduled.iter().map(|(id, _)| *id).collect(),
+        };
+        let submitted = self.send_bundle(user_operations, origin).await;
+        // the entries are either in the stored bundle or back in the mempool
+        let mut mempool = self.mempool.lock().await;
+        let mut releasing = self.releasing.lock().await;
+        for (hash, _) in ready.iter().chain(released.iter()) {
+            releasing.remove(hash);
+            mempool.touch(*hash);
+        }
+        let bundle_hash = match submitted {
+            Ok(bundle_hash) => bundle_hash,
+            Err(err) => {
+                // the schedules were not marked, only the mempool entries need to
+                // be put back to be retried on the next block
+                for (hash, entry) in ready {
+                    mempool.push_ready(hash, entry);
+                }
+                for (hash, entry) in released {
+                    mempool.park(hash, entry);
+                }
+                return Err(err);
+            }
+        };
+        drop(releasing);
+        drop(mempool);
+        log::info!(
+            "Released parked user operations in bundle {:?}",
+            bundle_hash
+        );
+
+        // the schedules move on once the bundle lands
+        let mut schedules = self.schedules.lock().await;
+        for (id, skip_reason) in scheduled {
+            let state = match skip_reason {
+                Some(reason) => ScheduledOperationState::Skipped {
+                    bundle_hash,
+                    reason,
+                },
+                None => ScheduledOperationState::Submitted { bundle_hash },
+            };
+            if let Some(schedule) = schedules.get_mut(&id) {
+                log::info!("Schedule {:?} operation {}: {:?}", id, schedule.next, state);
+                schedule.mark_submitted(state);
+            }
+        }
+        Ok(Some(bundle_hash))
     }
 
-    #[allow(clippy::vec_init_then_push)]
-    async fn send_user_operation(
+    /// Records the outcome of the next user operation of a schedule
+    async fn advance_schedule(&self, id: H256, state: ScheduledOperationState) {
+        if let Some(schedule) = self.schedules.lock().await.get_mut(&id) {
+            log::info!("Schedule {:?} operation {}: {:?}", id, schedule.next, state);
+            // bundled ops are reported by send_bundle
+            if let (Some(operation), ScheduledOperationState::Failed { reason }) =
+                (schedule.operations.get(schedule.next), &state)
+            {
+                self.emit(
+                    operation.hash,
+                    operation.user_operation.sender,
+                    UserOperationStatus::Failed {
+                        reason: reason.clone(),
+                    },
+                );
+            }
+            schedule.advance(state);
+        }
+    }
+
+    /// Validates a user operation and either bundles it right away or parks it
+    /// until its good-after time has passed and its condition holds
+    async fn add_user_operation(
         &self,
         user_operation: UserOperation,
         entry_point: Address,
+        condition: Option<Condition>,
     ) -> RpcResult<UserOperationHash> {
-        dotenv().ok();
-        let goerli_url = env::var("WSS_RPC").expect("WSS_RPC not set");
-
-        let provider = Arc::new(
-            Provider::<Ws>::connect(goerli_url.clone())
-                .await
-                .ok()
-                .ok_or(anyhow::anyhow!("Error connecting to Goerli"))
-                .unwrap(),
+        self.metrics.ops_received.inc();
+        if let Err(err) = self.check_entry_point(entry_point) {
+            self.metrics
+                .ops_rejected
+                .with_label_values(&[rejection_reason(&err)])
+                .inc();
+            return Err(err);
+        }
+        let hash = user_operation.hash(&entry_point, &U256::from(self.eth_chain_id.as_u64()));
+        let sender = user_operation.sender;
+        let span = tracing::info_span!(
+            "add_user_operation",
+            user_operation_hash = ?hash,
+            sender = ?sender,
+            conditional = condition.is_some()
         );
+        let result = self
+            .try_add_user_operation(user_operation, entry_point, condition)
+            .instrument(span.clone())
+            .await;
+        if let Err(err) = &result {
+            span.in_scope(|| tracing::warn!(error = %err, "Rejected user operation"));
+            self.metrics
+                .ops_rejected
+                .with_label_values(&[rejection_reason(err)])
+                .inc();
+            self.emit(
+                hash,
+                sender,
+                UserOperationStatus::Failed {
+                    reason: err.to_string(),
+                },
+            );
+        }
+        result
+    }
 
-        // Get bundle signer to authenticate with Flashbots
-        let _bundle_signer = env::var("FLASHBOTS_IDENTIFIER")
-            .expect("FLASHBOTS_IDENTIFIER environment variable is not set");
-        let bundle_signer = _bundle_signer.parse::<LocalWallet>().unwrap();
+    async fn try_add_user_operation(
+        &self,
+        user_operation: UserOperation,
+        entry_point: Address,
+        condition: Option<Condition>,
+    ) -> RpcResult<UserOperationHash> {
+        let _in_flight = self.in_flight.enter();
+        self.check_accepting()?;
+        self.check_entry_point(entry_point)?;
+        if let Some(condition) = &condition {
+            condition
+                .validate()
+                .map_err(|err| rpc_error(ErrorCode::InvalidParams.code(), err))?;
+        }
+        let hash = user_operation.hash(&entry_point, &U256::from(self.eth_chain_id.as_u64()));
+        if self.mempool.lock().await.is_cancelled(&hash) {
+            return Err(rpc_error(
+                ErrorCode::InvalidParams.code(),
+                "User operation was cancelled by its owner",
+            ));
+        }
+        if let Some(entity) = self.reputation.lock().await.banned_entity(&user_operation) {
+            return Err(rpc_error(
+                BANNED_OR_THROTTLED_CODE,
+                format!("Entity {:?} is banned", entity),
+            ));
+        }
 
-        // Set up RPC client middleware with Flashbots signing middleware
-        let signing_middleware = FlashbotsSignerLayer::new(bundle_signer.clone());
-        let service_builder = ServiceBuilder::new()
-            .map_err(HttpError::Http)
-            .layer(signing_middleware);
+        let timestamp = self
+            .latest_timestamp()
+            .await
+            .map_err(|err| rpc_error(VALIDATION_FAILED_CODE, err.to_string()))?;
 
-        // Create entry point binding
+        // Read the validity window reported by the account and paymaster
+        let validation = self.simulate_validation(&user_operation).await?;
+        let (_, _, _, valid_after, valid_until, _) = validation.return_info;
+        let mut valid_until = (valid_until != 0).then_some(valid_until);
+        if let Some(condition) = &condition {
+            // conditional ops are dropped at their deadline
+            valid_until = Some(valid_until.map_or(condition.deadline(), |until| {
+                until.min(condition.deadline())
+            }));
+        }
+        if matches!(valid_until, Some(valid_until) if valid_until < timestamp + VALID_UNTIL_MARGIN)
+        {
+            return Err(rpc_error(
+                OUT_OF_TIME_RANGE_CODE,
+                "User operation is expired or expires too soon",
+            ));
+        }
+
+        self.reputation.lock().await.add_seen(&user_operation);
+        self.emit(hash, user_operation.sender, UserOperationStatus::Accepted);
+
+        // Park GAT orders and not yet valid ops until their good-after time has passed
+        let mut good_after = valid_after;
+        let (gat_timestamp, gat_enabled) = gat_tx::decode(&user_operation.call_data);
+        if gat_enabled {
+            good_after = good_after.max(gat_timestamp.min(U256::from(u64::MAX)).as_u64());
+        }
+        let parked = condition.is_some() || good_after >= timestamp;
+        let entry = MempoolEntry {
+            user_operation,
+            entry_point,
+            good_after,
+            valid_until,
+            condition,
+            condition_met: false,
+            failed_attempts: 0,
+        };
+
+        if parked {
+            log::info!("Parking user operation {:?} until {}", hash, good_after);
+            self.emit(
+                hash,
+                entry.user_operation.sender,
+                UserOperationStatus::Parked { good_after },
+            );
+            self.mempool.lock().await.park(hash, entry);
+            self.persist().await;
+            return Ok(hash);
+        }
+        if *self.bundling_mode.lock().await == BundlingMode::Manual {
+            self.mempool.lock().await.push_ready(hash, entry);
+            self.persist().await;
+            return Ok(hash);
+        }
+        let user_operations = vec![entry.user_operation.clone()];
+        let origin = BundleOrigin {
+            entries: vec![(hash, entry)],
+            schedules: vec![],
+        };
+        self.send_bundle(user_operations, origin)
+            .await
+            .map_err(|err| rpc_error(ErrorCode::InternalError.code(), err.to_string()))?;
+        self.persist().await;
+
+        Ok(hash)
+    }
+
+    /// Runs `simulateValidation` and returns the decoded `ValidationResult` revert
+    #[tracing::instrument(skip_all, fields(sender = ?user_operation.sender))]
+    async fn simulate_validation(
+        &self,
+        user_operation: &UserOperation,
+    ) -> RpcResult<ValidationResult> {
         let entry_point_instance =
-            entrypointgoerli::entrypointgoerli::new(entry_point, provider.clone());
+            entrypointgoerli::entrypointgoerli::new(self.entry_point, self.eth_provider.clone());
 
-        // Get nonce
-        let nonce = provider
-            .clone()
-            .get_transaction_count(self.wallet.signer.address(), None)
+        match entry_point_instance
+            .simulate_validation(user_operation.clone())
+            .call()
             .await
-            .unwrap();
+        {
+            Ok(()) => Err(rpc_error(
+                VALIDATION_FAILED_CODE,
+                "simulateValidation did not revert",
+            )),
+            Err(err) => {
+                if let Some(result) = err.decode_revert::<ValidationResult>() {
+                    Ok(result)
+                } else if let Some(failed_op) = err.decode_revert::<FailedOp>() {
+                    Err(rpc_error(VALIDATION_FAILED_CODE, failed_op.reason))
+                } else {
+                    Err(rpc_error(VALIDATION_FAILED_CODE, err.to_string()))
+                }
+            }
+        }
+    }
+
+    /// Executes the wallet calldata from the entry point to check that the
+    /// GatTx pre-hook no longer reverts
+    async fn simulate_execution(
+        &self,
+        user_operation: &UserOperation,
+        entry_point: Address,
+    ) -> anyhow::Result<()> {
+        let tx = TransactionRequest::new()
+            .from(entry_point)
+            .to(user_operation.sender)
+            .data(user_operation.call_data.clone());
+        self.eth_provider.call(&tx.into(), None).await?;
+        Ok(())
+    }
+
+    /// Wraps the user operations in a `handleOps` transaction and sends it to
+    /// the relay, returning the bundle hash
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            user_operation_hashes = field::Empty,
+            signer = field::Empty,
+            tx_hash = field::Empty,
+            bundle_hash = field::Empty,
+            relay = self.relay.as_str()
+        )
+    )]
+    pub(crate) async fn send_bundle(
+        &self,
+        user_operations: Vec<UserOperation>,
+        origin: BundleOrigin,
+    ) -> anyhow::Result<H256> {
+        let _in_flight = self.in_flight.enter();
+
+        // Create entry point binding
+        let entry_point_instance =
+            entrypointgoerli::entrypointgoerli::new(self.entry_point, self.eth_provider.clone());
 
-        // Push user operation to array
-        let mut user_op_vec = vec![];
-        user_op_vec.push(user_operation.clone());
+        let included = user_operations.clone();
+        let chain_id = U256::from(self.eth_chain_id.as_u64());
+        let hashes: Vec<UserOperationHash> = included
+            .iter()
+            .map(|user_operation| user_operation.hash(&self.entry_point, &chain_id))
+            .collect();
+        tracing::Span::current().record("user_operation_hashes", field::debug(&hashes));
         let mut tx: TypedTransaction = entry_point_instance
-            .handle_ops(user_op_vec, self.wallet.signer.address())
+            .handle_ops(user_operations, self.beneficiary)
             .tx
             .clone();
-        tx.set_nonce(nonce).set_chain_id(U64::from(80001));
 
-        // Craft and sign the transaction
-        let typed_tx = TypedTransaction::Eip1559(tx.clone().into());
-        let raw_tx = self
-            .wallet
-            .signer
+        // Pick the least loaded key and reserve its next nonce
+        let lease = self
+            .signers
+            .acquire(self.eth_provider.as_ref(), self.min_balance)
+            .await?;
+        tracing::Span::current().record("signer", field::debug(lease.address()));
+        tx.set_from(lease.address())
+            .set_nonce(lease.nonce)
+            .set_chain_id(self.eth_chain_id);
+
+        let submitted = async {
+            // Craft, fill the gas and fees of and sign the transaction
+            let mut typed_tx = TypedTransaction::Eip1559(tx.clone().into());
+            self.eth_provider
+                .fill_transaction(&mut typed_tx, None)
+                .await?;
+            let raw_signed_tx = lease.signer.sign_transaction(&typed_tx).await?;
+
+            let (tx_hash, bundle_hash) = match self.relay {
+                Relay::Flashbots => self.send_to_flashbots(raw_signed_tx).await?,
+                Relay::Node => self.send_to_node(raw_signed_tx).await?,
+            };
+            let submitted_block = self.eth_provider.get_block_number().await?;
+            anyhow::Ok((tx_hash, bundle_hash, submitted_block))
+        }
+        .await;
+        let (tx_hash, bundle_hash, submitted_block) = match submitted {
+            Ok(submitted) => submitted,
+            Err(err) => {
+                // not tracked, the key would stay loaded forever
+                self.signers.release(&lease).await;
+                return Err(err);
+            }
+        };
+
+        self.metrics
+            .bundles_submitted
+            .with_label_values(&[self.relay.as_str()])
+            .inc();
+        self.pending_bundles.lock().await.push(PendingBundle {
+            tx_hash,
+            signer: lease.address(),
+            relay: self.relay.as_str(),
+            submitted_block,
+            origin,
+        });
+
+        let mut reputation = self.reputation.lock().await;
+        for user_operation in included.iter() {
+            reputation.add_included(user_operation);
+        }
+        drop(reputation);
+
+        for (hash, user_operation) in hashes.iter().zip(included.iter()) {
+            self.emit(
+                *hash,
+                user_operation.sender,
+                UserOperationStatus::Bundled { bundle_hash },
+            );
+        }
+
+        if let Some(store) = &self.store {
+            let bundle = SubmittedBundle {
+                bundle_hash,
+                user_operations: hashes,
+                submitted_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            };
+            if let Err(err) = store.insert_bundle(&bundle) {
+                log::warn!("Failed to store bundle {:?}: {:?}", bundle_hash, err);
+            }
+        }
+
+        Ok(bundle_hash)
+    }
+
+    /// Sends the signed `handleOps` transaction as a private bundle to
+    /// Flashbots, returning the transaction and bundle hashes
+    #[allow(clippy::vec_init_then_push)]
+    async fn send_to_flashbots(&self, raw_signed_tx: Bytes) -> anyhow::Result<(H256, H256)> {
+        // Get bundle signer to authenticate with Flashbots
+        let bundle_signer = self
+            .flashbots_identity
             .clone()
-            .sign_transaction(&typed_tx)
-            .await
-            .unwrap();
-        let raw_signed_tx = tx.rlp_signed(&raw_tx);
+            .ok_or_else(|| anyhow::anyhow!("Flashbots identity is not set"))?;
+
+        // Set up RPC client middleware with Flashbots signing middleware
+        let signing_middleware = FlashbotsSignerLayer::new(bundle_signer.clone());
+        let service_builder = ServiceBuilder::new()
+            .map_err(HttpError::Http)
+            .layer(signing_middleware);
 
         // Add tx to Flashbots bundle
         let mut bundle_req = BundleRequest::new();
         bundle_req = bundle_req.push_transaction(raw_signed_tx.clone());
         let tx_hash = bundle_req.transaction_hashes()[0];
+        tracing::Span::current().record("tx_hash", field::debug(&tx_hash));
 
         // Build bundle
         let mut bundle_body = vec![];
@@ -202,17 +1409,122 @@ where
         };
 
         // Set up the rpc client
-        let url = "https://relay.flashbots.net:443";
         let client = HttpClientBuilder::default()
             .set_middleware(service_builder)
-            .build(url)
+            .build(FLASHBOTS_RELAY_URL)
             .expect("Failed to create http client");
 
         // Send bundle
-        let res = client.send_bundle(bundle.clone()).await.unwrap();
+        let res = client.send_bundle(bundle.clone()).await?;
+        tracing::Span::current().record("bundle_hash", field::debug(&res.bundle_hash));
         log::info!("Bundle response: {:?}", res);
 
-        return Ok(UserOperationHash(res.bundle_hash));
+        Ok((tx_hash, res.bundle_hash))
+    }
+
+    /// Sends the signed `handleOps` transaction to the node, the bundle hash
+    /// being the transaction hash
+    async fn send_to_node(&self, raw_signed_tx: Bytes) -> anyhow::Result<(H256, H256)> {
+        let tx_hash = self
+            .eth_provider
+            .send_raw_transaction(raw_signed_tx)
+            .await?
+            .tx_hash();
+        tracing::Span::current().record("tx_hash", field::debug(&tx_hash));
+        tracing::Span::current().record("bundle_hash", field::debug(&tx_hash));
+        log::info!("Sent handleOps transaction {:?}", tx_hash);
+
+        Ok((tx_hash, tx_hash))
+    }
+}
+
+/// Converts a wei amount to a metric value, losing precision above 2^53 wei
+fn wei_to_f64(wei: U256) -> f64 {
+    wei.to_string().parse().unwrap_or(f64::MAX)
+}
+
+/// Eth API trait ported from AA-Bundler
+///  https://github.com/Vid201/aa-bundler/blob/main/crates/rpc/src/eth_api.rs
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EstimateUserOperationGasResponse {
+    pub pre_verification_gas: U256,
+    pub verification_gas_limit: U256,
+    pub call_gas_limit: U256,
+}
+
+#[rpc(server, namespace = "eth")]
+pub trait EthApi {
+    #[method(name = "chainId")]
+    async fn chain_id(&self) -> RpcResult<U64>;
+    #[method(name = "supportedEntryPoints")]
+    async fn supported_entry_points(&self) -> RpcResult<Vec<Address>>;
+    #[method(name = "sendUserOperation")]
+    async fn send_user_operation(
+        &self,
+        user_operation: UserOperation,
+        entry_point: Address,
+    ) -> RpcResult<UserOperationHash>;
+    #[method(name = "estimateUserOperationGas")]
+    async fn estimate_user_operation_gas(
+        &self,
+        user_operation: UserOperation,
+        entry_point: Address,
+    ) -> RpcResult<EstimateUserOperationGasResponse>;
+    #[method(name = "getUserOperationReceipt")]
+    async fn get_user_operation_receipt(
+        &self,
+        user_operation_hash: UserOperationHash,
+    ) -> RpcResult<Option<UserOperationReceipt>>;
+}
+
+/// Bundler specific API for intents that are parked until a condition holds
+#[rpc(server, namespace = "bundler")]
+pub trait BundlerApi {
+    #[method(name = "sendConditionalUserOperation")]
+    async fn send_conditional_user_operation(
+        &self,
+        user_operation: UserOperation,
+        entry_point: Address,
+        condition: Condition,
+    ) -> RpcResult<UserOperationHash>;
+    #[method(name = "submitSchedule")]
+    async fn submit_schedule(
+        &self,
+        user_operations: Vec<UserOperation>,
+        entry_point: Address,
+        policy: FailurePolicy,
+    ) -> RpcResult<H256>;
+    #[method(name = "getSchedule")]
+    async fn get_schedule(&self, schedule_id: H256) -> RpcResult<Option<Schedule>>;
+    #[method(name = "cancelUserOperation")]
+    async fn cancel_user_operation(
+        &self,
+        user_operation_hash: UserOperationHash,
+        signature: Bytes,
+    ) -> RpcResult<bool>;
+}
+
+#[async_trait]
+impl<M> EthApiServer for BabyBundler<M>
+where
+    M: Middleware + 'static,
+    M::Provider: Send + Sync + 'static,
+{
+    async fn chain_id(&self) -> RpcResult<U64> {
+        Ok(self.eth_chain_id)
+    }
+
+    async fn supported_entry_points(&self) -> RpcResult<Vec<Address>> {
+        Ok(vec![self.entry_point])
+    }
+
+    async fn send_user_operation(
+        &self,
+        user_operation: UserOperation,
+        entry_point: Address,
+    ) -> RpcResult<UserOperationHash> {
+        self.add_user_operation(user_operation, entry_point, None)
+            .await
     }
 
     // TODO: Implement this
@@ -230,12 +1542,554 @@ where
         })
     }
 
-    // TODO: Implement this
     async fn get_user_operation_receipt(
         &self,
         user_operation_hash: UserOperationHash,
     ) -> RpcResult<Option<UserOperationReceipt>> {
-        info!("{:?}", user_operation_hash);
-        Ok(None)
+        self.user_operation_receipt(user_operation_hash)
+            .await
+            .map_err(|err| rpc_error(ErrorCode::InternalError.code(), err.to_string()))
+    }
+}
+
+#[async_trait]
+impl<M> BundlerApiServer for BabyBundler<M>
+where
+    M: Middleware + 'static,
+    M::Provider: Send + Sync + 'static,
+{
+    async fn send_conditional_user_operation(
+        &self,
+        user_operation: UserOperation,
+        entry_point: Address,
+        condition: Condition,
+    ) -> RpcResult<UserOperationHash> {
+        self.add_user_operation(user_operation, entry_point, Some(condition))
+            .await
+    }
+    async fn submit_schedule(
+        &self,
+        user_operations: Vec<UserOperation>,
+        entry_point: Address,
+        policy: FailurePolicy,
+    ) -> RpcResult<H256> {
+        let _in_flight = self.in_flight.enter();
+        self.check_accepting()?;
+        self.check_entry_point(entry_point)?;
+        let schedule = Schedule::new(
+            user_operations,
+            entry_point,
+            U256::from(self.eth_chain_id.as_u64()),
+            policy,
+        )
+        .map_err(|err| rpc_error(ErrorCode::InvalidParams.code(), err))?;
+
+        // later nonces are not current yet, they are validated once due
+        self.simulate_validation(&schedule.operations[0].user_operation)
+            .await?;
+
+        let id = schedule.id;
+        log::info!(
+            "Accepted schedule {:?} of {} user operations",
+            id,
+            schedule.operations.len()
+        );
+        for operation in schedule.operations.iter() {
+            self.emit(
+                operation.hash,
+                operation.user_operation.sender,
+                UserOperationStatus::Parked {
+                    good_after: operation.good_after,
+                },
+            );
+        }
+        self.schedules.lock().await.insert(schedule);
+        self.persist().await;
+        Ok(id)
+    }
+
+    async fn get_schedule(&self, schedule_id: H256) -> RpcResult<Option<Schedule>> {
+        Ok(self.schedules.lock().await.get(&schedule_id).cloned())
+    }
+    async fn cancel_user_operation(
+        &self,
+        user_operation_hash: UserOperationHash,
+        signature: Bytes,
+    ) -> RpcResult<bool> {
+        let parked = self
+            .mempool
+            .lock()
+            .await
+            .get(&user_operation_hash)
+            .map(|entry| entry.user_operation.clone());
+        let scheduled = self
+            .schedules
+            .lock()
+            .await
+            .find_pending(&user_operation_hash);
+
+        let user_operation = match (parked, &scheduled) {
+            (Some(user_operation), _) => user_operation,
+            (None, Some((_, _, user_operation))) => user_operation.clone(),
+            (None, None) => return Ok(false),
+        };
+
+        if !cancel::is_valid_cancellation(
+            self.eth_provider.clone(),
+            &user_operation,
+            &user_operation_hash,
+            &signature,
+        )
+        .await
+        {
+            return Err(rpc_error(
+                SIGNATURE_CHECK_FAILED_CODE,
+                "Cancellation is not signed by the wallet owner",
+            ));
+        }
+
+        let timestamp = self
+            .latest_timestamp()
+            .await
+            .map_err(|err| rpc_error(ErrorCode::InternalError.code(), err.to_string()))?;
+        self.mempool
+            .lock()
+            .await
+            .cancel(&user_operation_hash, timestamp);
+        if let Some((id, index, _)) = scheduled {
+            if let Some(schedule) = self.schedules.lock().await.get_mut(&id) {
+                schedule.cancel_from(index);
+            }
+        }
+
+        self.persist().await;
+        self.emit(
+            user_operation_hash,
+            user_operation.sender,
+            UserOperationStatus::Failed {
+                reason: "Cancelled by the owner".to_string(),
+            },
+        );
+
+        log::info!("Cancelled user operation {:?}", user_operation_hash);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::bundler::signer::{BundlerSigner, KeySource};
+    use ethers::{
+        abi::{AbiEncode, Token},
+        contract::EthEvent,
+        providers::{JsonRpcError, MockProvider, MockResponse, Provider},
+        types::{Block, Log, TransactionReceipt},
+        utils::hex,
+    };
+
+    /// Bundler on top of a mocked provider, signing with the first key of the
+    /// test mnemonic
+    pub(crate) fn mocked_bundler(
+    ) -> anyhow::Result<(BabyBundler<Provider<MockProvider>>, MockProvider)> {
+        let (provider, mock) = Provider::mocked();
+        let source = KeySource::Mnemonic {
+            phrase: "test test test test test test test test test test test junk".to_string(),
+            index: 0,
+        };
+        let signer: Arc<dyn BundlerSigner> = Arc::new(source.load(80001)?);
+        // transactions sent by the tests are awaited without delay
+        let provider = provider.interval(Duration::from_millis(1));
+        let bundler = BabyBundler::new(
+            Arc::new(provider),
+            U256::max_value(),
+            U256::max_value(),
+            SignerPool::new(vec![signer])?,
+        );
+        Ok((bundler, mock))
+    }
+
+    /// Latest block returned by `eth_getBlockByNumber`
+    pub(crate) fn block(number: u64, timestamp: u64) -> Block<H256> {
+        Block {
+            number: Some(number.into()),
+            timestamp: timestamp.into(),
+            ..Default::default()
+        }
+    }
+
+    /// `simulateValidation` revert reporting the validity window of an op
+    pub(crate) fn validation_result(valid_after: u64, valid_until: u64) -> MockResponse {
+        let result = ValidationResult {
+            return_info: (
+                U256::zero(),
+                U256::zero(),
+                false,
+                valid_after,
+                valid_until,
+                Bytes::default(),
+            ),
+            ..Default::default()
+        };
+        MockResponse::Error(JsonRpcError {
+            code: 3,
+            message: "execution reverted".to_string(),
+            data: Some(format!("0x{}", hex::encode(result.encode())).into()),
+        })
+    }
+
+    fn pending_bundle(byte: u8, signer: Address) -> PendingBundle {
+        PendingBundle {
+            tx_hash: H256::repeat_byte(byte),
+            signer,
+            relay: Relay::Node.as_str(),
+            submitted_block: U64::from(10),
+            origin: BundleOrigin::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_pending_bundles_when_receipt_lookup_fails() -> anyhow::Result<()> {
+        let (bundler, mock) = mocked_bundler()?;
+        let signer = bundler.signers.addresses()[0];
+        bundler
+            .pending_bundles
+            .lock()
+            .await
+            .extend([pending_bundle(1, signer), pending_bundle(2, signer)]);
+
+        // the first receipt fails and the second is not found
+        mock.push(serde_json::Value::Null)?;
+        mock.push_response(MockResponse::Error(JsonRpcError {
+            code: -32000,
+            message: "header not found".to_string(),
+            data: None,
+        }));
+        assert!(bundler.track_inclusion(U64::from(11)).await.is_err());
+
+        let pending = bundler.pending_bundles.lock().await;
+        assert_eq!(pending.len(), 2);
+        assert!(pending
+            .iter()
+            .any(|bundle| bundle.tx_hash == H256::repeat_byte(1)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn releases_operations_of_timed_out_bundle_again() -> anyhow::Result<()> {
+        let (bundler, mock) = mocked_bundler()?;
+        let signer = bundler.signers.addresses()[0];
+        let hash = UserOperationHash(H256::repeat_byte(1));
+        let entry = MempoolEntry {
+            user_operation: UserOperation::default(),
+            entry_point: bundler.entry_point,
+            good_after: 0,
+            valid_until: None,
+            condition: None,
+            condition_met: false,
+            failed_attempts: 0,
+        };
+        let user_operation =
+            UserOperation::default().call_data(gat_tx::encode(Bytes::default(), U256::from(100)));
+        let mut schedule = Schedule::new(
+            vec![user_operation],
+            bundler.entry_point,
+            U256::from(80001),
+            FailurePolicy::Halt,
+        )
+        .map_err(anyhow::Error::msg)?;
+        schedule.mark_submitted(ScheduledOperationState::Submitted {
+            bundle_hash: H256::repeat_byte(1),
+        });
+        let id = schedule.id;
+        bundler.schedules.lock().await.insert(schedule);
+
+        let mut bundle = pending_bundle(1, signer);
+        bundle.origin = BundleOrigin {
+            entries: vec![(hash, entry)],
+            schedules: vec![id],
+        };
+        bundler.pending_bundles.lock().await.push(bundle);
+
+        // still waiting right before the timeout
+        mock.push(serde_json::Value::Null)?;
+        bundler
+            .track_inclusion(U64::from(10 + INCLUSION_TIMEOUT_BLOCKS - 1))
+            .await?;
+        assert_eq!(bundler.pending_bundles.lock().await.len(), 1);
+        assert!(bundler.mempool.lock().await.get(&hash).is_none());
+
+        mock.push(serde_json::Value::Null)?;
+        bundler
+            .track_inclusion(U64::from(10 + INCLUSION_TIMEOUT_BLOCKS))
+            .await?;
+        assert!(bundler.pending_bundles.lock().await.is_empty());
+        assert!(bundler.mempool.lock().await.get(&hash).is_some());
+        let schedules = bundler.schedules.lock().await;
+        let schedule = schedules.get(&id).expect("schedule kept");
+        assert_eq!(schedule.next, 0);
+        assert!(schedule.due(u64::MAX).is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_inclusion_of_bundled_operations() -> anyhow::Result<()> {
+        let (bundler, mock) = mocked_bundler()?;
+        *bundler.bundling_mode.lock().await = BundlingMode::Manual;
+        let signer = bundler.signers.addresses()[0];
+        bundler
+            .pending_bundles
+            .lock()
+            .await
+            .push(pending_bundle(1, signer));
+        let mut events = bundler.events.subscribe();
+
+        let user_operation_hash = H256::repeat_byte(7);
+        let sender = Address::repeat_byte(8);
+        let log = Log {
+            topics: vec![
+                UserOperationEventFilter::signature(),
+                user_operation_hash,
+                H256::from(sender),
+                H256::zero(),
+            ],
+            data: ethers::abi::encode(&[
+                Token::Uint(U256::zero()),
+                Token::Bool(true),
+                Token::Uint(U256::from(1_000)),
+                Token::Uint(U256::from(100)),
+            ])
+            .into(),
+            ..Default::default()
+        };
+        mock.push(TransactionReceipt {
+            transaction_hash: H256::repeat_byte(1),
+            block_number: Some(U64::from(12)),
+            logs: vec![log],
+            ..Default::default()
+        })?;
+        bundler.track_inclusion(U64::from(12)).await?;
+
+        let event = events.try_recv()?;
+        assert_eq!(
+            event.user_operation_hash,
+            UserOperationHash(user_operation_hash)
+        );
+        assert_eq!(event.sender, sender);
+        assert_eq!(
+            event.status,
+            UserOperationStatus::Included {
+                transaction_hash: H256::repeat_byte(1),
+                success: true,
+            }
+        );
+        assert!(bundler.pending_bundles.lock().await.is_empty());
+        assert_eq!(bundler.metrics.beneficiary_revenue.get(), 1_000.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn finds_user_operation_receipt_in_event_logs() -> anyhow::Result<()> {
+        let (bundler, mock) = mocked_bundler()?;
+        let tx_hash = H256::repeat_byte(1);
+        let event = |user_operation_hash: u8, log_index: u64| Log {
+            address: bundler.entry_point,
+            topics: vec![
+                UserOperationEventFilter::signature(),
+                H256::repeat_byte(user_operation_hash),
+                H256::from(Address::repeat_byte(8)),
+                H256::zero(),
+            ],
+            data: ethers::abi::encode(&[
+                Token::Uint(U256::from(3)),
+                Token::Bool(true),
+                Token::Uint(U256::from(1_000)),
+                Token::Uint(U256::from(100)),
+            ])
+            .into(),
+            transaction_hash: Some(tx_hash),
+            log_index: Some(log_index.into()),
+            ..Default::default()
+        };
+        // the second op of the bundle emits a log of its own
+        let transfer = Log {
+            address: Address::repeat_byte(9),
+            log_index: Some(2.into()),
+            ..Default::default()
+        };
+
+        mock.push(TransactionReceipt {
+            transaction_hash: tx_hash,
+            logs: vec![event(6, 1), transfer.clone(), event(7, 3)],
+            ..Default::default()
+        })?;
+        mock.push(vec![event(7, 3)])?;
+        mock.push(U64::from(20_000))?;
+        let receipt = bundler
+            .get_user_operation_receipt(UserOperationHash(H256::repeat_byte(7)))
+            .await?
+            .expect("receipt found");
+        // only the recent blocks are searched
+        mock.assert_request("eth_blockNumber", ())?;
+        let filter = Filter::new()
+            .address(bundler.entry_point)
+            .topic0(UserOperationEventFilter::signature())
+            .topic1(H256::repeat_byte(7))
+            .from_block(U64::from(10_000));
+        mock.assert_request("eth_getLogs", [filter])?;
+        assert_eq!(receipt.sender, Address::repeat_byte(8));
+        assert_eq!(receipt.nonce, U256::from(3));
+        assert_eq!(receipt.actual_gas_cost, U256::from(1_000));
+        assert!(receipt.success);
+        assert_eq!(receipt.logs, vec![transfer]);
+        assert_eq!(receipt.tx_receipt.transaction_hash, tx_hash);
+
+        // not on chain yet
+        mock.push(Vec::<Log>::new())?;
+        mock.push(U64::from(20_000))?;
+        assert!(bundler
+            .get_user_operation_receipt(UserOperationHash(H256::repeat_byte(8)))
+            .await?
+            .is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_unsupported_entry_point() -> anyhow::Result<()> {
+        let (bundler, _mock) = mocked_bundler()?;
+
+        let err = bundler
+            .send_user_operation(UserOperation::default(), Address::repeat_byte(1))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, RpcError::Call(CallError::Custom(error)) if error.code() == ErrorCode::InvalidParams.code()),
+            "unexpected error {err:?}"
+        );
+        assert_eq!(
+            bundler
+                .metrics
+                .ops_rejected
+                .with_label_values(&["other"])
+                .get(),
+            1
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn releases_lease_when_send_bundle_fails() -> anyhow::Result<()> {
+        let (bundler, mock) = mocked_bundler()?;
+        let signer = bundler.signers.addresses()[0];
+
+        // the nonce is reserved, then the fees cannot be estimated
+        mock.push_response(MockResponse::Error(JsonRpcError {
+            code: -32000,
+            message: "header not found".to_string(),
+            data: None,
+        }));
+        mock.push(U256::from(5))?;
+        assert!(bundler
+            .send_bundle(vec![UserOperation::default()], BundleOrigin::default())
+            .await
+            .is_err());
+        assert_eq!(bundler.signers.in_flight(signer).await, 0);
+        assert!(bundler.pending_bundles.lock().await.is_empty());
+
+        // the unused nonce is read from the chain again
+        mock.push(U256::from(5))?;
+        let lease = bundler
+            .signers
+            .acquire(bundler.eth_provider.as_ref(), U256::zero())
+            .await?;
+        assert_eq!(lease.nonce, U256::from(5));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retries_due_operation_before_dropping_it() -> anyhow::Result<()> {
+        let (bundler, mock) = mocked_bundler()?;
+        let hash = UserOperationHash(H256::repeat_byte(1));
+        bundler.mempool.lock().await.park(
+            hash,
+            MempoolEntry {
+                user_operation: UserOperation::default(),
+                entry_point: bundler.entry_point,
+                good_after: 100,
+                valid_until: None,
+                condition: None,
+                condition_met: false,
+                failed_attempts: 0,
+            },
+        );
+        let mut events = bundler.events.subscribe();
+
+        for attempt in 1..=MAX_EXECUTION_ATTEMPTS {
+            mock.push_response(MockResponse::Error(JsonRpcError {
+                code: 3,
+                message: "execution reverted".to_string(),
+                data: None,
+            }));
+            let released = bundler
+                .release_due_operations(200, U64::from(attempt), U64::from(attempt))
+                .await?;
+            assert!(released.is_none());
+            assert!(bundler.releasing.lock().await.is_empty());
+
+            let mempool = bundler.mempool.lock().await;
+            if attempt < MAX_EXECUTION_ATTEMPTS {
+                let entry = mempool.get(&hash).expect("op parked again");
+                assert_eq!(entry.failed_attempts, attempt);
+                assert!(events.try_recv().is_err());
+            } else {
+                assert!(mempool.get(&hash).is_none());
+                let event = events.try_recv()?;
+                assert!(matches!(event.status, UserOperationStatus::Failed { .. }));
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shuts_down_and_saves_abandoned_operations() -> anyhow::Result<()> {
+        let (bundler, _mock) = mocked_bundler()?;
+        let bundler = bundler.with_store(Store::temporary()?);
+        let store = bundler.store.clone().expect("store set");
+
+        // a bundle is being sent with an op taken out of the mempool
+        let hash = UserOperationHash(H256::repeat_byte(1));
+        let entry = MempoolEntry {
+            user_operation: UserOperation::default(),
+            entry_point: bundler.entry_point,
+            good_after: 0,
+            valid_until: None,
+            condition: None,
+            condition_met: false,
+            failed_attempts: 0,
+        };
+        bundler.releasing.lock().await.insert(hash, entry);
+        let in_flight = bundler.in_flight.enter();
+
+        let summary = bundler.shutdown(Duration::from_millis(10)).await;
+        assert!(!summary.drained);
+        assert_eq!(summary.abandoned, 1);
+        let stored = store.mempool()?;
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].0, hash);
+
+        let err = bundler
+            .try_add_user_operation(UserOperation::default(), bundler.entry_point, None)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, RpcError::Call(CallError::Custom(error)) if error.code() == ErrorCode::ServerIsBusy.code()),
+            "unexpected error {err:?}"
+        );
+
+        drop(in_flight);
+        let summary = bundler.shutdown(Duration::from_millis(10)).await;
+        assert!(summary.drained);
+        assert_eq!(summary.abandoned, 0);
+        Ok(())
     }
 }
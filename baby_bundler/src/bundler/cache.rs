@@ -0,0 +1,281 @@
+use crate::bundler::metrics::Metrics;
+use ethers::{providers::Middleware, types::U64};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Interval at which the head of the provider is checked for a new block
+const HEAD_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Answers of the chain that never change, `eth_chainId` being served by the
+/// bundler itself
+const CONSTANT_METHODS: [&str; 1] = ["net_version"];
+
+/// Answers that can only change with a new block, along with the position of
+/// their block parameter
+const BLOCK_METHODS: [(&str, usize); 2] = [("eth_getCode", 1), ("eth_call", 1)];
+
+/// Block tags whose answers may change without a new head
+const UNCACHED_TAGS: [&str; 1] = ["pending"];
+
+#[derive(Debug, Default)]
+struct BlockEntries {
+    /// Head the entries were answered at
+    head: Option<U64>,
+    entries: HashMap<String, Value>,
+}
+
+/// Results of proxied calls, kept forever for chain constants and until the
+/// next block for the rest. Only successful results are cached. The calls at
+/// `latest`, or without a block parameter, are answered for the head the
+/// entries were stored at and dropped with them.
+pub struct ResponseCache {
+    constant_methods: HashSet<String>,
+    block_methods: HashMap<String, usize>,
+    constant: Mutex<HashMap<String, Value>>,
+    block: Mutex<BlockEntries>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl fmt::Debug for ResponseCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseCache")
+            .field("constant_methods", &self.constant_methods)
+            .field("block_methods", &self.block_methods)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ResponseCache {
+    pub fn new() -> Self {
+        Self {
+            constant_methods: CONSTANT_METHODS.into_iter().map(str::to_string).collect(),
+            block_methods: BLOCK_METHODS
+                .into_iter()
+                .map(|(method, index)| (method.to_string(), index))
+                .collect(),
+            constant: Mutex::default(),
+            block: Mutex::default(),
+            metrics: None,
+        }
+    }
+
+    /// Counts the hits and misses in `proxy_cache_hits_total` and `proxy_cache_misses_total`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    pub fn with_constant_methods(mut self, methods: Vec<String>) -> Self {
+        self.constant_methods = methods.into_iter().collect();
+        self
+    }
+
+    /// Block scoped methods along with the position of their block parameter
+    pub fn with_block_methods(mut self, methods: Vec<(String, usize)>) -> Self {
+        self.block_methods = methods.into_iter().collect();
+        self
+    }
+
+    /// Whether the result of a call is cached at all, block scoped calls
+    /// being skipped when made at the `pending` tag
+    pub fn is_cached(&self, method: &str, params: &Value) -> bool {
+        if self.constant_methods.contains(method) {
+            return true;
+        }
+        let tagged = match params {
+            Value::Array(params) => params
+                .iter()
+                .any(|param| matches!(param.as_str(), Some(tag) if UNCACHED_TAGS.contains(&tag))),
+            _ => false,
+        };
+        self.block_methods.contains_key(method) && !tagged
+    }
+
+    /// Key of a call, an omitted block parameter standing for `latest`
+    fn key(&self, method: &str, params: &Value) -> String {
+        match (self.block_methods.get(method), params) {
+            (Some(&index), Value::Array(values)) if values.len() == index => {
+                let mut values = values.clone();
+                values.push(json!("latest"));
+                key(method, &Value::Array(values))
+            }
+            _ => key(method, params),
+        }
+    }
+
+    /// Returns the cached result of a call along with the head it must be
+    /// stored at on a miss, so that a result answered before a new head is
+    /// not kept after it
+    pub fn get(&self, method: &str, params: &Value) -> (Option<Value>, Option<U64>) {
+        let key = self.key(method, params);
+        let (result, head) = if self.constant_methods.contains(method) {
+            let constant = self.constant.lock().expect("Cache lock poisoned");
+            (constant.get(&key).cloned(), None)
+        } else {
+            let block = self.block.lock().expect("Cache lock poisoned");
+            (block.entries.get(&key).cloned(), block.head)
+        };
+
+        if let Some(metrics) = &self.metrics {
+            let counter = match result {
+                Some(_) => &metrics.proxy_cache_hits,
+                None => &metrics.proxy_cache_misses,
+            };
+            counter.with_label_values(&[method]).inc();
+        }
+        (result, head)
+    }
+
+    /// Stores the result of a call returned by the upstream, `head` being
+    /// the one returned by `get`
+    pub fn insert(&self, method: &str, params: &Value, result: Value, head: Option<U64>) {
+        let key = self.key(method, params);
+        if self.constant_methods.contains(method) {
+            self.constant
+                .lock()
+                .expect("Cache lock poisoned")
+                .insert(key, result);
+        } else if self.block_methods.contains_key(method) {
+            let mut block = self.block.lock().expect("Cache lock poisoned");
+            // nothing is cached until the head is known
+            if head.is_some() && block.head == head {
+                block.entries.insert(key, result);
+            }
+        }
+    }
+
+    /// Drops the block scoped results if `head` is a new block
+    pub fn new_head(&self, head: U64) {
+        let mut block = self.block.lock().expect("Cache lock poisoned");
+        if block.head != Some(head) {
+            block.head = Some(head);
+            block.entries.clear();
+        }
+    }
+
+    /// Spawns the task that follows the head of `eth_provider` to invalidate
+    /// the block scoped results
+    pub fn spawn_invalidation<M: Middleware + 'static>(
+        self: &Arc<Self>,
+        eth_provider: Arc<M>,
+    ) -> JoinHandle<()> {
+        let cache = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(HEAD_POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+                match eth_provider.get_block_number().await {
+                    Ok(head) => cache.new_head(head),
+                    Err(err) => {
+                        log::warn!("Failed to fetch head for the proxy cache: {:?}", err);
+                        // results may be stale as long as the head is unknown
+                        let mut block = cache.block.lock().expect("Cache lock poisoned");
+                        block.head = None;
+                        block.entries.clear();
+                    }
+                }
+            }
+        })
+    }
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn key(method: &str, params: &Value) -> String {
+    format!("{method}:{params}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_code(block: &str) -> Value {
+        json!(["0x0000000000000000000000000000000000000001", block])
+    }
+
+    #[test]
+    fn caches_constants_and_blocks_but_pending() {
+        let cache = ResponseCache::new();
+
+        assert!(cache.is_cached("net_version", &json!([])));
+        assert!(cache.is_cached("eth_getCode", &get_code("0x10")));
+        assert!(!cache.is_cached("eth_getCode", &get_code("pending")));
+        assert!(cache.is_cached("eth_getCode", &get_code("latest")));
+        assert!(cache.is_cached(
+            "eth_getCode",
+            &json!(["0x0000000000000000000000000000000000000001"])
+        ));
+        assert!(!cache.is_cached("eth_chainId", &json!([])));
+        assert!(!cache.is_cached("eth_getBalance", &get_code("0x10")));
+    }
+
+    #[test]
+    fn hits_after_insert_and_misses_other_params() {
+        let cache = ResponseCache::new();
+        cache.new_head(U64::from(16));
+
+        let (result, head) = cache.get("eth_getCode", &get_code("0x10"));
+        assert_eq!(result, None);
+        assert_eq!(head, Some(U64::from(16)));
+        cache.insert("eth_getCode", &get_code("0x10"), json!("0x60"), head);
+
+        assert_eq!(
+            cache.get("eth_getCode", &get_code("0x10")).0,
+            Some(json!("0x60"))
+        );
+        assert_eq!(cache.get("eth_getCode", &get_code("0x11")).0, None);
+
+        let (result, head) = cache.get("net_version", &json!([]));
+        assert_eq!((result, head), (None, None));
+        cache.insert("net_version", &json!([]), json!("1"), head);
+        assert_eq!(cache.get("net_version", &json!([])).0, Some(json!("1")));
+    }
+
+    #[test]
+    fn expires_block_entries_on_new_head() {
+        let cache = ResponseCache::new();
+
+        // nothing is cached until the head is known
+        let (_, head) = cache.get("eth_getCode", &get_code("0x10"));
+        cache.insert("eth_getCode", &get_code("0x10"), json!("0x60"), head);
+        assert_eq!(cache.get("eth_getCode", &get_code("0x10")).0, None);
+
+        cache.new_head(U64::from(16));
+        let (_, head) = cache.get("eth_getCode", &get_code("0x10"));
+        cache.insert("net_version", &json!([]), json!("1"), None);
+        cache.insert("eth_getCode", &get_code("0x10"), json!("0x60"), head);
+
+        // a result answered before the new head is not kept after it
+        cache.new_head(U64::from(17));
+        cache.insert("eth_getCode", &get_code("0x11"), json!("0x60"), head);
+        assert_eq!(cache.get("eth_getCode", &get_code("0x10")).0, None);
+        assert_eq!(cache.get("eth_getCode", &get_code("0x11")).0, None);
+        assert_eq!(cache.get("net_version", &json!([])).0, Some(json!("1")));
+    }
+
+    #[test]
+    fn keeps_latest_until_new_head() {
+        let cache = ResponseCache::new();
+        cache.new_head(U64::from(16));
+        let omitted = json!(["0x0000000000000000000000000000000000000001"]);
+
+        let (result, head) = cache.get("eth_getCode", &get_code("latest"));
+        assert_eq!(result, None);
+        cache.insert("eth_getCode", &get_code("latest"), json!("0x60"), head);
+
+        // an omitted block parameter stands for latest
+        assert_eq!(cache.get("eth_getCode", &omitted).0, Some(json!("0x60")));
+
+        cache.new_head(U64::from(17));
+        assert_eq!(cache.get("eth_getCode", &get_code("latest")).0, None);
+        assert_eq!(cache.get("eth_getCode", &omitted).0, None);
+    }
+}
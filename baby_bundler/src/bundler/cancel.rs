@@ -0,0 +1,137 @@
+use crate::bindings::erc1271::Erc1271;
+use aa_bundler_primitives::{UserOperation, UserOperationHash};
+use ethers::{
+    providers::Middleware,
+    types::{Bytes, Signature, H256},
+    utils::{hash_message, keccak256},
+};
+use std::sync::Arc;
+
+/// Value returned by ERC-1271 `isValidSignature` for a valid signature
+const ERC1271_MAGIC_VALUE: [u8; 4] = [0x16, 0x26, 0xba, 0x7e];
+
+/// Hash the owner signs to cancel a parked user operation. It differs from the
+/// user operation hash so the signature of the op itself cannot be replayed.
+pub fn cancellation_hash(user_operation_hash: &UserOperationHash) -> H256 {
+    let mut message = b"cancel".to_vec();
+    message.extend_from_slice(user_operation_hash.0.as_bytes());
+    H256::from(keccak256(message))
+}
+
+/// Checks that `signature` was made by the owner of the user operation's wallet.
+/// SimpleAccount-style ECDSA signatures are checked offline against the signer
+/// of the user operation, any other wallet through ERC-1271 `isValidSignature`.
+pub async fn is_valid_cancellation<M: Middleware + 'static>(
+    provider: Arc<M>,
+    user_operation: &UserOperation,
+    user_operation_hash: &UserOperationHash,
+    signature: &Bytes,
+) -> bool {
+    let cancellation_hash = cancellation_hash(user_operation_hash);
+
+    if let (Ok(owner_signature), Ok(cancel_signature)) = (
+        Signature::try_from(user_operation.signature.as_ref()),
+        Signature::try_from(signature.as_ref()),
+    ) {
+        if let (Ok(owner), Ok(signer)) = (
+            owner_signature.recover(hash_message(user_operation_hash.0)),
+            cancel_signature.recover(hash_message(cancellation_hash)),
+        ) {
+            if owner == signer {
+                return true;
+            }
+        }
+    }
+
+    let wallet = Erc1271::new(user_operation.sender, provider);
+    matches!(
+        wallet
+            .is_valid_signature(cancellation_hash.0, signature.clone())
+            .call()
+            .await,
+        Ok(magic_value) if magic_value == ERC1271_MAGIC_VALUE
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::{
+        abi::Token,
+        core::rand::thread_rng,
+        providers::Provider,
+        signers::{LocalWallet, Signer},
+        types::Address,
+    };
+
+    fn signed_user_operation(
+        owner: &LocalWallet,
+        user_operation_hash: &UserOperationHash,
+    ) -> anyhow::Result<UserOperation> {
+        let signature = owner.sign_hash(hash_message(user_operation_hash.0))?;
+        Ok(UserOperation::default()
+            .sender(Address::repeat_byte(1))
+            .signature(Bytes::from(signature.to_vec())))
+    }
+
+    /// `isValidSignature` return data
+    fn magic_value(value: [u8; 4]) -> Bytes {
+        ethers::abi::encode(&[Token::FixedBytes(value.to_vec())]).into()
+    }
+
+    #[tokio::test]
+    async fn accepts_owner_signature_of_cancellation_hash() -> anyhow::Result<()> {
+        let (provider, _mock) = Provider::mocked();
+        let owner = LocalWallet::new(&mut thread_rng());
+        let hash = UserOperationHash(H256::repeat_byte(7));
+        let user_operation = signed_user_operation(&owner, &hash)?;
+
+        let signature = owner.sign_hash(hash_message(cancellation_hash(&hash)))?;
+        assert!(
+            is_valid_cancellation(
+                Arc::new(provider),
+                &user_operation,
+                &hash,
+                &signature.to_vec().into()
+            )
+            .await
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_replayed_user_operation_signature() -> anyhow::Result<()> {
+        let (provider, mock) = Provider::mocked();
+        let owner = LocalWallet::new(&mut thread_rng());
+        let hash = UserOperationHash(H256::repeat_byte(7));
+        let user_operation = signed_user_operation(&owner, &hash)?;
+
+        // the wallet does not vouch for it either
+        mock.push(magic_value([0; 4]))?;
+        assert!(
+            !is_valid_cancellation(
+                Arc::new(provider),
+                &user_operation,
+                &hash,
+                &user_operation.signature
+            )
+            .await
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accepts_erc1271_magic_value() -> anyhow::Result<()> {
+        let (provider, mock) = Provider::mocked();
+        let provider = Arc::new(provider);
+        let hash = UserOperationHash(H256::repeat_byte(7));
+        let user_operation = UserOperation::default().sender(Address::repeat_byte(1));
+        let signature = Bytes::from(vec![1, 2, 3]);
+
+        mock.push(magic_value(ERC1271_MAGIC_VALUE))?;
+        assert!(is_valid_cancellation(provider.clone(), &user_operation, &hash, &signature).await);
+        mock.push(magic_value([0; 4]))?;
+        assert!(!is_valid_cancellation(provider, &user_operation, &hash, &signature).await);
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+use crate::bundler::{
+    bundler::{rpc_error, BabyBundler, BundlingMode},
+    mempool::Mempool,
+    reputation::{Reputation, ReputationEntry},
+    schedule::Schedules,
+};
+use aa_bundler_primitives::UserOperation;
+use async_trait::async_trait;
+use ethers::{
+    providers::Middleware,
+    types::{Address, H256},
+};
+use jsonrpsee::{core::RpcResult, proc_macros::rpc, types::error::ErrorCode};
+
+/// Debug API used by the ERC-4337 bundler spec test suite
+/// https://github.com/eth-infinitism/bundler-spec-tests
+#[rpc(server, namespace = "debug")]
+pub trait DebugApi {
+    #[method(name = "bundler_clearState")]
+    async fn clear_state(&self) -> RpcResult<String>;
+    #[method(name = "bundler_dumpMempool")]
+    async fn dump_mempool(&self, entry_point: Address) -> RpcResult<Vec<UserOperation>>;
+    #[method(name = "bundler_sendBundleNow")]
+    async fn send_bundle_now(&self) -> RpcResult<Option<H256>>;
+    #[method(name = "bundler_setBundlingMode")]
+    async fn set_bundling_mode(&self, mode: BundlingMode) -> RpcResult<String>;
+    #[method(name = "bundler_setReputation")]
+    async fn set_reputation(
+        &self,
+        reputations: Vec<ReputationEntry>,
+        entry_point: Address,
+    ) -> RpcResult<String>;
+    #[method(name = "bundler_dumpReputation")]
+    async fn dump_reputation(&self, entry_point: Address) -> RpcResult<Vec<ReputationEntry>>;
+}
+
+#[async_trait]
+impl<M> DebugApiServer for BabyBundler<M>
+where
+    M: Middleware + 'static,
+    M::Provider: Send + Sync + 'static,
+{
+    async fn clear_state(&self) -> RpcResult<String> {
+        *self.mempool.lock().await = Mempool::default();
+        *self.schedules.lock().await = Schedules::default();
+        *self.reputation.lock().await = Reputation::default();
+        if let Some(store) = &self.store {
+            store
+                .clear()
+                .map_err(|err| rpc_error(ErrorCode::InternalError.code(), err.to_string()))?;
+        }
+        Ok("ok".to_string())
+    }
+
+    async fn dump_mempool(&self, entry_point: Address) -> RpcResult<Vec<UserOperation>> {
+        self.check_entry_point(entry_point)?;
+        Ok(self.mempool.lock().await.user_operations())
+    }
+
+    async fn send_bundle_now(&self) -> RpcResult<Option<H256>> {
+        let internal_error =
+            |err: anyhow::Error| rpc_error(ErrorCode::InternalError.code(), err.to_string());
+
+        let (number, timestamp) = self.latest_block().await.map_err(internal_error)?;
+        let bundle_hash = self
+            .release_due_operations(timestamp, number, number)
+            .await
+            .map_err(internal_error)?;
+        self.persist().await;
+        Ok(bundle_hash)
+    }
+
+    async fn set_bundling_mode(&self, mode: BundlingMode) -> RpcResult<String> {
+        *self.bundling_mode.lock().await = mode;
+        Ok("ok".to_string())
+    }
+
+    async fn set_reputation(
+        &self,
+        reputations: Vec<ReputationEntry>,
+        entry_point: Address,
+    ) -> RpcResult<String> {
+        self.check_entry_point(entry_point)?;
+        self.reputation.lock().await.set(reputations);
+        self.persist().await;
+        Ok("ok".to_string())
+    }
+
+    async fn dump_reputation(&self, entry_point: Address) -> RpcResult<Vec<ReputationEntry>> {
+        self.check_entry_point(entry_point)?;
+        Ok(self.reputation.lock().await.dump())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundler::{
+        bundler::tests::{block, mocked_bundler},
+        mempool::MempoolEntry,
+        reputation::ReputationStatus,
+    };
+    use aa_bundler_primitives::UserOperationHash;
+    use ethers::types::U256;
+
+    fn reputation(address: Address, status: ReputationStatus) -> ReputationEntry {
+        ReputationEntry {
+            address,
+            ops_seen: 3,
+            ops_included: 1,
+            status,
+        }
+    }
+
+    #[tokio::test]
+    async fn dumps_and_clears_state() -> anyhow::Result<()> {
+        let (bundler, _) = mocked_bundler()?;
+        let user_operation = UserOperation::default().nonce(U256::from(7));
+        bundler.mempool.lock().await.park(
+            UserOperationHash(H256::repeat_byte(1)),
+            MempoolEntry {
+                user_operation: user_operation.clone(),
+                entry_point: bundler.entry_point,
+                good_after: u64::MAX,
+                valid_until: None,
+                condition: None,
+                condition_met: false,
+                failed_attempts: 0,
+            },
+        );
+        let banned = reputation(Address::repeat_byte(2), ReputationStatus::Banned);
+        bundler
+            .set_reputation(vec![banned.clone()], bundler.entry_point)
+            .await?;
+
+        let dumped = bundler.dump_mempool(bundler.entry_point).await?;
+        assert_eq!(dumped.len(), 1);
+        assert_eq!(dumped[0].nonce, user_operation.nonce);
+        assert_eq!(
+            bundler.dump_reputation(bundler.entry_point).await?,
+            vec![banned]
+        );
+        assert!(bundler.dump_mempool(Address::repeat_byte(3)).await.is_err());
+        assert!(bundler
+            .set_reputation(vec![], Address::repeat_byte(3))
+            .await
+            .is_err());
+
+        bundler.clear_state().await?;
+        assert!(bundler.dump_mempool(bundler.entry_point).await?.is_empty());
+        assert!(bundler
+            .dump_reputation(bundler.entry_point)
+            .await?
+            .is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn switches_bundling_mode_and_sends_empty_bundle() -> anyhow::Result<()> {
+        let (bundler, mock) = mocked_bundler()?;
+        bundler.set_bundling_mode(BundlingMode::Manual).await?;
+        assert_eq!(*bundler.bundling_mode.lock().await, BundlingMode::Manual);
+
+        // nothing is due, so nothing is sent
+        mock.push(block(10, 1_000))?;
+        assert_eq!(bundler.send_bundle_now().await?, None);
+
+        bundler.set_bundling_mode(BundlingMode::Auto).await?;
+        assert_eq!(*bundler.bundling_mode.lock().await, BundlingMode::Auto);
+        Ok(())
+    }
+}
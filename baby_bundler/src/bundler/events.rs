@@ -0,0 +1,146 @@
+use crate::bundler::bundler::BabyBundler;
+use aa_bundler_primitives::UserOperationHash;
+use async_trait::async_trait;
+use ethers::{
+    providers::Middleware,
+    types::{Address, H256},
+};
+use jsonrpsee::{
+    core::SubscriptionResult,
+    proc_macros::rpc,
+    types::error::{ErrorCode, ErrorObject},
+    PendingSubscriptionSink, SubscriptionMessage,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+/// Kind of subscription served by `eth_subscribe`
+const USER_OPERATION_EVENTS: &str = "userOperationEvents";
+
+/// Events buffered per subscriber before the slowest ones start missing events
+pub const EVENTS_CAPACITY: usize = 1024;
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum UserOperationStatus {
+    /// Passed validation
+    Accepted,
+    /// Held back until its good-after time, condition or schedule
+    #[serde(rename_all = "camelCase")]
+    Parked { good_after: u64 },
+    /// Sent to the relay in a bundle
+    #[serde(rename_all = "camelCase")]
+    Bundled { bundle_hash: H256 },
+    /// Executed on chain, `success` is false if the call itself reverted
+    #[serde(rename_all = "camelCase")]
+    Included {
+        transaction_hash: H256,
+        success: bool,
+    },
+    /// Rejected, dropped, expired or cancelled
+    Failed { reason: String },
+}
+
+/// Status change of a user operation pushed to the subscribers
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserOperationEvent {
+    pub user_operation_hash: UserOperationHash,
+    pub sender: Address,
+    #[serde(flatten)]
+    pub status: UserOperationStatus,
+}
+
+/// Selects the user operations a subscriber is notified about, every user
+/// operation if neither field is set
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserOperationEventFilter {
+    pub user_operation_hash: Option<UserOperationHash>,
+    pub sender: Option<Address>,
+}
+
+impl UserOperationEventFilter {
+    pub fn matches(&self, event: &UserOperationEvent) -> bool {
+        (self.user_operation_hash.is_none()
+            || self.user_operation_hash == Some(event.user_operation_hash))
+            && (self.sender.is_none() || self.sender == Some(event.sender))
+    }
+}
+
+#[rpc(server, namespace = "eth")]
+pub trait UserOperationEventsApi {
+    /// `eth_subscribe("userOperationEvents", filter)`
+    #[subscription(name = "subscribe" => "subscription", unsubscribe = "unsubscribe", item = UserOperationEvent)]
+    async fn subscribe(
+        &self,
+        kind: String,
+        filter: Option<UserOperationEventFilter>,
+    ) -> SubscriptionResult;
+}
+
+#[async_trait]
+impl<M> UserOperationEventsApiServer for BabyBundler<M>
+where
+    M: Middleware + 'static,
+    M::Provider: Send + Sync + 'static,
+{
+    async fn subscribe(
+        &self,
+        pending: PendingSubscriptionSink,
+        kind: String,
+        filter: Option<UserOperationEventFilter>,
+    ) -> SubscriptionResult {
+        if kind != USER_OPERATION_EVENTS {
+            pending
+                .reject(ErrorObject::owned(
+                    ErrorCode::InvalidParams.code(),
+                    format!("Unsupported subscription {kind}"),
+                    None::<()>,
+                ))
+                .await;
+            return Ok(());
+        }
+
+        let filter = filter.unwrap_or_default();
+        let mut events = self.events.subscribe();
+        let sink = pending.accept().await?;
+
+        loop {
+            tokio::select! {
+                _ = sink.closed() => break,
+                event = events.recv() => match event {
+                    Ok(event) if filter.matches(&event) => {
+                        sink.send(SubscriptionMessage::from_json(&event)?).await?;
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::warn!("Subscriber missed {} user operation events", skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<M> BabyBundler<M>
+where
+    M: Middleware + 'static,
+    M::Provider: Send + Sync + 'static,
+{
+    /// Notifies the subscribers of a status change, dropped if there are none
+    pub(crate) fn emit(
+        &self,
+        user_operation_hash: UserOperationHash,
+        sender: Address,
+        status: UserOperationStatus,
+    ) {
+        let _ = self.events.send(UserOperationEvent {
+            user_operation_hash,
+            sender,
+            status,
+        });
+    }
+}
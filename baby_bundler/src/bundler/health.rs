@@ -0,0 +1,126 @@
+use crate::bundler::{
+    bundler::{BabyBundler, Relay, FLASHBOTS_RELAY_URL},
+    server::{Readiness, ReadinessCheck},
+};
+use async_trait::async_trait;
+use ethers::{
+    providers::{Http, HttpClientError, JsonRpcClient, Middleware},
+    types::{BlockNumber, U64},
+};
+use std::future::Future;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Seconds the latest block may lag behind the wall clock before the upstream
+/// node is considered out of sync
+const MAX_SYNC_LAG: u64 = 60;
+
+/// Time a probe is given before it is reported as failed, so that a hung node
+/// or relay does not stall `/ready`
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl<M> BabyBundler<M>
+where
+    M: Middleware + 'static,
+    M::Provider: Send + Sync + 'static,
+{
+    async fn check_upstream(&self) -> anyhow::Result<String> {
+        let block = self
+            .eth_provider
+            .get_block(BlockNumber::Latest)
+            .await?
+            .ok_or(anyhow::anyhow!("Latest block not found"))?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let lag = now.saturating_sub(block.timestamp.as_u64());
+        if lag > MAX_SYNC_LAG {
+            anyhow::bail!("Latest block is {lag}s behind");
+        }
+        Ok(format!(
+            "Block {} is {lag}s behind",
+            block.number.unwrap_or_default()
+        ))
+    }
+
+    async fn check_balance(&self) -> anyhow::Result<String> {
+        // bundles go to the funded keys, one is enough to keep bundling
+        let balances = self
+            .signers
+            .refresh_balances(self.eth_provider.as_ref())
+            .await?;
+        let funded = balances
+            .iter()
+            .filter(|(_, balance)| *balance >= self.min_balance)
+            .count();
+        if funded == 0 {
+            anyhow::bail!("Balance of every key is below {}", self.min_balance);
+        }
+        Ok(format!("{funded} of {} keys funded", balances.len()))
+    }
+
+    async fn check_entry_point_code(&self) -> anyhow::Result<String> {
+        let code = self.eth_provider.get_code(self.entry_point, None).await?;
+        if code.is_empty() {
+            anyhow::bail!("No code at entry point {:?}", self.entry_point);
+        }
+        Ok(format!("{} bytes of code", code.len()))
+    }
+
+    async fn check_relay(&self) -> anyhow::Result<String> {
+        if self.relay == Relay::Node {
+            // bundles are sent as raw transactions to the node itself
+            let block_number = self.eth_provider.get_block_number().await?;
+            return Ok(format!("Node at block {block_number}"));
+        }
+        // the relay rejects unsigned requests, any JSON-RPC answer means it is up
+        let relay = Http::from_str(FLASHBOTS_RELAY_URL)?;
+        match relay.request::<_, U64>("eth_blockNumber", ()).await {
+            Ok(_) | Err(HttpClientError::JsonRpcError(_)) => Ok("Reachable".to_string()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+async fn with_timeout(
+    probe: impl Future<Output = anyhow::Result<String>>,
+) -> anyhow::Result<String> {
+    tokio::time::timeout(PROBE_TIMEOUT, probe)
+        .await
+        .map_err(|_| anyhow::anyhow!("No answer after {:?}", PROBE_TIMEOUT))?
+}
+
+fn to_check(name: &'static str, result: anyhow::Result<String>) -> ReadinessCheck {
+    match result {
+        Ok(detail) => ReadinessCheck {
+            name,
+            ok: true,
+            detail,
+        },
+        Err(err) => ReadinessCheck {
+            name,
+            ok: false,
+            detail: err.to_string(),
+        },
+    }
+}
+
+#[async_trait]
+impl<M> Readiness for BabyBundler<M>
+where
+    M: Middleware + 'static,
+    M::Provider: Send + Sync + 'static,
+{
+    async fn readiness(&self) -> Vec<ReadinessCheck> {
+        let (upstream, balance, entry_point, relay) = tokio::join!(
+            with_timeout(self.check_upstream()),
+            with_timeout(self.check_balance()),
+            with_timeout(self.check_entry_point_code()),
+            with_timeout(self.check_relay()),
+        );
+        vec![
+            to_check("upstream", upstream),
+            to_check("balance", balance),
+            to_check("entryPoint", entry_point),
+            to_check("relay", relay),
+        ]
+    }
+}
@@ -0,0 +1,334 @@
+use crate::bindings::uniswap_v2_router_1::uniswap_v2_router_1;
+use ethers::{
+    contract::builders::Event,
+    providers::Middleware,
+    types::{Address, Bytes, Filter, Log, ValueOrArray, U256, U64},
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Condition that must hold before a parked user operation is bundled
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Condition {
+    Price(PriceCondition),
+    Log(LogCondition),
+}
+
+impl Condition {
+    /// Latest timestamp at which the condition may still release the user operation
+    pub fn deadline(&self) -> u64 {
+        match self {
+            Condition::Price(condition) => condition.deadline,
+            Condition::Log(condition) => condition.deadline,
+        }
+    }
+
+    /// Whether the condition stays met once it has held. A log stays on chain
+    /// once emitted, while a price can move back.
+    pub fn latches(&self) -> bool {
+        matches!(self, Condition::Log(_))
+    }
+
+    /// Checks that the condition can be evaluated, a log condition must name the
+    /// contracts it listens to
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Condition::Log(condition) if condition.addresses().is_empty() => {
+                Err("Log condition filter has no address".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Evaluates the condition, a log condition against the `logs` fetched with
+    /// [`fetch_logs`] for the blocks since the last evaluation
+    pub async fn is_met<M: Middleware + 'static>(
+        &self,
+        provider: Arc<M>,
+        logs: &[Log],
+    ) -> anyhow::Result<bool> {
+        match self {
+            Condition::Price(condition) => condition.is_met(provider).await,
+            Condition::Log(condition) => Ok(logs.iter().any(|log| condition.matches(log))),
+        }
+    }
+}
+
+/// Fetches the logs emitted from `from_block` to `to_block` by the contracts of
+/// every condition in a single query
+pub async fn fetch_logs<M: Middleware + 'static>(
+    provider: Arc<M>,
+    conditions: &[&LogCondition],
+    from_block: U64,
+    to_block: U64,
+) -> anyhow::Result<Vec<Log>> {
+    let mut addresses: Vec<Address> = conditions
+        .iter()
+        .flat_map(|condition| condition.addresses())
+        .collect();
+    addresses.sort();
+    addresses.dedup();
+    if addresses.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let filter = Filter::new()
+        .address(addresses)
+        .from_block(from_block)
+        .to_block(to_block);
+    Ok(provider.get_logs(&filter).await?)
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum Comparison {
+    /// Release once the quote is at or above the threshold, e.g. a limit sell
+    AtLeast,
+    /// Release once the quote is at or below the threshold, e.g. a stop-loss
+    AtMost,
+}
+
+/// Price trigger quoted through `getAmountsOut` on a Uniswap V2 router
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PriceCondition {
+    /// Uniswap V2 router to quote against
+    pub router: Address,
+    /// Swap path, from the token sold to the token bought
+    pub path: Vec<Address>,
+    /// Amount of the first token in the path to quote
+    pub amount_in: U256,
+    pub comparison: Comparison,
+    /// Amount of the last token in the path the quote is compared to
+    pub threshold: U256,
+    /// Timestamp after which the user operation is dropped
+    pub deadline: u64,
+}
+
+impl PriceCondition {
+    pub async fn is_met<M: Middleware + 'static>(&self, provider: Arc<M>) -> anyhow::Result<bool> {
+        let router = uniswap_v2_router_1::new(self.router, provider);
+        let amounts = router
+            .get_amounts_out(self.amount_in, self.path.clone())
+            .call()
+            .await?;
+        let amount_out = amounts
+            .last()
+            .copied()
+            .ok_or(anyhow::anyhow!("Empty getAmountsOut response"))?;
+
+        Ok(match self.comparison {
+            Comparison::AtLeast => amount_out >= self.threshold,
+            Comparison::AtMost => amount_out <= self.threshold,
+        })
+    }
+}
+
+/// Event trigger, met once a log matching the filter lands on chain
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogCondition {
+    /// Address and topic filter, its block range is ignored. The address is
+    /// required.
+    pub filter: Filter,
+    /// Exact log data to match, any data matches if not set
+    pub data: Option<Bytes>,
+    /// Timestamp after which the user operation is dropped
+    pub deadline: u64,
+}
+
+impl LogCondition {
+    /// Condition on an event filter generated with the bindings, e.g.
+    /// `weth::new(address, provider).transfer_filter().topic2(wallet)`
+    pub fn from_event<B, M, D>(event: Event<B, M, D>, data: Option<Bytes>, deadline: u64) -> Self {
+        Self {
+            filter: event.filter,
+            data,
+            deadline,
+        }
+    }
+
+    /// Contracts whose logs the condition listens to
+    pub fn addresses(&self) -> Vec<Address> {
+        match &self.filter.address {
+            Some(ValueOrArray::Value(address)) => vec![*address],
+            Some(ValueOrArray::Array(addresses)) => addresses.clone(),
+            None => vec![],
+        }
+    }
+
+    /// Whether the log comes from one of the contracts of the filter and matches
+    /// its topics and the expected data
+    pub fn matches(&self, log: &Log) -> bool {
+        let topics_match =
+            self.filter
+                .topics
+                .iter()
+                .enumerate()
+                .all(|(index, topic)| match topic {
+                    None | Some(ValueOrArray::Value(None)) => true,
+                    Some(ValueOrArray::Value(Some(topic))) => log.topics.get(index) == Some(topic),
+                    Some(ValueOrArray::Array(topics)) => {
+                        topics.is_empty()
+                            || topics.iter().any(|topic| {
+                                topic.is_none() || topic.as_ref() == log.topics.get(index)
+                            })
+                    }
+                });
+
+        self.addresses().contains(&log.address)
+            && topics_match
+            && self.data.as_ref().is_none_or(|data| *data == log.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bindings::weth::{weth, TransferFilter};
+    use ethers::{abi::AbiEncode, contract::EthEvent, providers::Provider, types::H256};
+
+    /// `getAmountsOut` return data
+    fn amounts_out(amounts: &[u64]) -> Bytes {
+        amounts
+            .iter()
+            .map(|amount| U256::from(*amount))
+            .collect::<Vec<_>>()
+            .encode()
+            .into()
+    }
+
+    fn price_condition(comparison: Comparison) -> PriceCondition {
+        PriceCondition {
+            router: Address::repeat_byte(1),
+            path: vec![Address::repeat_byte(2), Address::repeat_byte(3)],
+            amount_in: U256::from(1_000),
+            comparison,
+            threshold: U256::from(2_000),
+            deadline: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn price_condition_compares_quote_to_threshold() -> anyhow::Result<()> {
+        let (provider, mock) = Provider::mocked();
+        let provider = Arc::new(provider);
+
+        for (comparison, amount_out, met) in [
+            (Comparison::AtLeast, 2_000, true),
+            (Comparison::AtLeast, 1_999, false),
+            (Comparison::AtMost, 2_000, true),
+            (Comparison::AtMost, 2_001, false),
+        ] {
+            mock.push(amounts_out(&[1_000, amount_out]))?;
+            let condition = price_condition(comparison);
+            assert_eq!(condition.is_met(provider.clone()).await?, met);
+        }
+
+        mock.push(amounts_out(&[]))?;
+        assert!(price_condition(Comparison::AtLeast)
+            .is_met(provider)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    fn log_condition(data: Option<Bytes>) -> Condition {
+        Condition::Log(LogCondition {
+            filter: Filter::new()
+                .address(Address::repeat_byte(1))
+                .topic0(H256::repeat_byte(2)),
+            data,
+            deadline: 0,
+        })
+    }
+
+    fn log(data: &[u8]) -> Log {
+        Log {
+            address: Address::repeat_byte(1),
+            topics: vec![H256::repeat_byte(2)],
+            data: data.to_vec().into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn log_condition_matches_log_data() -> anyhow::Result<()> {
+        let (provider, _mock) = Provider::mocked();
+        let provider = Arc::new(provider);
+        let condition = log_condition(Some(vec![1, 2].into()));
+        assert!(condition.latches());
+        assert!(!Condition::Price(price_condition(Comparison::AtLeast)).latches());
+
+        assert!(!condition.is_met(provider.clone(), &[]).await?);
+        assert!(!condition.is_met(provider.clone(), &[log(&[1])]).await?);
+        assert!(
+            condition
+                .is_met(provider.clone(), &[log(&[1]), log(&[1, 2])])
+                .await?
+        );
+
+        // without data any log of the filter matches, but only from its address
+        let condition = log_condition(None);
+        assert!(condition.is_met(provider.clone(), &[log(&[3])]).await?);
+        let other = Log {
+            address: Address::repeat_byte(9),
+            ..log(&[3])
+        };
+        assert!(!condition.is_met(provider, &[other]).await?);
+        Ok(())
+    }
+
+    #[test]
+    fn log_condition_requires_an_address() {
+        let condition = Condition::Log(LogCondition {
+            filter: Filter::new().topic0(H256::repeat_byte(2)),
+            data: None,
+            deadline: 0,
+        });
+        assert!(condition.validate().is_err());
+        assert!(log_condition(None).validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn fetches_logs_of_every_condition_at_once() -> anyhow::Result<()> {
+        let (provider, mock) = Provider::mocked();
+        let provider = Arc::new(provider);
+        let weth_address = Address::repeat_byte(1);
+        let wallet = Address::repeat_byte(4);
+        let weth = weth::new(weth_address, provider.clone());
+        let transfer =
+            LogCondition::from_event(weth.transfer_filter().topic2(H256::from(wallet)), None, 0);
+        let deposit = LogCondition::from_event(weth.deposit_filter(), None, 0);
+
+        let transfer_log = Log {
+            address: weth_address,
+            topics: vec![
+                TransferFilter::signature(),
+                H256::from(Address::repeat_byte(3)),
+                H256::from(wallet),
+            ],
+            ..Default::default()
+        };
+        mock.push(vec![transfer_log.clone()])?;
+        let logs = fetch_logs(provider.clone(), &[&transfer, &deposit], 1.into(), 2.into()).await?;
+        assert_eq!(logs, vec![transfer_log.clone()]);
+        assert!(transfer.matches(&transfer_log));
+        assert!(!deposit.matches(&transfer_log));
+
+        // a transfer to another wallet does not match
+        let mut other = transfer_log;
+        other.topics[2] = H256::from(Address::repeat_byte(5));
+        assert!(!transfer.matches(&other));
+
+        // the logs of both conditions are fetched in a single query
+        let filter = Filter::new()
+            .address(vec![weth_address])
+            .from_block(U64::from(1))
+            .to_block(U64::from(2));
+        mock.assert_request("eth_getLogs", [filter])?;
+        assert!(mock.assert_request("eth_getLogs", ()).is_err());
+        Ok(())
+    }
+}
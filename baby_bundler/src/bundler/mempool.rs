@@ -0,0 +1,337 @@
+use crate::bundler::intent::Condition;
+use aa_bundler_primitives::{UserOperation, UserOperationHash};
+use ethers::types::Address;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// A user operation held back by the bundler until it becomes executable
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MempoolEntry {
+    /// The parked user operation
+    pub user_operation: UserOperation,
+    /// Entry point the user operation was submitted to
+    pub entry_point: Address,
+    /// Block timestamp after which the user operation may be bundled
+    pub good_after: u64,
+    /// `validUntil` reported by `simulateValidation`, `None` if the op never expires
+    pub valid_until: Option<u64>,
+    /// Condition that must hold before the user operation is bundled
+    pub condition: Option<Condition>,
+    /// Set once a latching condition has held, it is not evaluated again
+    #[serde(default)]
+    pub condition_met: bool,
+    /// Times the op failed to execute once due, it is dropped after a few
+    #[serde(default)]
+    pub failed_attempts: u32,
+}
+
+impl MempoolEntry {
+    /// Whether the user operation stops being valid before `deadline`
+    pub fn expires_before(&self, deadline: u64) -> bool {
+        matches!(self.valid_until, Some(valid_until) if valid_until < deadline)
+    }
+}
+
+/// Time-indexed queue of parked user operations
+#[derive(Default, Debug)]
+pub struct Mempool {
+    entries: HashMap<UserOperationHash, MempoolEntry>,
+    parked: BTreeMap<u64, Vec<UserOperationHash>>,
+    conditional: HashSet<UserOperationHash>,
+    /// Executable user operations waiting for the next bundle in manual bundling mode
+    ready: Vec<UserOperationHash>,
+    /// Timestamp at which each cancelled user operation was cancelled by its owner
+    cancelled: HashMap<UserOperationHash, u64>,
+    /// Entries and cancellations added, updated or removed since the last save
+    changed: HashSet<UserOperationHash>,
+    changed_cancellations: HashSet<UserOperationHash>,
+}
+
+impl Mempool {
+    /// Parks a user operation until its `good_after` timestamp has passed, or
+    /// until its condition holds if it carries one
+    pub fn park(&mut self, hash: UserOperationHash, entry: MempoolEntry) {
+        if entry.condition.is_some() {
+            self.conditional.insert(hash);
+        } else {
+            self.parked.entry(entry.good_after).or_default().push(hash);
+        }
+        self.changed.insert(hash);
+        self.entries.insert(hash, entry);
+    }
+
+    /// Queues an executable user operation for the next bundle
+    pub fn push_ready(&mut self, hash: UserOperationHash, entry: MempoolEntry) {
+        self.ready.push(hash);
+        self.changed.insert(hash);
+        self.entries.insert(hash, entry);
+    }
+
+    /// Removes and returns every entry queued for the next bundle
+    pub fn take_ready(&mut self) -> Vec<(UserOperationHash, MempoolEntry)> {
+        std::mem::take(&mut self.ready)
+            .into_iter()
+            .filter_map(|hash| self.entries.remove(&hash).map(|entry| (hash, entry)))
+            .collect()
+    }
+
+    /// Returns a copy of every user operation held in the mempool
+    pub fn user_operations(&self) -> Vec<UserOperation> {
+        self.entries
+            .values()
+            .map(|entry| entry.user_operation.clone())
+            .collect()
+    }
+
+    /// Returns a copy of every entry held in the mempool
+    pub fn entries(&self) -> Vec<(UserOperationHash, MempoolEntry)> {
+        self.entries
+            .iter()
+            .map(|(hash, entry)| (*hash, entry.clone()))
+            .collect()
+    }
+
+    /// Returns the number of user operations parked until a timestamp, waiting on
+    /// a condition and queued for the next bundle
+    pub fn counts(&self) -> (usize, usize, usize) {
+        let parked = self.parked.values().map(Vec::len).sum();
+        (parked, self.conditional.len(), self.ready.len())
+    }
+
+    pub fn get(&self, hash: &UserOperationHash) -> Option<&MempoolEntry> {
+        self.entries.get(hash)
+    }
+
+    /// Returns a copy of every entry waiting on a condition
+    pub fn conditional(&self) -> Vec<(UserOperationHash, MempoolEntry)> {
+        self.conditional
+            .iter()
+            .filter_map(|hash| self.entries.get(hash).map(|entry| (*hash, entry.clone())))
+            .collect()
+    }
+
+    /// Records that the condition of a parked user operation has held
+    pub fn latch_condition(&mut self, hash: &UserOperationHash) {
+        if let Some(entry) = self.entries.get_mut(hash) {
+            entry.condition_met = true;
+            self.changed.insert(*hash);
+        }
+    }
+
+    /// Removes and returns every entry whose `good_after` timestamp is before `timestamp`
+    pub fn take_due(&mut self, timestamp: u64) -> Vec<(UserOperationHash, MempoolEntry)> {
+        let pending = self.parked.split_off(&timestamp);
+        let due = std::mem::replace(&mut self.parked, pending);
+
+        due.into_values()
+            .flatten()
+            .filter_map(|hash| self.entries.remove(&hash).map(|entry| (hash, entry)))
+            .collect()
+    }
+
+    /// Removes the entry with the given hash from the queue
+    pub fn remove(&mut self, hash: &UserOperationHash) -> Option<MempoolEntry> {
+        let entry = self.entries.remove(hash)?;
+        self.changed.insert(*hash);
+        self.conditional.remove(hash);
+        self.ready.retain(|ready| ready != hash);
+        if let Some(hashes) = self.parked.get_mut(&entry.good_after) {
+            hashes.retain(|parked| parked != hash);
+            if hashes.is_empty() {
+                self.parked.remove(&entry.good_after);
+            }
+        }
+        Some(entry)
+    }
+
+    /// Records the cancellation of a user operation and removes it from the queue
+    pub fn cancel(&mut self, hash: &UserOperationHash, timestamp: u64) -> Option<MempoolEntry> {
+        self.cancelled.insert(*hash, timestamp);
+        self.changed_cancellations.insert(*hash);
+        self.remove(hash)
+    }
+
+    /// Whether the owner cancelled the user operation, it is not accepted again
+    pub fn is_cancelled(&self, hash: &UserOperationHash) -> bool {
+        self.cancelled.contains_key(hash)
+    }
+
+    /// Returns every cancelled user operation with the timestamp it was cancelled at
+    pub fn cancellations(&self) -> Vec<(UserOperationHash, u64)> {
+        self.cancelled
+            .iter()
+            .map(|(hash, timestamp)| (*hash, *timestamp))
+            .collect()
+    }
+
+    /// Restores cancellations saved before a restart
+    pub fn set_cancellations(&mut self, cancellations: Vec<(UserOperationHash, u64)>) {
+        self.cancelled.extend(cancellations);
+    }
+
+    /// Marks an entry as changed, e.g. once an entry taken out of the mempool to
+    /// be bundled is gone for good
+    pub fn touch(&mut self, hash: UserOperationHash) {
+        self.changed.insert(hash);
+    }
+
+    /// Returns the entries and the cancellations changed since the last call,
+    /// `None` for the ones removed. Entries taken out by `take_ready` and
+    /// `take_due` are not reported until they are put back or touched.
+    pub fn take_changes(
+        &mut self,
+    ) -> (
+        Vec<(UserOperationHash, Option<MempoolEntry>)>,
+        Vec<(UserOperationHash, Option<u64>)>,
+    ) {
+        let entries = std::mem::take(&mut self.changed)
+            .into_iter()
+            .map(|hash| (hash, self.entries.get(&hash).cloned()))
+            .collect();
+        let cancellations = std::mem::take(&mut self.changed_cancellations)
+            .into_iter()
+            .map(|hash| (hash, self.cancelled.get(&hash).copied()))
+            .collect();
+        (entries, cancellations)
+    }
+
+    /// Forgets and returns the cancellations made before `timestamp`
+    pub fn expire_cancellations(&mut self, timestamp: u64) -> Vec<UserOperationHash> {
+        let expired: Vec<UserOperationHash> = self
+            .cancelled
+            .iter()
+            .filter(|(_, cancelled_at)| **cancelled_at < timestamp)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired.iter() {
+            self.cancelled.remove(hash);
+            self.changed_cancellations.insert(*hash);
+        }
+        expired
+    }
+
+    /// Evicts and returns every entry that expires before `deadline`
+    pub fn evict_expired(&mut self, deadline: u64) -> Vec<(UserOperationHash, MempoolEntry)> {
+        let expired: Vec<UserOperationHash> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expires_before(deadline))
+            .map(|(hash, _)| *hash)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|hash| self.remove(&hash).map(|entry| (hash, entry)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{H256, U256};
+
+    fn entry(nonce: u64, good_after: u64, valid_until: Option<u64>) -> MempoolEntry {
+        MempoolEntry {
+            user_operation: UserOperation::default().nonce(U256::from(nonce)),
+            entry_point: Address::zero(),
+            good_after,
+            valid_until,
+            condition: None,
+            condition_met: false,
+            failed_attempts: 0,
+        }
+    }
+
+    fn hash(nonce: u64) -> UserOperationHash {
+        UserOperationHash(H256::from_low_u64_be(nonce))
+    }
+
+    #[test]
+    fn releases_parked_operations_once_valid_after_has_passed() {
+        let mut mempool = Mempool::default();
+        mempool.park(hash(1), entry(1, 100, None));
+        mempool.park(hash(2), entry(2, 200, None));
+        assert_eq!(mempool.counts(), (2, 0, 0));
+
+        // an op is due strictly after its good-after timestamp
+        assert!(mempool.take_due(100).is_empty());
+        let due = mempool.take_due(101);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].0, hash(1));
+        assert!(mempool.get(&hash(1)).is_none());
+        assert_eq!(mempool.counts(), (1, 0, 0));
+
+        let due = mempool.take_due(u64::MAX);
+        assert_eq!(due[0].0, hash(2));
+        assert_eq!(mempool.counts(), (0, 0, 0));
+    }
+
+    #[test]
+    fn evicts_operations_expiring_before_the_deadline() {
+        let mut mempool = Mempool::default();
+        mempool.park(hash(1), entry(1, 100, Some(150)));
+        mempool.park(hash(2), entry(2, 100, Some(300)));
+        mempool.push_ready(hash(3), entry(3, 0, Some(120)));
+        mempool.park(hash(4), entry(4, 100, None));
+
+        assert!(!entry(0, 0, Some(150)).expires_before(150));
+        assert!(entry(0, 0, Some(150)).expires_before(151));
+        assert!(!entry(0, 0, None).expires_before(u64::MAX));
+
+        let evicted: Vec<_> = mempool
+            .evict_expired(160)
+            .into_iter()
+            .map(|(hash, _)| hash)
+            .collect();
+        assert_eq!(evicted.len(), 2);
+        assert!(evicted.contains(&hash(1)) && evicted.contains(&hash(3)));
+        assert_eq!(mempool.counts(), (2, 0, 0));
+        assert!(mempool.take_ready().is_empty());
+
+        let due: Vec<_> = mempool
+            .take_due(101)
+            .into_iter()
+            .map(|(hash, _)| hash)
+            .collect();
+        assert_eq!(due.len(), 2);
+        assert!(due.contains(&hash(2)) && due.contains(&hash(4)));
+    }
+
+    #[test]
+    fn latches_condition_of_parked_operation() {
+        let mut mempool = Mempool::default();
+        mempool.park(hash(1), entry(1, 100, None));
+        mempool.latch_condition(&hash(1));
+        mempool.latch_condition(&hash(2));
+
+        assert!(mempool
+            .get(&hash(1))
+            .is_some_and(|entry| entry.condition_met));
+        assert!(mempool.get(&hash(2)).is_none());
+    }
+
+    #[test]
+    fn remembers_cancelled_operations() {
+        let mut mempool = Mempool::default();
+        mempool.park(hash(1), entry(1, 100, None));
+
+        assert!(mempool.cancel(&hash(1), 50).is_some());
+        assert!(mempool.get(&hash(1)).is_none());
+        assert!(mempool.is_cancelled(&hash(1)));
+        assert!(!mempool.is_cancelled(&hash(2)));
+
+        // cancellations outlive a restart through the store
+        let mut restored = Mempool::default();
+        restored.set_cancellations(mempool.cancellations());
+        assert!(restored.is_cancelled(&hash(1)));
+
+        let (_, cancellations) = mempool.take_changes();
+        assert_eq!(cancellations, vec![(hash(1), Some(50))]);
+
+        assert!(restored.expire_cancellations(50).is_empty());
+        assert_eq!(restored.expire_cancellations(51), vec![hash(1)]);
+        assert!(!restored.is_cancelled(&hash(1)));
+    }
+}
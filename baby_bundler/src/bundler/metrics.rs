@@ -0,0 +1,299 @@
+use jsonrpsee::server::logger::{HttpRequest, Logger, MethodKind, Params, TransportProtocol};
+use prometheus::{
+    core::Collector, Counter, Encoder, Gauge, GaugeVec, Histogram, HistogramOpts, HistogramVec,
+    IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// `rpc_latency` label of the calls forwarded to the upstream nodes, whose
+/// method names come from the clients
+pub const PROXIED_METHOD_LABEL: &str = "proxied";
+
+/// `rpc_latency` label of the calls of methods the bundler does not serve
+pub const OTHER_METHOD_LABEL: &str = "other";
+
+/// Prometheus metrics of the bundler, served on `/metrics` by `JsonRpcServer`
+pub struct Metrics {
+    registry: Registry,
+    /// User operations held by the bundler, by state
+    pub mempool_size: IntGaugeVec,
+    pub ops_received: IntCounter,
+    /// User operations rejected on submission, by reason
+    pub ops_rejected: IntCounterVec,
+    /// User operations with a `UserOperationEvent` in an included bundle
+    pub ops_included: IntCounter,
+    /// Bundles sent, by relay
+    pub bundles_submitted: IntCounterVec,
+    /// Bundles that landed on chain, by relay
+    pub bundles_included: IntCounterVec,
+    /// Blocks between sending a bundle and its inclusion
+    pub inclusion_latency: Histogram,
+    /// Wei paid by the entry point to the beneficiary
+    pub beneficiary_revenue: Counter,
+    /// Wei spent on gas by the bundler EOA for `handleOps`
+    pub gas_spent: Counter,
+    /// Balance of the bundler EOAs in wei, summed over the signer pool
+    pub eoa_balance: Gauge,
+    /// Balance of each key of the signer pool in wei
+    pub signer_balance: GaugeVec,
+    /// Duration of the JSON-RPC calls in seconds, by method served by the
+    /// bundler, `proxied` or `other`
+    pub rpc_latency: HistogramVec,
+    /// Proxied calls answered from the cache, by method
+    pub proxy_cache_hits: IntCounterVec,
+    /// Proxied calls of cached methods sent to the upstreams, by method
+    pub proxy_cache_misses: IntCounterVec,
+    /// Transfers made by the sweeper, by direction
+    pub sweeps: IntCounterVec,
+    /// Times the bundler or funding wallet was found about to run dry
+    pub balance_alerts: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("baby_bundler".to_string()), None)
+            .expect("Should be able to create the metrics registry");
+
+        Self {
+            mempool_size: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("mempool_size", "User operations held, by state"),
+                    &["state"],
+                ),
+            ),
+            ops_received: register(
+                &registry,
+                IntCounter::new("ops_received_total", "User operations received"),
+            ),
+            ops_rejected: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("ops_rejected_total", "User operations rejected, by reason"),
+                    &["reason"],
+                ),
+            ),
+            ops_included: register(
+                &registry,
+                IntCounter::new("ops_included_total", "User operations included on chain"),
+            ),
+            bundles_submitted: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("bundles_submitted_total", "Bundles sent, by relay"),
+                    &["relay"],
+                ),
+            ),
+            bundles_included: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("bundles_included_total", "Bundles included, by relay"),
+                    &["relay"],
+                ),
+            ),
+            inclusion_latency: register(
+                &registry,
+                Histogram::with_opts(
+                    HistogramOpts::new(
+                        "inclusion_latency_blocks",
+                        "Blocks between sending a bundle and its inclusion",
+                    )
+                    .buckets(vec![1.0, 2.0, 3.0, 5.0, 10.0, 25.0]),
+                ),
+            ),
+            beneficiary_revenue: register(
+                &registry,
+                Counter::new(
+                    "beneficiary_revenue_wei_total",
+                    "Wei paid by the entry point to the beneficiary",
+                ),
+            ),
+            gas_spent: register(
+                &registry,
+                Counter::new("gas_spent_wei_total", "Wei spent on gas for handleOps"),
+            ),
+            eoa_balance: register(
+                &registry,
+                Gauge::new("eoa_balance_wei", "Balance of the bundler EOAs"),
+            ),
+            signer_balance: register(
+                &registry,
+                GaugeVec::new(
+                    Opts::new("signer_balance_wei", "Balance of the bundler EOA, by key"),
+                    &["signer"],
+                ),
+            ),
+            rpc_latency: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new("rpc_latency_seconds", "JSON-RPC call duration"),
+                    &["method"],
+                ),
+            ),
+            proxy_cache_hits: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "proxy_cache_hits_total",
+                        "Proxied calls answered from the cache",
+                    ),
+                    &["method"],
+                ),
+            ),
+            proxy_cache_misses: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "proxy_cache_misses_total",
+                        "Proxied calls of cached methods sent upstream",
+                    ),
+                    &["method"],
+                ),
+            ),
+            sweeps: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new("sweeps_total", "Sweeper transfers, by direction"),
+                    &["direction"],
+                ),
+            ),
+            balance_alerts: register(
+                &registry,
+                IntCounter::new("balance_alerts_total", "Low balance alerts"),
+            ),
+            registry,
+        }
+    }
+
+    /// Renders every metric in the Prometheus text format
+    pub fn encode(&self) -> anyhow::Result<String> {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn register<C: Collector + Clone + 'static>(
+    registry: &Registry,
+    collector: prometheus::Result<C>,
+) -> C {
+    let collector = collector.expect("Metric options should be valid");
+    registry
+        .register(Box::new(collector.clone()))
+        .expect("Metric should only be registered once");
+    collector
+}
+
+/// jsonrpsee logger recording the latency of every JSON-RPC call
+#[derive(Clone, Default)]
+pub struct RpcMetricsLogger {
+    metrics: Option<Arc<Metrics>>,
+    /// Methods served by the bundler, any other name is labelled `other`
+    methods: Arc<HashSet<&'static str>>,
+}
+
+impl RpcMetricsLogger {
+    pub fn new(metrics: Option<Arc<Metrics>>) -> Self {
+        Self {
+            metrics,
+            methods: Arc::default(),
+        }
+    }
+
+    pub fn with_methods(mut self, methods: impl IntoIterator<Item = &'static str>) -> Self {
+        self.methods = Arc::new(methods.into_iter().collect());
+        self
+    }
+
+    fn label<'a>(&self, method_name: &'a str) -> &'a str {
+        if self.methods.contains(method_name) {
+            method_name
+        } else {
+            OTHER_METHOD_LABEL
+        }
+    }
+}
+
+impl Logger for RpcMetricsLogger {
+    type Instant = Instant;
+
+    fn on_connect(&self, _remote_addr: SocketAddr, _request: &HttpRequest, _t: TransportProtocol) {}
+
+    fn on_request(&self, _transport: TransportProtocol) -> Self::Instant {
+        Instant::now()
+    }
+
+    fn on_call(
+        &self,
+        _method_name: &str,
+        _params: Params,
+        _kind: MethodKind,
+        _transport: TransportProtocol,
+    ) {
+    }
+
+    fn on_result(
+        &self,
+        method_name: &str,
+        _success: bool,
+        started_at: Self::Instant,
+        _transport: TransportProtocol,
+    ) {
+        if let Some(metrics) = &self.metrics {
+            metrics
+                .rpc_latency
+                .with_label_values(&[self.label(method_name)])
+                .observe(started_at.elapsed().as_secs_f64());
+        }
+    }
+
+    fn on_response(
+        &self,
+        _result: &str,
+        _started_at: Self::Instant,
+        _transport: TransportProtocol,
+    ) {
+    }
+
+    fn on_disconnect(&self, _remote_addr: SocketAddr, _transport: TransportProtocol) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_unknown_methods_as_other() {
+        let logger = RpcMetricsLogger::new(Some(Arc::new(Metrics::new())))
+            .with_methods(["eth_sendUserOperation"]);
+        for method in [
+            "eth_sendUserOperation",
+            "random_method_1",
+            "random_method_2",
+        ] {
+            let started_at = logger.on_request(TransportProtocol::Http);
+            logger.on_result(method, true, started_at, TransportProtocol::Http);
+        }
+
+        let metrics = logger.metrics.expect("metrics set");
+        let latency = |label: &str| {
+            metrics
+                .rpc_latency
+                .with_label_values(&[label])
+                .get_sample_count()
+        };
+        assert_eq!(latency("eth_sendUserOperation"), 1);
+        assert_eq!(latency(OTHER_METHOD_LABEL), 2);
+        let encoded = metrics.encode().expect("metrics encode");
+        assert!(!encoded.contains("random_method"));
+    }
+}
@@ -1,3 +1,23 @@
 #![allow(clippy::module_inception)]
+pub mod auth;
+pub mod body_limit;
 pub mod bundler;
+pub mod cache;
+pub mod cancel;
+pub mod debug;
+pub mod events;
+pub mod health;
+pub mod intent;
+pub mod mempool;
+pub mod metrics;
+pub mod operator;
+pub mod rate_limit;
+pub mod reputation;
+pub mod schedule;
 pub mod server;
+pub mod signer;
+pub mod signer_pool;
+pub mod store;
+pub mod sweeper;
+pub mod trace;
+pub mod upstream;
@@ -0,0 +1,147 @@
+use crate::bindings::entrypointgoerli::{entrypointgoerli, DepositInfo};
+use crate::bundler::signer::BundlerSigner;
+use ethers::{
+    providers::Middleware,
+    types::{
+        transaction::eip2718::TypedTransaction, Address, BlockNumber, Bytes, TransactionReceipt,
+        U256,
+    },
+};
+use std::sync::Arc;
+
+/// Entry point call managing the deposit or stake of the bundler
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OperatorAction {
+    /// `depositTo`, funding the deposit of `account` with `amount` wei
+    Deposit { account: Address, amount: U256 },
+    /// `withdrawTo`, sending `amount` wei of the deposit to `to`
+    Withdraw { to: Address, amount: U256 },
+    /// `addStake`, staking `amount` wei with the given unstake delay
+    Stake {
+        unstake_delay_sec: u32,
+        amount: U256,
+    },
+    /// `unlockStake`, starting the unstake delay
+    Unlock,
+    /// `withdrawStake`, sending the unlocked stake to `to`
+    WithdrawStake { to: Address },
+}
+
+/// Outcome of an operator action
+#[derive(Clone, Debug)]
+pub enum OperatorOutcome {
+    /// Signed transaction that was not sent
+    DryRun {
+        tx: TypedTransaction,
+        raw_tx: Bytes,
+    },
+    Sent {
+        receipt: Option<TransactionReceipt>,
+    },
+}
+
+/// Manages the entry point deposit and stake of the bundler EOA
+pub struct Operator<M: Middleware> {
+    eth_provider: Arc<M>,
+    entry_point: Address,
+    signer: Arc<dyn BundlerSigner>,
+}
+
+impl<M> Operator<M>
+where
+    M: Middleware + 'static,
+{
+    pub fn new(eth_provider: Arc<M>, entry_point: Address, signer: Arc<dyn BundlerSigner>) -> Self {
+        Self {
+            eth_provider,
+            entry_point,
+            signer,
+        }
+    }
+
+    pub fn address(&self) -> Address {
+        self.signer.address()
+    }
+
+    /// Deposit and stake of `account` in the entry point
+    pub async fn deposit_info(&self, account: Address) -> anyhow::Result<DepositInfo> {
+        Ok(self.entry_point().get_deposit_info(account).call().await?)
+    }
+
+    /// Signs the transaction of `action` and sends it unless `dry_run` is set
+    pub async fn execute(
+        &self,
+        action: OperatorAction,
+        dry_run: bool,
+    ) -> anyhow::Result<OperatorOutcome> {
+        let entry_point = self.entry_point();
+        let call = match action {
+            OperatorAction::Deposit { account, amount } => {
+                entry_point.deposit_to(account).value(amount)
+            }
+            OperatorAction::Withdraw { to, amount } => entry_point.withdraw_to(to, amount),
+            OperatorAction::Stake {
+                unstake_delay_sec,
+                amount,
+            } => entry_point.add_stake(unstake_delay_sec).value(amount),
+            OperatorAction::Unlock => entry_point.unlock_stake(),
+            OperatorAction::WithdrawStake { to } => entry_point.withdraw_stake(to),
+        };
+
+        let mut tx = call.tx;
+        let raw_tx =
+            sign_transaction(self.eth_provider.as_ref(), self.signer.as_ref(), &mut tx).await?;
+        if dry_run {
+            return Ok(OperatorOutcome::DryRun { tx, raw_tx });
+        }
+
+        let receipt = send_transaction(self.eth_provider.as_ref(), raw_tx).await?;
+        Ok(OperatorOutcome::Sent { receipt })
+    }
+
+    fn entry_point(&self) -> entrypointgoerli::entrypointgoerli<M> {
+        entrypointgoerli::entrypointgoerli::new(self.entry_point, self.eth_provider.clone())
+    }
+}
+
+/// Fills the nonce, unless already reserved, gas and fees of a transaction
+/// sent by `signer` and signs it
+pub(crate) async fn sign_transaction<M: Middleware>(
+    eth_provider: &M,
+    signer: &dyn BundlerSigner,
+    tx: &mut TypedTransaction,
+) -> anyhow::Result<Bytes> {
+    tx.set_from(signer.address());
+    if tx.nonce().is_none() {
+        let nonce = eth_provider
+            .get_transaction_count(signer.address(), Some(BlockNumber::Pending.into()))
+            .await
+            .map_err(|err| anyhow::anyhow!("Failed to fetch nonce: {err}"))?;
+        tx.set_nonce(nonce);
+    }
+    tx.set_chain_id(
+        eth_provider
+            .get_chainid()
+            .await
+            .map_err(|err| anyhow::anyhow!("Failed to fetch chain id: {err}"))?
+            .as_u64(),
+    );
+    eth_provider
+        .fill_transaction(tx, None)
+        .await
+        .map_err(|err| anyhow::anyhow!("Failed to fill transaction: {err}"))?;
+    signer.sign_transaction(tx).await
+}
+
+/// Sends a signed transaction and waits for its receipt, none if it was dropped
+pub(crate) async fn send_transaction<M: Middleware>(
+    eth_provider: &M,
+    raw_tx: Bytes,
+) -> anyhow::Result<Option<TransactionReceipt>> {
+    let pending = eth_provider
+        .send_raw_transaction(raw_tx)
+        .await
+        .map_err(|err| anyhow::anyhow!("Failed to send transaction: {err}"))?;
+    log::info!("Sent transaction {:?}", pending.tx_hash());
+    Ok(pending.await?)
+}
@@ -0,0 +1,599 @@
+use crate::bundler::server::{error_response, is_websocket_upgrade, parse_calls, PeerAddr};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use jsonrpsee::{
+    server::{MethodCallback, MethodResponse},
+    types::{error::ErrorCode, ErrorObject, Id, Params, ResponsePayload},
+    Methods,
+};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tower::{Layer, Service};
+
+/// EIP-1474 error code returned when a rate limit is hit
+pub const LIMIT_EXCEEDED_CODE: i32 = -32005;
+
+/// Method the server calls first on every WebSocket connection it opens to
+/// the jsonrpsee server, binding the connection to the IP of the client
+pub(crate) const BIND_CONNECTION_METHOD: &str = "rateLimit_bindConnection";
+
+/// Response of a method call made over WebSocket
+type CallFuture = Pin<Box<dyn Future<Output = MethodResponse> + Send>>;
+
+/// Buckets kept before the idle ones are dropped
+const MAX_BUCKETS: usize = 10_000;
+
+/// Time after which an unused bucket has refilled and can be dropped
+const IDLE_BUCKET_TTL: Duration = Duration::from_secs(600);
+
+/// Methods whose first parameter is a user operation, or a list of them, with a `sender`
+const SENDER_METHODS: [&str; 4] = [
+    "eth_sendUserOperation",
+    "eth_estimateUserOperationGas",
+    "bundler_sendConditionalUserOperation",
+    "bundler_submitSchedule",
+];
+
+#[derive(Clone, Copy, Debug)]
+pub struct BucketConfig {
+    /// Tokens a client can spend in a burst
+    pub capacity: f64,
+    /// Tokens given back every second
+    pub refill_per_second: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    /// Bucket of every client IP, shared by its HTTP requests and WebSocket calls
+    pub ip: BucketConfig,
+    /// Bucket of every user operation sender
+    pub sender: BucketConfig,
+    /// Tokens taken by a call of each method, `default_cost` if not listed
+    pub method_costs: HashMap<String, u32>,
+    pub default_cost: u32,
+    /// Reverse proxies whose `X-Forwarded-For` header is trusted
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let method_costs = [
+            ("eth_sendUserOperation", 10),
+            ("eth_estimateUserOperationGas", 10),
+            ("bundler_sendConditionalUserOperation", 10),
+            ("bundler_submitSchedule", 20),
+            ("bundler_cancelUserOperation", 5),
+        ]
+        .into_iter()
+        .map(|(method, cost)| (method.to_string(), cost))
+        .collect();
+
+        Self {
+            ip: BucketConfig {
+                capacity: 100.0,
+                refill_per_second: 10.0,
+            },
+            sender: BucketConfig {
+                capacity: 30.0,
+                refill_per_second: 1.0,
+            },
+            method_costs,
+            default_cost: 1,
+            trusted_proxies: vec![],
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn with_ip(mut self, ip: BucketConfig) -> Self {
+        self.ip = ip;
+        self
+    }
+
+    pub fn with_sender(mut self, sender: BucketConfig) -> Self {
+        self.sender = sender;
+        self
+    }
+
+    pub fn with_method_cost(mut self, method: impl Into<String>, cost: u32) -> Self {
+        self.method_costs.insert(method.into(), cost);
+        self
+    }
+
+    pub fn with_trusted_proxies(mut self, trusted_proxies: Vec<IpAddr>) -> Self {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    fn cost(&self, method: &str) -> u32 {
+        self.method_costs
+            .get(method)
+            .copied()
+            .unwrap_or(self.default_cost)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn refill(&mut self, config: &BucketConfig, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.refill_per_second).min(config.capacity);
+        self.updated = now;
+    }
+}
+
+/// Token buckets of every client, keyed by IP or sender
+#[derive(Debug, Default)]
+struct Buckets {
+    buckets: HashMap<String, TokenBucket>,
+}
+
+impl Buckets {
+    /// Takes its cost from each bucket of `costs`, or nothing if one of the
+    /// buckets does not hold enough tokens, returning its key
+    fn try_take(&mut self, costs: &[(String, BucketConfig, f64)]) -> Result<(), String> {
+        self.try_take_at(costs, Instant::now())
+    }
+
+    fn try_take_at(
+        &mut self,
+        costs: &[(String, BucketConfig, f64)],
+        now: Instant,
+    ) -> Result<(), String> {
+        if self.buckets.len() > MAX_BUCKETS {
+            self.buckets
+                .retain(|_, bucket| now.duration_since(bucket.updated) < IDLE_BUCKET_TTL);
+        }
+
+        for (key, config, cost) in costs {
+            let bucket = self.buckets.entry(key.clone()).or_insert(TokenBucket {
+                tokens: config.capacity,
+                updated: now,
+            });
+            bucket.refill(config, now);
+            if bucket.tokens < *cost {
+                return Err(key.clone());
+            }
+        }
+        for (key, _, cost) in costs {
+            if let Some(bucket) = self.buckets.get_mut(key) {
+                bucket.tokens -= cost;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Throttles JSON-RPC requests per client IP and per user operation sender.
+/// The client IP is the peer address, or the address a trusted reverse proxy
+/// put in `X-Forwarded-For`. The calls made over an open WebSocket connection
+/// do not go through the middleware and are limited one by one by the methods
+/// of `limit_methods`, against the bucket of the IP the server bound the
+/// connection to with `BIND_CONNECTION_METHOD`.
+#[derive(Clone, Debug)]
+pub struct RateLimitLayer {
+    config: Arc<RateLimitConfig>,
+    buckets: Arc<Mutex<Buckets>>,
+    /// Client IP of every open WebSocket connection, by connection id
+    connections: Arc<Mutex<HashMap<usize, IpAddr>>>,
+}
+
+/// IP a request is charged to, set on WebSocket upgrades for the server to
+/// bind the connection to
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ClientIp(pub IpAddr);
+
+impl RateLimitLayer {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config: Arc::new(config),
+            buckets: Arc::new(Mutex::new(Buckets::default())),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Takes the cost of every call of `calls` from the buckets of `keys`
+    /// and of the sender of the user operation it carries, all at once so
+    /// that nothing is taken if one of them is empty, returning the error
+    /// message in that case
+    fn take(
+        &self,
+        keys: Vec<(String, BucketConfig)>,
+        calls: &[(&str, &Value)],
+    ) -> Result<(), String> {
+        let mut costs: Vec<(String, BucketConfig, f64)> = vec![];
+        let mut charge = |key: String, config: BucketConfig, cost: f64| match costs
+            .iter_mut()
+            .find(|(charged, _, _)| *charged == key)
+        {
+            Some((_, _, total)) => *total += cost,
+            None => costs.push((key, config, cost)),
+        };
+        for (method, params) in calls {
+            let cost = self.config.cost(method) as f64;
+            for (key, config) in &keys {
+                charge(key.clone(), *config, cost);
+            }
+            if let Some(sender) = sender(method, params) {
+                charge(format!("sender:{sender}"), self.config.sender, cost);
+            }
+        }
+        self.buckets
+            .lock()
+            .map_err(|_| "Rate limiter lock poisoned".to_string())?
+            .try_take(&costs)
+            .map_err(|key| format!("Rate limit exceeded for {key}"))
+    }
+
+    /// Wraps the method calls of `methods` so that each one made over a
+    /// WebSocket connection is charged to the bucket of the IP of the
+    /// connection, and adds `BIND_CONNECTION_METHOD`, only answered to the
+    /// calls carrying `token`. Subscriptions are left as they are, bounded per
+    /// connection by the server.
+    pub fn limit_methods(
+        &self,
+        methods: &Methods,
+        token: &str,
+    ) -> Result<Methods, jsonrpsee::core::Error> {
+        let mut limited = Methods::new();
+        for name in methods.method_names() {
+            let Some(callback) = methods.method(name).cloned() else {
+                continue;
+            };
+            let callback = match callback {
+                MethodCallback::Sync(callback) => {
+                    let limiter = self.clone();
+                    MethodCallback::Async(Arc::new(
+                        move |id: Id<'static>,
+                              params: Params<'static>,
+                              conn_id: usize,
+                              max_response_size: usize|
+                              -> CallFuture {
+                            let response = match limiter.take_ws(conn_id, name, &params) {
+                                Ok(()) => callback(id, params, max_response_size),
+                                Err(message) => limit_exceeded(id, message),
+                            };
+                            Box::pin(async move { response })
+                        },
+                    ))
+                }
+                MethodCallback::Async(callback) => {
+                    let limiter = self.clone();
+                    MethodCallback::Async(Arc::new(
+                        move |id: Id<'static>,
+                              params: Params<'static>,
+                              conn_id: usize,
+                              max_response_size: usize|
+                              -> CallFuture {
+                            match limiter.take_ws(conn_id, name, &params) {
+                                Ok(()) => callback(id, params, conn_id, max_response_size),
+                                Err(message) => {
+                                    let response = limit_exceeded(id, message);
+                                    Box::pin(async move { response })
+                                }
+                            }
+                        },
+                    ))
+                }
+                subscription => subscription,
+            };
+            limited.verify_and_insert(name, callback)?;
+        }
+
+        let limiter = self.clone();
+        let token = token.to_string();
+        limited.verify_and_insert(
+            BIND_CONNECTION_METHOD,
+            MethodCallback::Async(Arc::new(
+                move |id: Id<'static>,
+                      params: Params<'static>,
+                      conn_id: usize,
+                      max_response_size: usize|
+                      -> CallFuture {
+                    let response = match params.parse::<(String, IpAddr)>() {
+                        Ok((bind_token, ip)) if bind_token == token => {
+                            limiter.bind(conn_id, ip);
+                            MethodResponse::response(
+                                id,
+                                ResponsePayload::result(conn_id),
+                                max_response_size,
+                            )
+                        }
+                        // the method does not exist for the clients
+                        _ => {
+                            MethodResponse::error(id, ErrorObject::from(ErrorCode::MethodNotFound))
+                        }
+                    };
+                    Box::pin(async move { response })
+                },
+            )),
+        )?;
+        Ok(limited)
+    }
+
+    fn bind(&self, conn_id: usize, ip: IpAddr) {
+        if let Ok(mut connections) = self.connections.lock() {
+            connections.insert(conn_id, ip);
+        }
+    }
+
+    /// Forgets the IP of a WebSocket connection once it is closed
+    pub(crate) fn unbind(&self, conn_id: usize) {
+        if let Ok(mut connections) = self.connections.lock() {
+            connections.remove(&conn_id);
+        }
+    }
+
+    fn take_ws(&self, conn_id: usize, method: &str, params: &Params) -> Result<(), String> {
+        let ip = self
+            .connections
+            .lock()
+            .map_err(|_| "Rate limiter lock poisoned".to_string())?
+            .get(&conn_id)
+            .copied()
+            .ok_or_else(|| "WebSocket connection is not bound to a client".to_string())?;
+        let params: Value = params.parse().unwrap_or_default();
+        self.take(
+            vec![(format!("ip:{ip}"), self.config.ip)],
+            &[(method, &params)],
+        )
+    }
+}
+
+fn limit_exceeded(id: Id, message: String) -> MethodResponse {
+    MethodResponse::error(
+        id,
+        ErrorObject::owned(LIMIT_EXCEEDED_CODE, message, None::<()>),
+    )
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitRequest<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitRequest {
+            inner,
+            limiter: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimitRequest<S> {
+    inner: S,
+    limiter: RateLimitLayer,
+}
+
+impl<S> Service<Request<Body>> for RateLimitRequest<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Error: Into<Box<dyn Error + Send + Sync>> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Box<dyn Error + Send + Sync + 'static>;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let limiter = self.limiter.clone();
+
+        Box::pin(async move {
+            let ip = client_ip(&req, &limiter.config.trusted_proxies);
+            let ip_keys = || {
+                ip.iter()
+                    .map(|ip| (format!("ip:{ip}"), limiter.config.ip))
+                    .collect::<Vec<_>>()
+            };
+
+            // opening a WebSocket connection costs a call, its messages are
+            // then limited by the server against the IP of the client
+            if is_websocket_upgrade(&req) {
+                if let Err(message) = limiter.take(ip_keys(), &[("", &Value::Null)]) {
+                    return Ok(error_response(
+                        StatusCode::TOO_MANY_REQUESTS,
+                        &[],
+                        LIMIT_EXCEEDED_CODE,
+                        &message,
+                    )?);
+                }
+                if let Some(ip) = ip {
+                    req.extensions_mut().insert(ClientIp(ip));
+                }
+                return inner.call(req).await.map_err(Into::into);
+            }
+
+            if req.method() != Method::POST {
+                return inner.call(req).await.map_err(Into::into);
+            }
+
+            let (req_h, req_b) = req.into_parts();
+            let req_bb = hyper::body::to_bytes(req_b).await?;
+            let calls = parse_calls(&req_bb);
+
+            // a batch is charged as a whole, none of its calls is if one is limited
+            let charged = calls
+                .iter()
+                .map(|call| (call["method"].as_str().unwrap_or_default(), &call["params"]))
+                .collect::<Vec<_>>();
+            if let Err(message) = limiter.take(ip_keys(), &charged) {
+                return Ok(error_response(
+                    StatusCode::OK,
+                    &calls,
+                    LIMIT_EXCEEDED_CODE,
+                    &message,
+                )?);
+            }
+
+            inner
+                .call(Request::from_parts(req_h, Body::from(req_bb)))
+                .await
+                .map_err(Into::into)
+        })
+    }
+}
+
+/// Address of the client, the peer unless it is a trusted proxy, in which
+/// case the last address of `X-Forwarded-For` not appended by one
+fn client_ip(req: &Request<Body>, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = req.extensions().get::<PeerAddr>()?.0.ip();
+    if !trusted_proxies.contains(&peer) {
+        return Some(peer);
+    }
+
+    let mut client = peer;
+    let hops = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>();
+    for hop in hops.into_iter().rev() {
+        match hop.trim().parse() {
+            Ok(ip) if trusted_proxies.contains(&ip) => client = ip,
+            Ok(ip) => return Some(ip),
+            Err(_) => break,
+        }
+    }
+    Some(client)
+}
+
+/// Returns the sender of the user operation passed to `method`, if any
+fn sender(method: &str, params: &Value) -> Option<String> {
+    if !SENDER_METHODS.contains(&method) {
+        return None;
+    }
+    let user_operation = match &params[0] {
+        Value::Array(user_operations) => user_operations.first()?,
+        user_operation => user_operation,
+    };
+    user_operation["sender"]
+        .as_str()
+        .map(|sender| sender.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    const BUCKET: BucketConfig = BucketConfig {
+        capacity: 3.0,
+        refill_per_second: 1.0,
+    };
+
+    fn costs(keys: &[&str], cost: f64) -> Vec<(String, BucketConfig, f64)> {
+        keys.iter()
+            .map(|key| (key.to_string(), BUCKET, cost))
+            .collect()
+    }
+
+    fn request(peer: &str, forwarded_for: Option<&str>) -> Request<Body> {
+        let mut req = Request::new(Body::empty());
+        let peer: SocketAddr = peer.parse().unwrap();
+        req.extensions_mut().insert(PeerAddr(peer));
+        if let Some(forwarded_for) = forwarded_for {
+            req.headers_mut()
+                .insert("x-forwarded-for", forwarded_for.parse().unwrap());
+        }
+        req
+    }
+
+    #[test]
+    fn empties_and_refills_bucket() {
+        let mut buckets = Buckets::default();
+        let start = Instant::now();
+        let ip = ["ip:1.2.3.4"];
+
+        for _ in 0..3 {
+            assert!(buckets.try_take_at(&costs(&ip, 1.0), start).is_ok());
+        }
+        assert_eq!(
+            buckets.try_take_at(&costs(&ip, 1.0), start),
+            Err("ip:1.2.3.4".to_string())
+        );
+
+        // a token is given back every second, up to the capacity
+        let later = start + Duration::from_millis(1_500);
+        assert!(buckets.try_take_at(&costs(&ip, 1.0), later).is_ok());
+        assert!(buckets.try_take_at(&costs(&ip, 1.0), later).is_err());
+        let much_later = later + Duration::from_secs(60);
+        assert!(buckets.try_take_at(&costs(&ip, 3.0), much_later).is_ok());
+        assert!(buckets.try_take_at(&costs(&ip, 1.0), much_later).is_err());
+    }
+
+    #[test]
+    fn takes_from_no_bucket_if_one_is_empty() {
+        let mut buckets = Buckets::default();
+        let now = Instant::now();
+
+        assert!(buckets.try_take_at(&costs(&["sender:a"], 3.0), now).is_ok());
+        assert_eq!(
+            buckets.try_take_at(&costs(&["ip:1.2.3.4", "sender:a"], 1.0), now),
+            Err("sender:a".to_string())
+        );
+        // the IP bucket was left full
+        assert!(buckets
+            .try_take_at(&costs(&["ip:1.2.3.4"], 3.0), now)
+            .is_ok());
+    }
+
+    #[test]
+    fn charges_batch_as_a_whole() {
+        let bucket = BucketConfig {
+            capacity: 3.0,
+            refill_per_second: 0.0,
+        };
+        let limiter = RateLimitLayer::new(RateLimitConfig::default().with_ip(bucket));
+        let ip = || vec![("ip:1.2.3.4".to_string(), bucket)];
+        let params = Value::Null;
+        let call = ("eth_chainId", &params);
+
+        assert_eq!(
+            limiter.take(ip(), &[call; 4]),
+            Err("Rate limit exceeded for ip:1.2.3.4".to_string())
+        );
+        // the rejected batch took nothing
+        assert!(limiter.take(ip(), &[call; 3]).is_ok());
+        assert!(limiter.take(ip(), &[call]).is_err());
+    }
+
+    #[test]
+    fn trusts_forwarded_for_from_trusted_proxies_only() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "1.2.3.4".parse().unwrap();
+
+        // a client cannot pick its bucket with the header
+        let req = request("1.2.3.4:5000", Some("9.9.9.9"));
+        assert_eq!(client_ip(&req, &[proxy]), Some(client));
+
+        // the proxy appends the address of the client to what the client sent
+        let req = request("10.0.0.1:5000", Some("9.9.9.9, 1.2.3.4"));
+        assert_eq!(client_ip(&req, &[proxy]), Some(client));
+        let req = request("10.0.0.1:5000", Some("1.2.3.4, 10.0.0.1"));
+        assert_eq!(client_ip(&req, &[proxy]), Some(client));
+
+        let req = request("10.0.0.1:5000", None);
+        assert_eq!(client_ip(&req, &[proxy]), Some(proxy));
+        let req = request("10.0.0.1:5000", Some("1.2.3.4"));
+        assert_eq!(client_ip(&req, &[]), Some(proxy));
+    }
+}
@@ -0,0 +1,168 @@
+use aa_bundler_primitives::UserOperation;
+use ethers::types::Address;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ReputationStatus {
+    #[default]
+    Ok,
+    Throttled,
+    Banned,
+}
+
+/// Reputation of an entity in the format of the ERC-4337 bundler spec tests
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ReputationEntry {
+    pub address: Address,
+    pub ops_seen: u64,
+    pub ops_included: u64,
+    #[serde(default)]
+    pub status: ReputationStatus,
+}
+
+/// Reputation of the senders, factories and paymasters seen by the bundler
+#[derive(Default, Debug)]
+pub struct Reputation {
+    entries: HashMap<Address, ReputationEntry>,
+    /// Unix time at which each entry was last updated
+    updated_at: HashMap<Address, u64>,
+    /// Entries added, updated or removed since the last save
+    changed: HashSet<Address>,
+}
+
+impl Reputation {
+    /// Returns the sender, factory and paymaster of a user operation
+    pub fn entities(user_operation: &UserOperation) -> Vec<Address> {
+        let mut entities = vec![user_operation.sender];
+        for data in [
+            &user_operation.init_code,
+            &user_operation.paymaster_and_data,
+        ] {
+            if data.len() >= 20 {
+                entities.push(Address::from_slice(&data[..20]));
+            }
+        }
+        entities
+    }
+
+    fn entry(&mut self, address: Address) -> &mut ReputationEntry {
+        self.touch(address);
+        self.entries
+            .entry(address)
+            .or_insert_with(|| ReputationEntry {
+                address,
+                ops_seen: 0,
+                ops_included: 0,
+                status: ReputationStatus::Ok,
+            })
+    }
+
+    /// Returns the first entity of the user operation that is banned
+    pub fn banned_entity(&self, user_operation: &UserOperation) -> Option<Address> {
+        Self::entities(user_operation).into_iter().find(|address| {
+            matches!(self.entries.get(address), Some(entry) if entry.status == ReputationStatus::Banned)
+        })
+    }
+
+    pub fn add_seen(&mut self, user_operation: &UserOperation) {
+        for address in Self::entities(user_operation) {
+            self.entry(address).ops_seen += 1;
+        }
+    }
+
+    pub fn add_included(&mut self, user_operation: &UserOperation) {
+        for address in Self::entities(user_operation) {
+            self.entry(address).ops_included += 1;
+        }
+    }
+
+    pub fn set(&mut self, entries: Vec<ReputationEntry>) {
+        for entry in entries {
+            self.touch(entry.address);
+            self.entries.insert(entry.address, entry);
+        }
+    }
+
+    pub fn dump(&self) -> Vec<ReputationEntry> {
+        self.entries.values().cloned().collect()
+    }
+
+    fn touch(&mut self, address: Address) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_secs());
+        self.updated_at.insert(address, now);
+        self.changed.insert(address);
+    }
+
+    /// Drops the entries in good standing not updated since `timestamp`, a
+    /// throttled or banned entity is remembered
+    pub fn prune(&mut self, timestamp: u64) {
+        let stale: Vec<Address> = self
+            .entries
+            .values()
+            .filter(|entry| {
+                entry.status == ReputationStatus::Ok
+                    && self
+                        .updated_at
+                        .get(&entry.address)
+                        .is_none_or(|updated_at| *updated_at < timestamp)
+            })
+            .map(|entry| entry.address)
+            .collect();
+        for address in stale {
+            self.entries.remove(&address);
+            self.updated_at.remove(&address);
+            self.changed.insert(address);
+        }
+    }
+
+    /// Returns the entries changed since the last call, `None` for the ones removed
+    pub fn take_changes(&mut self) -> Vec<(Address, Option<ReputationEntry>)> {
+        std::mem::take(&mut self.changed)
+            .into_iter()
+            .map(|address| (address, self.entries.get(&address).cloned()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prunes_stale_entries_in_good_standing() {
+        let mut reputation = Reputation::default();
+        let user_operation = UserOperation::default();
+        reputation.add_seen(&user_operation);
+        reputation.set(vec![ReputationEntry {
+            address: Address::repeat_byte(1),
+            ops_seen: 10,
+            ops_included: 0,
+            status: ReputationStatus::Banned,
+        }]);
+        assert_eq!(reputation.take_changes().len(), 2);
+
+        reputation.prune(0);
+        assert_eq!(reputation.dump().len(), 2);
+        assert!(reputation.take_changes().is_empty());
+
+        reputation.prune(u64::MAX);
+        assert_eq!(
+            reputation
+                .dump()
+                .iter()
+                .map(|entry| entry.address)
+                .collect::<Vec<_>>(),
+            vec![Address::repeat_byte(1)]
+        );
+        assert_eq!(
+            reputation.take_changes(),
+            vec![(user_operation.sender, None)]
+        );
+    }
+}
@@ -0,0 +1,461 @@
+use crate::sdk::gat_tx;
+use aa_bundler_primitives::{UserOperation, UserOperationHash};
+use ethers::{
+    types::{Address, H256, U256},
+    utils::keccak256,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// What to do when a scheduled user operation no longer executes successfully
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum FailurePolicy {
+    /// Bundle the user operation anyway so its nonce is used and the schedule
+    /// moves on, the call itself reverts on chain
+    Skip,
+    /// Stop the schedule, no later user operation is bundled
+    Halt,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "camelCase")]
+pub enum ScheduledOperationState {
+    Pending,
+    Submitted { bundle_hash: H256 },
+    Skipped { bundle_hash: H256, reason: String },
+    Failed { reason: String },
+    Cancelled,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduledOperation {
+    pub user_operation: UserOperation,
+    pub hash: UserOperationHash,
+    /// Timestamp from the GatTx trailer after which the user operation is due
+    pub good_after: u64,
+    #[serde(flatten)]
+    pub state: ScheduledOperationState,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ScheduleStatus {
+    Active,
+    Completed,
+    Halted,
+    Cancelled,
+}
+
+/// An ordered list of pre-signed user operations released one by one
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Schedule {
+    pub id: H256,
+    pub entry_point: Address,
+    pub policy: FailurePolicy,
+    pub status: ScheduleStatus,
+    /// Index of the next user operation to release
+    pub next: usize,
+    pub operations: Vec<ScheduledOperation>,
+}
+
+impl Schedule {
+    /// Checks that the user operations share a sender, use sequential nonces and
+    /// carry non-decreasing GatTx timestamps
+    pub fn new(
+        user_operations: Vec<UserOperation>,
+        entry_point: Address,
+        chain_id: U256,
+        policy: FailurePolicy,
+    ) -> Result<Self, String> {
+        let first = user_operations
+            .first()
+            .ok_or("Schedule has no user operations")?;
+
+        let mut operations: Vec<ScheduledOperation> = vec![];
+        for (index, user_operation) in user_operations.iter().enumerate() {
+            if user_operation.sender != first.sender {
+                return Err(format!("User operation {index} has a different sender"));
+            }
+            if user_operation.nonce != first.nonce + index {
+                return Err(format!("User operation {index} has a non-sequential nonce"));
+            }
+
+            let (timestamp, enabled) = gat_tx::decode(&user_operation.call_data);
+            if !enabled {
+                return Err(format!("User operation {index} has no ENABLE_GAT trailer"));
+            }
+            let good_after = timestamp.min(U256::from(u64::MAX)).as_u64();
+            if matches!(operations.last(), Some(previous) if previous.good_after > good_after) {
+                return Err(format!(
+                    "User operation {index} is due before the previous one"
+                ));
+            }
+
+            operations.push(ScheduledOperation {
+                user_operation: user_operation.clone(),
+                hash: user_operation.hash(&entry_point, &chain_id),
+                good_after,
+                state: ScheduledOperationState::Pending,
+            });
+        }
+
+        let hashes: Vec<u8> = operations
+            .iter()
+            .flat_map(|operation| operation.hash.0.to_fixed_bytes())
+            .collect();
+
+        Ok(Self {
+            id: H256::from(keccak256(hashes)),
+            entry_point,
+            policy,
+            status: ScheduleStatus::Active,
+            next: 0,
+            operations,
+        })
+    }
+
+    /// Returns the next user operation if the schedule is active and it is due
+    pub fn due(&self, timestamp: u64) -> Option<&ScheduledOperation> {
+        if self.status != ScheduleStatus::Active {
+            return None;
+        }
+        self.operations.get(self.next).filter(|operation| {
+            operation.state == ScheduledOperationState::Pending && operation.good_after < timestamp
+        })
+    }
+
+    /// Records the outcome of the next user operation and moves the schedule on
+    pub fn advance(&mut self, state: ScheduledOperationState) {
+        let halted = matches!(state, ScheduledOperationState::Failed { .. });
+        if let Some(operation) = self.operations.get_mut(self.next) {
+            operation.state = state;
+        }
+
+        if halted {
+            self.status = ScheduleStatus::Halted;
+        } else {
+            self.next += 1;
+            match self.operations.get(self.next) {
+                None => self.status = ScheduleStatus::Completed,
+                Some(operation) if operation.state == ScheduledOperationState::Cancelled => {
+                    self.status = ScheduleStatus::Cancelled
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    /// Records that the next user operation was sent in a bundle, the schedule
+    /// only moves on once the bundle lands
+    pub fn mark_submitted(&mut self, state: ScheduledOperationState) {
+        if let Some(operation) = self.operations.get_mut(self.next) {
+            operation.state = state;
+        }
+    }
+
+    /// Moves on past the next user operation once its bundle landed
+    pub fn confirm(&mut self) {
+        if let Some(state) = self.submitted_state() {
+            self.advance(state);
+        }
+    }
+
+    /// Makes the next user operation due again when its bundle never landed
+    pub fn roll_back(&mut self) {
+        if self.submitted_state().is_some() {
+            self.operations[self.next].state = ScheduledOperationState::Pending;
+        }
+    }
+
+    fn submitted_state(&self) -> Option<ScheduledOperationState> {
+        self.operations
+            .get(self.next)
+            .map(|operation| operation.state.clone())
+            .filter(|state| {
+                matches!(
+                    state,
+                    ScheduledOperationState::Submitted { .. }
+                        | ScheduledOperationState::Skipped { .. }
+                )
+            })
+    }
+
+    /// Cancels the user operation at `index` along with every later one, as their
+    /// nonces can no longer be reached
+    pub fn cancel_from(&mut self, index: usize) {
+        for operation in self.operations.iter_mut().skip(index) {
+            operation.state = ScheduledOperationState::Cancelled;
+        }
+        if self.status == ScheduleStatus::Active && self.next >= index {
+            self.status = ScheduleStatus::Cancelled;
+        }
+    }
+}
+
+/// Schedules submitted through `bundler_submitSchedule`, keyed by id
+#[derive(Default, Debug)]
+pub struct Schedules {
+    schedules: HashMap<H256, Schedule>,
+    /// Schedules inserted or borrowed mutably since the last save
+    changed: HashSet<H256>,
+}
+
+impl Schedules {
+    pub fn insert(&mut self, schedule: Schedule) {
+        self.changed.insert(schedule.id);
+        self.schedules.insert(schedule.id, schedule);
+    }
+
+    pub fn get(&self, id: &H256) -> Option<&Schedule> {
+        self.schedules.get(id)
+    }
+
+    pub fn get_mut(&mut self, id: &H256) -> Option<&mut Schedule> {
+        let schedule = self.schedules.get_mut(id)?;
+        self.changed.insert(*id);
+        Some(schedule)
+    }
+
+    /// Returns a copy of every schedule changed since the last call
+    pub fn take_changes(&mut self) -> Vec<Schedule> {
+        std::mem::take(&mut self.changed)
+            .into_iter()
+            .filter_map(|id| self.schedules.get(&id).cloned())
+            .collect()
+    }
+
+    /// Returns the number of user operations of active schedules not released yet
+    pub fn pending_count(&self) -> usize {
+        self.schedules
+            .values()
+            .filter(|schedule| schedule.status == ScheduleStatus::Active)
+            .flat_map(|schedule| schedule.operations.iter())
+            .filter(|operation| operation.state == ScheduledOperationState::Pending)
+            .count()
+    }
+
+    /// Finds a user operation that has not been released yet, returning the id of
+    /// its schedule, its index and the user operation
+    pub fn find_pending(&self, hash: &UserOperationHash) -> Option<(H256, usize, UserOperation)> {
+        self.schedules.values().find_map(|schedule| {
+            schedule
+                .operations
+                .iter()
+                .position(|operation| {
+                    operation.hash == *hash && operation.state == ScheduledOperationState::Pending
+                })
+                .map(|index| {
+                    (
+                        schedule.id,
+                        index,
+                        schedule.operations[index].user_operation.clone(),
+                    )
+                })
+        })
+    }
+
+    /// Returns a copy of every schedule whose next user operation is due
+    pub fn due(&self, timestamp: u64) -> Vec<Schedule> {
+        self.schedules
+            .values()
+            .filter(|schedule| schedule.due(timestamp).is_some())
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::Bytes;
+
+    fn user_operation(nonce: u64, good_after: u64) -> UserOperation {
+        UserOperation::default()
+            .sender(Address::repeat_byte(1))
+            .nonce(U256::from(nonce))
+            .call_data(gat_tx::encode(Bytes::default(), U256::from(good_after)))
+    }
+
+    fn schedule(policy: FailurePolicy) -> Schedule {
+        let user_operations = (0..3)
+            .map(|index| user_operation(5 + index, 100 * (index + 1)))
+            .collect();
+        Schedule::new(user_operations, Address::zero(), U256::from(80001), policy)
+            .expect("valid schedule")
+    }
+
+    #[test]
+    fn rejects_operations_out_of_order() {
+        let new = |user_operations| {
+            Schedule::new(
+                user_operations,
+                Address::zero(),
+                U256::from(80001),
+                FailurePolicy::Halt,
+            )
+        };
+
+        assert!(new(vec![]).is_err());
+        assert!(new(vec![user_operation(5, 100), user_operation(7, 200)])
+            .unwrap_err()
+            .contains("non-sequential nonce"));
+        assert!(new(vec![user_operation(5, 200), user_operation(6, 100)])
+            .unwrap_err()
+            .contains("due before the previous one"));
+        assert!(new(vec![
+            user_operation(5, 100),
+            user_operation(6, 200).sender(Address::repeat_byte(2))
+        ])
+        .unwrap_err()
+        .contains("different sender"));
+        assert!(new(vec![UserOperation::default()])
+            .unwrap_err()
+            .contains("no ENABLE_GAT trailer"));
+    }
+
+    #[test]
+    fn releases_operations_one_by_one() {
+        let mut schedule = schedule(FailurePolicy::Halt);
+        let mut schedules = Schedules::default();
+        schedules.insert(schedule.clone());
+        assert_eq!(schedules.pending_count(), 3);
+        assert_eq!(schedules.take_changes().len(), 1);
+        assert!(schedules.take_changes().is_empty());
+        assert!(schedules.due(100).is_empty());
+
+        // only the next operation is due, even once the later ones are
+        let due = schedule.due(u64::MAX).expect("first operation due");
+        assert_eq!(due.user_operation.nonce, U256::from(5));
+        assert_eq!(due.good_after, 100);
+        assert!(schedule.due(100).is_none());
+
+        let bundle_hash = H256::repeat_byte(9);
+        schedule.advance(ScheduledOperationState::Submitted { bundle_hash });
+        assert_eq!(schedule.next, 1);
+        assert_eq!(schedule.status, ScheduleStatus::Active);
+        assert_eq!(
+            schedule.operations[0].state,
+            ScheduledOperationState::Submitted { bundle_hash }
+        );
+        assert_eq!(
+            schedule
+                .due(u64::MAX)
+                .map(|operation| operation.user_operation.nonce),
+            Some(U256::from(6))
+        );
+
+        schedule.advance(ScheduledOperationState::Submitted { bundle_hash });
+        schedule.advance(ScheduledOperationState::Submitted { bundle_hash });
+        assert_eq!(schedule.next, 3);
+        assert_eq!(schedule.status, ScheduleStatus::Completed);
+        assert!(schedule.due(u64::MAX).is_none());
+    }
+
+    #[test]
+    fn moves_on_only_once_submitted_operation_lands() {
+        let mut schedule = schedule(FailurePolicy::Halt);
+        let bundle_hash = H256::repeat_byte(9);
+        schedule.mark_submitted(ScheduledOperationState::Submitted { bundle_hash });
+        assert_eq!(schedule.next, 0);
+        assert!(schedule.due(u64::MAX).is_none());
+
+        // the bundle timed out, the operation is released again
+        schedule.roll_back();
+        assert_eq!(
+            schedule.due(u64::MAX).map(|operation| operation.hash),
+            Some(schedule.operations[0].hash)
+        );
+
+        schedule.mark_submitted(ScheduledOperationState::Submitted { bundle_hash });
+        schedule.confirm();
+        assert_eq!(schedule.next, 1);
+        assert_eq!(
+            schedule.operations[0].state,
+            ScheduledOperationState::Submitted { bundle_hash }
+        );
+
+        // nothing to confirm or roll back until the next one is sent
+        schedule.confirm();
+        schedule.roll_back();
+        assert_eq!(schedule.next, 1);
+        assert_eq!(
+            schedule.operations[1].state,
+            ScheduledOperationState::Pending
+        );
+    }
+
+    #[test]
+    fn skip_policy_moves_on_and_halt_policy_stops() {
+        let bundle_hash = H256::repeat_byte(9);
+        let mut skipped = schedule(FailurePolicy::Skip);
+        skipped.advance(ScheduledOperationState::Skipped {
+            bundle_hash,
+            reason: "reverted".to_string(),
+        });
+        assert_eq!(skipped.status, ScheduleStatus::Active);
+        assert_eq!(skipped.next, 1);
+
+        let mut halted = schedule(FailurePolicy::Halt);
+        halted.advance(ScheduledOperationState::Failed {
+            reason: "reverted".to_string(),
+        });
+        assert_eq!(halted.status, ScheduleStatus::Halted);
+        assert_eq!(halted.next, 0);
+        assert!(halted.due(u64::MAX).is_none());
+
+        let mut schedules = Schedules::default();
+        schedules.insert(halted);
+        assert_eq!(schedules.pending_count(), 0);
+    }
+
+    #[test]
+    fn cancels_remaining_operations() {
+        let mut schedule = schedule(FailurePolicy::Halt);
+        let hash = schedule.operations[1].hash;
+        let mut schedules = Schedules::default();
+        schedules.insert(schedule.clone());
+        assert_eq!(
+            schedules
+                .find_pending(&hash)
+                .map(|(id, index, _)| (id, index)),
+            Some((schedule.id, 1))
+        );
+
+        // the first operation is still released, the schedule stops after it
+        schedule.cancel_from(1);
+        assert_eq!(schedule.status, ScheduleStatus::Active);
+        assert!(schedule.due(u64::MAX).is_some());
+        schedule.advance(ScheduledOperationState::Submitted {
+            bundle_hash: H256::zero(),
+        });
+        assert_eq!(schedule.status, ScheduleStatus::Cancelled);
+        assert_eq!(
+            schedule.operations[2].state,
+            ScheduledOperationState::Cancelled
+        );
+    }
+
+    #[test]
+    fn progress_survives_json_round_trip() -> anyhow::Result<()> {
+        // the store keeps schedules as JSON
+        let mut schedule = schedule(FailurePolicy::Skip);
+        schedule.advance(ScheduledOperationState::Skipped {
+            bundle_hash: H256::repeat_byte(9),
+            reason: "reverted".to_string(),
+        });
+
+        let restored: Schedule = serde_json::from_slice(&serde_json::to_vec(&schedule)?)?;
+        assert_eq!(restored.id, schedule.id);
+        assert_eq!(restored.next, 1);
+        assert_eq!(restored.status, ScheduleStatus::Active);
+        assert_eq!(restored.operations[0].state, schedule.operations[0].state);
+        assert_eq!(
+            restored.operations[1].state,
+            ScheduledOperationState::Pending
+        );
+        Ok(())
+    }
+}
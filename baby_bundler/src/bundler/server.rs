@@ -1,20 +1,42 @@
 // Credit to AA-bundler's RPC crate: https://github.com/Vid201/aa-bundler/tree/main/crates/rpc
+use crate::bundler::{
+    auth::{ApiKeys, AuthLayer},
+    body_limit::{BodyLimitLayer, DEFAULT_MAX_REQUEST_BODY_SIZE},
+    metrics::{Metrics, RpcMetricsLogger, PROXIED_METHOD_LABEL},
+    rate_limit::{ClientIp, RateLimitConfig, RateLimitLayer, BIND_CONNECTION_METHOD},
+    trace::TraceLayer,
+    upstream::{ProxyConfig, UpstreamPool},
+};
 use anyhow;
+use async_trait::async_trait;
+use ethers::core::rand;
+use hyper::{
+    client::HttpConnector,
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Client, Server, Uri,
+};
 use hyper::{http::HeaderValue, Method};
-use hyper::{Body, Request, Response};
-use jsonrpsee::core::error::Error as JsonRpcError;
+use hyper::{Body, Request, Response, StatusCode};
 use jsonrpsee::types::error::{ErrorCode, METHOD_NOT_FOUND_MSG};
 use jsonrpsee::types::ErrorObjectOwned;
 use jsonrpsee::{
     server::{ServerBuilder, ServerHandle},
     Methods,
 };
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
 use std::error::Error;
 use std::future::Future;
+use std::net::{IpAddr, SocketAddr, TcpListener};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::Context;
 use std::task::Poll;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tower::ServiceBuilder;
 use tower::{Layer, Service};
 use tower_http::cors::{AllowOrigin, Any, CorsLayer};
@@ -22,6 +44,11 @@ pub struct JsonRpcServer {
     listen_address: String,
     cors_layer: Option<CorsLayer>,
     proxy_layer: Option<ProxyJsonRpcLayer>,
+    metrics_layer: Option<MetricsLayer>,
+    health_layer: Option<HealthLayer>,
+    rate_limit_layer: Option<RateLimitLayer>,
+    auth_layer: Option<AuthLayer>,
+    max_request_body_size: u32,
 }
 
 impl JsonRpcServer {
@@ -30,6 +57,11 @@ impl JsonRpcServer {
             listen_address,
             cors_layer: None,
             proxy_layer: None,
+            metrics_layer: None,
+            health_layer: None,
+            rate_limit_layer: None,
+            auth_layer: None,
+            max_request_body_size: DEFAULT_MAX_REQUEST_BODY_SIZE,
         }
     }
 
@@ -58,59 +90,590 @@ impl JsonRpcServer {
         self
     }
 
-    pub fn with_proxy(mut self, eth_client_address: String) -> Self {
-        self.proxy_layer = Some(ProxyJsonRpcLayer::new(eth_client_address));
+    /// Forwards the methods the bundler does not serve to the node at
+    /// `eth_client_address`, over HTTP(S) or a shared WebSocket connection
+    pub fn with_proxy(self, eth_client_address: String) -> Self {
+        self.with_proxy_config(ProxyConfig::new(vec![eth_client_address]))
+    }
+
+    /// Forwards the methods the bundler does not serve to a pool of upstream nodes
+    pub fn with_proxy_config(mut self, config: ProxyConfig) -> Self {
+        let pool = UpstreamPool::new(config).expect("Proxy upstream URLs should be valid");
+        self.proxy_layer = Some(ProxyJsonRpcLayer::new(Arc::new(pool)));
+        self
+    }
+
+    /// Serves the bundler metrics on `GET /metrics` and records RPC latencies
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics_layer = Some(MetricsLayer::new(metrics));
+        self
+    }
+
+    /// Only serves requests carrying one of the API keys and counts their usage
+    pub fn with_auth(mut self, api_keys: ApiKeys) -> Self {
+        self.auth_layer = Some(AuthLayer::new(api_keys));
+        self
+    }
+
+    /// Throttles requests with token buckets per client IP and per user operation sender
+    pub fn with_rate_limit(mut self, config: RateLimitConfig) -> Self {
+        self.rate_limit_layer = Some(RateLimitLayer::new(config));
+        self
+    }
+
+    /// Answers 413 to the requests whose body is larger than `max_size` bytes
+    pub fn with_max_request_body_size(mut self, max_size: u32) -> Self {
+        self.max_request_body_size = max_size;
+        self
+    }
+
+    /// Serves the liveness probe on `GET /health` and the readiness probe on `GET /ready`
+    pub fn with_health(mut self, readiness: Arc<dyn Readiness>) -> Self {
+        self.health_layer = Some(HealthLayer::new(readiness));
         self
     }
 
+    /// Serves JSON-RPC over both HTTP and WebSocket on the listen address.
+    /// jsonrpsee does not hand the peer address to the middleware, so the
+    /// connections are accepted here and the requests that pass the
+    /// middleware are forwarded to two jsonrpsee servers on loopback, one
+    /// for HTTP and one for WebSocket whose calls are rate limited one by one.
+    /// The jsonrpsee version in use cannot be served as a tower service, so
+    /// the loopback servers only answer requests carrying a token drawn at
+    /// start, which keeps local processes from going around the middleware.
+    /// Stopping the returned handle stops all three.
     pub async fn start(&self, methods: impl Into<Methods>) -> anyhow::Result<ServerHandle> {
+        let methods: Methods = methods.into();
+        let metrics = self
+            .metrics_layer
+            .as_ref()
+            .map(|layer| layer.metrics.clone());
+        let proxy_layer = self.proxy_layer.clone().map(|layer| {
+            layer
+                .with_local_methods(methods.method_names())
+                .with_metrics(metrics.clone())
+        });
+        let logger = RpcMetricsLogger::new(metrics).with_methods(methods.method_names());
+
+        if let Some(proxy_layer) = &self.proxy_layer {
+            proxy_layer.upstreams.spawn_health_checks();
+        }
+
+        let ws_methods = match &self.auth_layer {
+            Some(layer) => layer.ws_methods(&methods)?,
+            None => methods.clone(),
+        };
+        let guard_layer = BackendGuardLayer::new();
+        let ws_methods = match &self.rate_limit_layer {
+            Some(layer) => layer.limit_methods(&ws_methods, guard_layer.token.to_str()?)?,
+            None => ws_methods,
+        };
+        let http_server = ServerBuilder::new()
+            .http_only()
+            .max_request_body_size(self.max_request_body_size)
+            .set_logger(logger.clone())
+            .set_middleware(ServiceBuilder::new().layer(guard_layer.clone()))
+            .build("127.0.0.1:0")
+            .await?;
+        let ws_server = ServerBuilder::new()
+            .ws_only()
+            .max_request_body_size(self.max_request_body_size)
+            .set_logger(logger)
+            .set_middleware(ServiceBuilder::new().layer(guard_layer.clone()))
+            .build("127.0.0.1:0")
+            .await?;
+        let backend = Backend {
+            client: Client::new(),
+            http_address: http_server.local_addr()?,
+            ws_address: ws_server.local_addr()?,
+            token: guard_layer.token,
+            rate_limit: self.rate_limit_layer.clone(),
+        };
+        let http_handle = http_server.start(methods)?;
+        let ws_handle = ws_server.start(ws_methods)?;
+
+        // CORS goes first so that browsers can read the rejections of the other layers
         let service = ServiceBuilder::new()
             .option_layer(self.cors_layer.clone())
-            .option_layer(self.proxy_layer.clone());
+            .layer(BodyLimitLayer::new(self.max_request_body_size))
+            .layer(TraceLayer)
+            .option_layer(self.health_layer.clone())
+            .option_layer(self.metrics_layer.clone())
+            .option_layer(self.auth_layer.clone())
+            .option_layer(self.rate_limit_layer.clone())
+            .option_layer(proxy_layer)
+            .service(backend);
+        let make_service = make_service_fn(move |conn: &AddrStream| {
+            let peer = PeerAddr(conn.remote_addr());
+            let service = service.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                    req.extensions_mut().insert(peer);
+                    service.clone().call(req)
+                }))
+            }
+        });
 
-        let server = ServerBuilder::new()
-            .set_middleware(service)
-            .build(&self.listen_address)
-            .await?;
+        let listener = TcpListener::bind(&self.listen_address)?;
+        listener.set_nonblocking(true)?;
+        let stopped = http_handle.clone();
+        let server = Server::from_tcp(listener)?
+            .serve(make_service)
+            .with_graceful_shutdown(async move {
+                stopped.stopped().await;
+                let _ = ws_handle.stop();
+            });
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                log::error!("JSON-RPC server failed: {err}");
+            }
+        });
+
+        Ok(http_handle)
+    }
+}
+
+/// Address of the peer a request was received from, set on every request
+/// before it goes through the middleware
+#[derive(Clone, Copy, Debug)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// Forwards the requests that passed the middleware to the jsonrpsee
+/// servers, piping the WebSocket connections together once upgraded
+#[derive(Clone, Debug)]
+struct Backend {
+    client: Client<HttpConnector>,
+    http_address: SocketAddr,
+    ws_address: SocketAddr,
+    /// Token the jsonrpsee servers expect in `BACKEND_TOKEN_HEADER`
+    token: HeaderValue,
+    /// Rate limiter the WebSocket connections are bound to the client IP of
+    rate_limit: Option<RateLimitLayer>,
+}
+
+impl Service<Request<Body>> for Backend {
+    type Response = Response<Body>;
+    type Error = Box<dyn Error + Send + Sync + 'static>;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    #[inline]
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let client = self.client.clone();
+        let upgrade = is_websocket_upgrade(&req);
+        let token = self.token.clone();
+        let rate_limit = self.rate_limit.clone();
+        let client_ip = req.extensions().get::<ClientIp>().copied();
+        req.headers_mut()
+            .insert(BACKEND_TOKEN_HEADER, self.token.clone());
+        let address = if upgrade {
+            self.ws_address
+        } else {
+            self.http_address
+        };
+
+        Box::pin(async move {
+            let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+            let uri: Uri = format!("http://{address}{path}").parse()?;
+            *req.uri_mut() = uri;
+            let client_upgrade = upgrade.then(|| hyper::upgrade::on(&mut req));
+
+            let mut res = client.request(req).await?;
+            if let Some(client_upgrade) = client_upgrade {
+                if res.status() == StatusCode::SWITCHING_PROTOCOLS {
+                    let server_upgrade = hyper::upgrade::on(&mut res);
+                    tokio::spawn(async move {
+                        match tokio::try_join!(client_upgrade, server_upgrade) {
+                            Ok((mut client_io, mut server_io)) => {
+                                // the calls of the connection are charged to the client IP
+                                let bound = match (&rate_limit, client_ip) {
+                                    (Some(_), Some(ClientIp(ip))) => {
+                                        match bind_connection(&mut server_io, &token, ip).await {
+                                            Ok(conn_id) => Some(conn_id),
+                                            Err(err) => {
+                                                log::debug!("WebSocket binding failed: {err}");
+                                                return;
+                                            }
+                                        }
+                                    }
+                                    _ => None,
+                                };
+                                let _ =
+                                    tokio::io::copy_bidirectional(&mut client_io, &mut server_io)
+                                        .await;
+                                if let (Some(rate_limit), Some(conn_id)) = (&rate_limit, bound) {
+                                    rate_limit.unbind(conn_id);
+                                }
+                            }
+                            Err(err) => log::debug!("WebSocket upgrade failed: {err}"),
+                        }
+                    });
+                }
+            }
+            Ok(res)
+        })
+    }
+}
+
+/// Largest WebSocket frame read while binding a connection
+const MAX_BIND_FRAME_SIZE: u64 = 64 * 1024;
+
+/// Calls `BIND_CONNECTION_METHOD` over a WebSocket connection just opened to
+/// the jsonrpsee server, before any message of the client goes through,
+/// returning the id the server gave the connection
+async fn bind_connection<T>(io: &mut T, token: &HeaderValue, ip: IpAddr) -> anyhow::Result<usize>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let call = json!({
+        "jsonrpc": "2.0",
+        "id": 0,
+        "method": BIND_CONNECTION_METHOD,
+        "params": [token.to_str()?, ip],
+    })
+    .to_string();
+
+    // a single masked text frame, as sent by clients
+    let mut frame = vec![0x81];
+    match call.len() {
+        len if len < 126 => frame.push(0x80 | len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(0x80 | 126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    let mask = rand::random::<[u8; 4]>();
+    frame.extend(mask);
+    frame.extend(
+        call.bytes()
+            .enumerate()
+            .map(|(index, byte)| byte ^ mask[index % 4]),
+    );
+    io.write_all(&frame).await?;
+
+    // the server may ping first, its frames are not masked
+    loop {
+        let mut header = [0u8; 2];
+        io.read_exact(&mut header).await?;
+        let len = match header[1] & 0x7f {
+            126 => io.read_u16().await? as u64,
+            127 => io.read_u64().await?,
+            len => len as u64,
+        };
+        if len > MAX_BIND_FRAME_SIZE {
+            anyhow::bail!("Frame of {len} bytes");
+        }
+        let mut payload = vec![0; len as usize];
+        io.read_exact(&mut payload).await?;
+        if header[0] & 0x0f == 0x1 {
+            let response: Value = serde_json::from_slice(&payload)?;
+            return response["result"]
+                .as_u64()
+                .map(|conn_id| conn_id as usize)
+                .ok_or_else(|| anyhow::anyhow!("Binding rejected: {response}"));
+        }
+    }
+}
+
+/// Header carrying the token of the requests forwarded to the jsonrpsee servers
+const BACKEND_TOKEN_HEADER: &str = "x-backend-token";
+
+/// Answers 403 to the requests reaching a jsonrpsee server without the token
+/// of `Backend`, drawn when the server starts
+#[derive(Clone, Debug)]
+struct BackendGuardLayer {
+    token: HeaderValue,
+}
+
+impl BackendGuardLayer {
+    fn new() -> Self {
+        let token = format!(
+            "{:032x}{:032x}",
+            rand::random::<u128>(),
+            rand::random::<u128>()
+        );
+        Self {
+            token: HeaderValue::from_str(&token).expect("Hex digits are a valid header value"),
+        }
+    }
+}
+
+impl<S> Layer<S> for BackendGuardLayer {
+    type Service = BackendGuardRequest<S>;
 
-        Ok(server.start(methods)?)
+    fn layer(&self, inner: S) -> Self::Service {
+        BackendGuardRequest {
+            inner,
+            token: self.token.clone(),
+        }
     }
 }
 
 #[derive(Clone, Debug)]
+struct BackendGuardRequest<S> {
+    inner: S,
+    token: HeaderValue,
+}
+
+impl<S> Service<Request<Body>> for BackendGuardRequest<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Error: Into<Box<dyn Error + Send + Sync>> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Box<dyn Error + Send + Sync + 'static>;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        if req.headers().get(BACKEND_TOKEN_HEADER) != Some(&self.token) {
+            return Box::pin(async move {
+                Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::empty())?)
+            });
+        }
+        let fut = self.inner.call(req);
+        Box::pin(async move { fut.await.map_err(Into::into) })
+    }
+}
+
+/// Outcome of one of the checks behind the readiness probe
+#[derive(Clone, Debug, Serialize)]
+pub struct ReadinessCheck {
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: String,
+}
+
+/// Checks run on every `GET /ready`, the server is ready if they all pass
+#[async_trait]
+pub trait Readiness: Send + Sync {
+    async fn readiness(&self) -> Vec<ReadinessCheck>;
+}
+
+#[derive(Clone)]
+pub struct HealthLayer {
+    pub readiness: Arc<dyn Readiness>,
+}
+
+impl HealthLayer {
+    pub fn new(readiness: Arc<dyn Readiness>) -> Self {
+        Self { readiness }
+    }
+}
+
+impl<S> Layer<S> for HealthLayer {
+    type Service = HealthRequest<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        HealthRequest {
+            inner,
+            readiness: self.readiness.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct HealthRequest<S> {
+    inner: S,
+    readiness: Arc<dyn Readiness>,
+}
+
+impl<S> Service<Request<Body>> for HealthRequest<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Error: Into<Box<dyn Error + Send + Sync>> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Box<dyn Error + Send + Sync + 'static>;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        if req.method() == Method::GET && req.uri().path() == "/health" {
+            return Box::pin(async move { Ok(Response::new(Body::from("ok"))) });
+        }
+
+        if req.method() == Method::GET && req.uri().path() == "/ready" {
+            let readiness = self.readiness.clone();
+            return Box::pin(async move {
+                #[derive(Serialize)]
+                struct ReadinessResponse {
+                    ready: bool,
+                    checks: Vec<ReadinessCheck>,
+                }
+
+                let checks = readiness.readiness().await;
+                let ready = checks.iter().all(|check| check.ok);
+                let status = if ready {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                };
+                let body = serde_json::to_vec(&ReadinessResponse { ready, checks })?;
+                Ok(Response::builder()
+                    .status(status)
+                    .header(hyper::header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(body))?)
+            });
+        }
+
+        let fut = self.inner.call(req);
+        Box::pin(async move { fut.await.map_err(Into::into) })
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricsLayer {
+    pub metrics: Arc<Metrics>,
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self { metrics }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsRequest<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsRequest {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricsRequest<S> {
+    inner: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S> Service<Request<Body>> for MetricsRequest<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Error: Into<Box<dyn Error + Send + Sync>> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Box<dyn Error + Send + Sync + 'static>;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        if req.method() == Method::GET && req.uri().path() == "/metrics" {
+            let metrics = self.metrics.clone();
+            return Box::pin(async move {
+                let body = metrics.encode()?;
+                Ok(Response::builder()
+                    .header(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                    .body(Body::from(body))?)
+            });
+        }
+
+        let fut = self.inner.call(req);
+        Box::pin(async move { fut.await.map_err(Into::into) })
+    }
+}
+
+#[derive(Clone)]
 pub struct ProxyJsonRpcLayer {
-    pub address: String,
+    pub upstreams: Arc<UpstreamPool>,
+    /// Methods served by the bundler itself, used to split batch requests
+    pub local_methods: Arc<HashSet<&'static str>>,
+    /// Records the latency of the calls answered by the upstreams
+    pub metrics: Option<Arc<Metrics>>,
 }
 
 impl ProxyJsonRpcLayer {
-    pub fn new(address: impl Into<String>) -> Self {
+    pub fn new(upstreams: Arc<UpstreamPool>) -> Self {
         Self {
-            address: address.into(),
+            upstreams,
+            local_methods: Arc::default(),
+            metrics: None,
         }
     }
+
+    pub fn with_local_methods(mut self, methods: impl IntoIterator<Item = &'static str>) -> Self {
+        self.local_methods = Arc::new(methods.into_iter().collect());
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: Option<Arc<Metrics>>) -> Self {
+        self.metrics = metrics;
+        self
+    }
 }
 
 impl<S> Layer<S> for ProxyJsonRpcLayer {
     type Service = ProxyJsonRpcRequest<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        ProxyJsonRpcRequest::new(inner, &self.address)
-            .expect("Should be able to create ProxyJsonRpcRequest")
+        ProxyJsonRpcRequest {
+            inner,
+            upstreams: self.upstreams.clone(),
+            local_methods: self.local_methods.clone(),
+            metrics: self.metrics.clone(),
+        }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct ProxyJsonRpcRequest<S> {
     inner: S,
-    address: Arc<str>,
+    upstreams: Arc<UpstreamPool>,
+    local_methods: Arc<HashSet<&'static str>>,
+    metrics: Option<Arc<Metrics>>,
 }
 
 impl<S> ProxyJsonRpcRequest<S> {
-    pub fn new(inner: S, address: &str) -> Result<Self, JsonRpcError> {
-        Ok(Self {
+    pub fn new(inner: S, upstreams: Arc<UpstreamPool>) -> Self {
+        Self {
             inner,
-            address: Arc::from(address),
-        })
+            upstreams,
+            local_methods: Arc::default(),
+            metrics: None,
+        }
+    }
+}
+
+/// Records the latency of `calls` calls answered by the upstreams since `started_at`
+fn observe_proxied(metrics: &Option<Arc<Metrics>>, calls: usize, started_at: Instant) {
+    if let Some(metrics) = metrics {
+        let latency = metrics
+            .rpc_latency
+            .with_label_values(&[PROXIED_METHOD_LABEL]);
+        for _ in 0..calls {
+            latency.observe(started_at.elapsed().as_secs_f64());
+        }
     }
 }
 
@@ -132,12 +695,80 @@ where
     }
 
     fn call(&mut self, req: Request<Body>) -> Self::Future {
-        let addr = String::from(self.address.as_ref());
+        let upstreams = self.upstreams.clone();
+        let local_methods = self.local_methods.clone();
+        let metrics = self.metrics.clone();
         let mut inner = self.inner.clone();
 
+        // subscriptions are only served locally, the upgrade must reach the server untouched
+        if is_websocket_upgrade(&req) {
+            let fut = inner.call(req);
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        }
+
         let res_fut = async move {
             let (req_h, req_b) = req.into_parts();
             let req_bb = hyper::body::to_bytes(req_b).await?;
+
+            let started_at = Instant::now();
+            let body = serde_json::from_slice::<Value>(&req_bb);
+
+            // a call of a node method goes straight to the upstreams
+            if let Ok(Value::Object(call)) = &body {
+                if !local_methods.is_empty()
+                    && call
+                        .get("method")
+                        .and_then(Value::as_str)
+                        .is_some_and(|method| !local_methods.contains(method))
+                {
+                    let res = upstreams.forward(&req_bb).await;
+                    observe_proxied(&metrics, 1, started_at);
+                    return res;
+                }
+            }
+
+            // a batch mixing bundler and node methods is split in two sub-batches
+            if let Ok(Value::Array(calls)) = body {
+                let (local, remote): (Vec<Value>, Vec<Value>) =
+                    calls.iter().cloned().partition(|call| {
+                        match call["method"].as_str() {
+                            Some(method) => local_methods.contains(method),
+                            // invalid calls are answered by the server
+                            None => true,
+                        }
+                    });
+
+                if !local.is_empty() && !remote.is_empty() {
+                    let local_body = Body::from(Value::Array(local).to_string());
+                    let res = inner
+                        .call(Request::from_parts(req_h, local_body))
+                        .await
+                        .map_err(Into::into)?;
+                    let (res_h, res_b) = res.into_parts();
+                    let res_bb = hyper::body::to_bytes(res_b).await?;
+
+                    let local_responses = match serde_json::from_slice(&res_bb) {
+                        Ok(Value::Array(responses)) => responses,
+                        // the server answers a batch of notifications with nothing
+                        _ => vec![],
+                    };
+                    let started_at = Instant::now();
+                    let remote_responses = upstreams.forward_calls(&remote).await;
+                    observe_proxied(&metrics, remote.len(), started_at);
+                    let responses = merge_responses(&calls, local_responses, remote_responses);
+
+                    return Ok(Response::from_parts(
+                        res_h,
+                        Body::from(Value::Array(responses).to_string()),
+                    ));
+                }
+                if local.is_empty() {
+                    let res = upstreams.forward(&req_bb).await;
+                    observe_proxied(&metrics, remote.len(), started_at);
+                    return res;
+                }
+            }
+
             let fut = inner.call(Request::from_parts(req_h, Body::from(req_bb.clone())));
 
             let res = fut.await.map_err(|err| err.into())?;
@@ -154,12 +785,10 @@ where
                 if err.error.code() == ErrorCode::MethodNotFound.code()
                     && err.error.message() == METHOD_NOT_FOUND_MSG
                 {
-                    let client = hyper::Client::new();
-                    let req = Request::post(addr)
-                        .header(hyper::header::CONTENT_TYPE, "application/json")
-                        .body(Body::from(req_bb))?;
-                    let res = client.request(req).await?;
-                    return Ok(res);
+                    let started_at = Instant::now();
+                    let res = upstreams.forward(&req_bb).await;
+                    observe_proxied(&metrics, 1, started_at);
+                    return res;
                 }
             }
 
@@ -169,3 +798,427 @@ where
         Box::pin(res_fut)
     }
 }
+
+/// Puts the responses of the two sub-batches of a batch back in the order of
+/// its calls, matched on their ids. Notifications have no response.
+fn merge_responses(calls: &[Value], local: Vec<Value>, remote: Vec<Value>) -> Vec<Value> {
+    let mut by_id: HashMap<String, VecDeque<Value>> = HashMap::new();
+    for response in local.into_iter().chain(remote) {
+        by_id
+            .entry(response["id"].to_string())
+            .or_default()
+            .push_back(response);
+    }
+
+    calls
+        .iter()
+        .filter_map(|call| call.get("id"))
+        .filter_map(|id| by_id.get_mut(&id.to_string())?.pop_front())
+        .collect()
+}
+
+/// Splits a JSON-RPC request body into its calls, none if it is malformed as
+/// the server rejects it itself
+pub(crate) fn parse_calls(body: &[u8]) -> Vec<Value> {
+    match serde_json::from_slice(body) {
+        Ok(Value::Array(calls)) => calls,
+        Ok(call) => vec![call],
+        Err(_) => vec![],
+    }
+}
+
+/// Answers every call of a request with the same JSON-RPC error
+pub(crate) fn error_response(
+    status: StatusCode,
+    calls: &[Value],
+    code: i32,
+    message: &str,
+) -> Result<Response<Body>, hyper::http::Error> {
+    let errors: Vec<Value> = calls
+        .iter()
+        .map(|call| {
+            json!({
+                "jsonrpc": "2.0",
+                "id": call["id"].clone(),
+                "error": { "code": code, "message": message },
+            })
+        })
+        .collect();
+    let body = match errors.as_slice() {
+        [error] => error.to_string(),
+        [] => json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "error": { "code": code, "message": message },
+        })
+        .to_string(),
+        _ => Value::Array(errors).to_string(),
+    };
+
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+}
+
+pub(crate) fn is_websocket_upgrade(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(hyper::header::UPGRADE)
+        .and_then(|upgrade| upgrade.to_str().ok())
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BackendGuardLayer, JsonRpcServer, BACKEND_TOKEN_HEADER};
+    use crate::bundler::{
+        rate_limit::{BucketConfig, RateLimitConfig, BIND_CONNECTION_METHOD},
+        trace::REQUEST_ID_HEADER,
+        upstream::ProxyConfig,
+    };
+    use ethers::types::{Bytes, U64};
+    use jsonrpsee::{
+        core::{client::ClientT, params::BatchRequestBuilder, Error as RpcError, RpcResult},
+        http_client::HttpClientBuilder,
+        proc_macros::rpc,
+        rpc_params,
+        server::{ServerBuilder, ServerHandle},
+        types::error::{CallError, ErrorObject},
+        ws_client::WsClientBuilder,
+        RpcModule,
+    };
+    use serde_json::{json, Value};
+    use std::convert::Infallible;
+    use tower::{Layer, Service};
+
+    #[rpc(server)]
+    trait MockUpstream {
+        #[method(name = "eth_blockNumber")]
+        fn block_number(&self) -> RpcResult<U64>;
+        #[method(name = "eth_call")]
+        fn call(&self, tx: Value, block: String) -> RpcResult<Bytes>;
+    }
+
+    struct MockNode;
+
+    impl MockUpstreamServer for MockNode {
+        fn block_number(&self) -> RpcResult<U64> {
+            Ok(U64::from(16))
+        }
+
+        fn call(&self, _tx: Value, _block: String) -> RpcResult<Bytes> {
+            Err(RpcError::Call(CallError::Custom(ErrorObject::owned(
+                -32000,
+                "execution reverted",
+                None::<()>,
+            ))))
+        }
+    }
+
+    /// Starts the mock node, serving both HTTP and WebSocket on the same address
+    async fn start_mock_upstream() -> anyhow::Result<(String, ServerHandle)> {
+        let server = ServerBuilder::default().build("127.0.0.1:0").await?;
+        let address = server.local_addr()?.to_string();
+        Ok((address, server.start(MockNode.into_rpc())?))
+    }
+
+    /// Starts the bundler server without any method of its own so that every
+    /// call goes to the upstreams
+    async fn start_proxy(config: ProxyConfig) -> anyhow::Result<(String, ServerHandle)> {
+        start_proxy_with(config, RpcModule::new(())).await
+    }
+
+    async fn start_proxy_with(
+        config: ProxyConfig,
+        methods: RpcModule<()>,
+    ) -> anyhow::Result<(String, ServerHandle)> {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .to_string();
+        let handle = JsonRpcServer::new(address.clone())
+            .with_proxy_config(config)
+            .start(methods)
+            .await?;
+        Ok((format!("http://{address}"), handle))
+    }
+
+    async fn check_forwarding(upstreams: Vec<String>) -> anyhow::Result<()> {
+        let (url, _handle) = start_proxy(ProxyConfig::new(upstreams)).await?;
+        let client = HttpClientBuilder::default().build(url)?;
+
+        let block_number: U64 = client.request("eth_blockNumber", rpc_params![]).await?;
+        assert_eq!(block_number, U64::from(16));
+
+        let err = client
+            .request::<Bytes, _>("eth_call", rpc_params![json!({}), "latest"])
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, RpcError::Call(CallError::Custom(error)) if error.code() == -32000 && error.message() == "execution reverted"),
+            "unexpected error {err:?}"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn proxies_to_http_upstream() -> anyhow::Result<()> {
+        let (upstream, _upstream_handle) = start_mock_upstream().await?;
+        check_forwarding(vec![format!("http://{upstream}")]).await
+    }
+
+    #[tokio::test]
+    async fn proxies_to_ws_upstream() -> anyhow::Result<()> {
+        let (upstream, _upstream_handle) = start_mock_upstream().await?;
+        check_forwarding(vec![format!("ws://{upstream}")]).await
+    }
+
+    #[tokio::test]
+    async fn reconnects_to_ws_upstream() -> anyhow::Result<()> {
+        let (upstream, upstream_handle) = start_mock_upstream().await?;
+        let (url, _handle) =
+            start_proxy(ProxyConfig::new(vec![format!("ws://{upstream}")])).await?;
+        let client = HttpClientBuilder::default().build(url)?;
+        let block_number: U64 = client.request("eth_blockNumber", rpc_params![]).await?;
+        assert_eq!(block_number, U64::from(16));
+
+        // the node restarts on the same address, dropping the shared connection
+        upstream_handle.stop()?;
+        upstream_handle.stopped().await;
+        let server = ServerBuilder::default().build(&upstream).await?;
+        let _upstream_handle = server.start(MockNode.into_rpc())?;
+
+        // the call made on the dropped connection fails, the next one reconnects
+        let _ = client
+            .request::<U64, _>("eth_blockNumber", rpc_params![])
+            .await;
+        let block_number: U64 = client.request("eth_blockNumber", rpc_params![]).await?;
+        assert_eq!(block_number, U64::from(16));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_next_upstream() -> anyhow::Result<()> {
+        let (upstream, _upstream_handle) = start_mock_upstream().await?;
+        // nothing listens on the port of a dropped listener
+        let dead = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+        check_forwarding(vec![format!("ws://{dead}"), format!("http://{upstream}")]).await
+    }
+
+    #[tokio::test]
+    async fn denies_sensitive_methods() -> anyhow::Result<()> {
+        let (upstream, _upstream_handle) = start_mock_upstream().await?;
+        let config = ProxyConfig::new(vec![format!("http://{upstream}")])
+            .with_allowed_methods(vec!["eth_*".to_string()]);
+        let (url, _handle) = start_proxy(config).await?;
+        let client = HttpClientBuilder::default().build(url)?;
+
+        for method in ["personal_listAccounts", "net_version"] {
+            let err = client
+                .request::<Value, _>(method, rpc_params![])
+                .await
+                .unwrap_err();
+            assert!(
+                matches!(&err, RpcError::Call(CallError::Custom(error)) if error.code() == -32004),
+                "unexpected error {err:?}"
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn splits_mixed_batches() -> anyhow::Result<()> {
+        let (upstream, _upstream_handle) = start_mock_upstream().await?;
+        let mut methods = RpcModule::new(());
+        methods.register_method("bundler_ping", |_, _| RpcResult::Ok("pong"))?;
+        let config = ProxyConfig::new(vec![format!("http://{upstream}")]);
+        let (url, _handle) = start_proxy_with(config, methods).await?;
+        let client = HttpClientBuilder::default().build(url)?;
+
+        let mut batch = BatchRequestBuilder::new();
+        batch.insert("eth_blockNumber", rpc_params![])?;
+        batch.insert("bundler_ping", rpc_params![])?;
+        batch.insert("eth_blockNumber", rpc_params![])?;
+        let responses: Vec<Value> = client
+            .batch_request(batch)
+            .await?
+            .into_ok()
+            .map_err(|errors| anyhow::anyhow!("{:?}", errors.collect::<Vec<_>>()))?
+            .collect();
+        assert_eq!(responses, vec![json!("0x10"), json!("pong"), json!("0x10")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limits_every_websocket_message() -> anyhow::Result<()> {
+        let mut methods = RpcModule::new(());
+        methods.register_method("bundler_ping", |_, _| RpcResult::Ok("pong"))?;
+        let address = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .to_string();
+        let bucket = BucketConfig {
+            capacity: 4.0,
+            refill_per_second: 0.0,
+        };
+        let _handle = JsonRpcServer::new(address.clone())
+            .with_rate_limit(RateLimitConfig::default().with_ip(bucket))
+            .start(methods)
+            .await?;
+
+        // the upgrade and the calls all take a token from the IP
+        let client = WsClientBuilder::default()
+            .build(format!("ws://{address}"))
+            .await?;
+        for _ in 0..3 {
+            let pong: String = client.request("bundler_ping", rpc_params![]).await?;
+            assert_eq!(pong, "pong");
+        }
+        let err = client
+            .request::<String, _>("bundler_ping", rpc_params![])
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, RpcError::Call(CallError::Custom(error)) if error.code() == -32005),
+            "unexpected error {err:?}"
+        );
+
+        // a client cannot bind its connection to another IP
+        let err = client
+            .request::<usize, _>(BIND_CONNECTION_METHOD, rpc_params!["0x00", "1.2.3.4"])
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, RpcError::Call(CallError::Custom(error)) if error.code() == -32601),
+            "unexpected error {err:?}"
+        );
+
+        // the connection emptied the bucket of the IP for HTTP as well
+        let client = HttpClientBuilder::default().build(format!("http://{address}"))?;
+        assert!(client
+            .request::<String, _>("bundler_ping", rpc_params![])
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    /// Posts `body` to the bundler server at `address` with the given request id
+    async fn post(
+        address: &str,
+        request_id: Option<&str>,
+        body: String,
+    ) -> anyhow::Result<hyper::Response<hyper::Body>> {
+        let mut request = hyper::Request::post(format!("http://{address}"))
+            .header(hyper::header::CONTENT_TYPE, "application/json");
+        if let Some(request_id) = request_id {
+            request = request.header(REQUEST_ID_HEADER, request_id);
+        }
+        let request = request.body(hyper::Body::from(body))?;
+        Ok(hyper::Client::new().request(request).await?)
+    }
+
+    #[tokio::test]
+    async fn echoes_or_generates_request_id() -> anyhow::Result<()> {
+        let mut methods = RpcModule::new(());
+        methods.register_method("bundler_ping", |_, _| RpcResult::Ok("pong"))?;
+        let address = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .to_string();
+        let _handle = JsonRpcServer::new(address.clone()).start(methods).await?;
+        let ping = json!({ "jsonrpc": "2.0", "id": 1, "method": "bundler_ping" }).to_string();
+
+        let response = post(&address, Some("req-42"), ping.clone()).await?;
+        assert_eq!(response.headers()[REQUEST_ID_HEADER], "req-42");
+
+        let response = post(&address, None, ping).await?;
+        let request_id = response.headers()[REQUEST_ID_HEADER].to_str()?;
+        assert_eq!(request_id.len(), 16);
+        assert!(request_id.chars().all(|c| c.is_ascii_hexdigit()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_bodies_over_the_limit() -> anyhow::Result<()> {
+        let mut methods = RpcModule::new(());
+        methods.register_method("bundler_ping", |_, _| RpcResult::Ok("pong"))?;
+        let address = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .to_string();
+        let _handle = JsonRpcServer::new(address.clone())
+            .with_max_request_body_size(128)
+            .start(methods)
+            .await?;
+
+        let ping = json!({ "jsonrpc": "2.0", "id": 1, "method": "bundler_ping" }).to_string();
+        let response = post(&address, None, ping).await?;
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+
+        let padded = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "bundler_ping",
+            "params": ["0".repeat(256)],
+        })
+        .to_string();
+        let response = post(&address, None, padded).await?;
+        assert_eq!(response.status(), hyper::StatusCode::PAYLOAD_TOO_LARGE);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn backend_rejects_requests_without_token() -> anyhow::Result<()> {
+        let guard_layer = BackendGuardLayer::new();
+        let mut service =
+            guard_layer.layer(tower::service_fn(|_: hyper::Request<hyper::Body>| async {
+                Ok::<_, Infallible>(hyper::Response::new(hyper::Body::empty()))
+            }));
+
+        let response = service
+            .call(hyper::Request::new(hyper::Body::empty()))
+            .await
+            .map_err(|err| anyhow::anyhow!(err))?;
+        assert_eq!(response.status(), hyper::StatusCode::FORBIDDEN);
+
+        let request = hyper::Request::builder()
+            .header(BACKEND_TOKEN_HEADER, guard_layer.token.clone())
+            .body(hyper::Body::empty())?;
+        let response = service
+            .call(request)
+            .await
+            .map_err(|err| anyhow::anyhow!(err))?;
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn allows_origin_on_rejected_requests() -> anyhow::Result<()> {
+        let mut methods = RpcModule::new(());
+        methods.register_method("bundler_ping", |_, _| RpcResult::Ok("pong"))?;
+        let address = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .to_string();
+        let bucket = BucketConfig {
+            capacity: 1.0,
+            refill_per_second: 0.0,
+        };
+        let _handle = JsonRpcServer::new(address.clone())
+            .with_cors(vec!["*".to_string()])
+            .with_rate_limit(RateLimitConfig::default().with_ip(bucket))
+            .start(methods)
+            .await?;
+
+        let ping = json!({ "jsonrpc": "2.0", "id": 1, "method": "bundler_ping" }).to_string();
+        for expected in ["pong", "Rate limit exceeded"] {
+            let request = hyper::Request::post(format!("http://{address}"))
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .header(hyper::header::ORIGIN, "https://dapp.example")
+                .body(hyper::Body::from(ping.clone()))?;
+            let response = hyper::Client::new().request(request).await?;
+            assert_eq!(
+                response.headers()[hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN],
+                "*"
+            );
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+            assert!(String::from_utf8(body.to_vec())?.contains(expected));
+        }
+        Ok(())
+    }
+}
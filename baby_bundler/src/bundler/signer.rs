@@ -0,0 +1,298 @@
+use anyhow::Context;
+use async_trait::async_trait;
+use ethers::{
+    prelude::LocalWallet,
+    signers::{coins_bip39::English, MnemonicBuilder, Signer},
+    types::{transaction::eip2718::TypedTransaction, Address, Bytes},
+    utils::rlp::Rlp,
+};
+use jsonrpsee::{
+    core::client::ClientT,
+    http_client::{HttpClient, HttpClientBuilder},
+    rpc_params,
+};
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Time the remote signer has to answer a request
+const REMOTE_SIGNER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Key the bundler EOA signs its transactions with
+#[async_trait]
+pub trait BundlerSigner: Debug + Send + Sync {
+    fn address(&self) -> Address;
+
+    /// Signs `tx` and returns it RLP encoded, ready to be sent
+    async fn sign_transaction(&self, tx: &TypedTransaction) -> anyhow::Result<Bytes>;
+}
+
+#[async_trait]
+impl BundlerSigner for LocalWallet {
+    fn address(&self) -> Address {
+        Signer::address(self)
+    }
+
+    async fn sign_transaction(&self, tx: &TypedTransaction) -> anyhow::Result<Bytes> {
+        let signature = Signer::sign_transaction(self, tx).await?;
+        Ok(tx.rlp_signed(&signature))
+    }
+}
+
+/// Where a local key is read from
+#[derive(Clone, Debug)]
+pub enum KeySource {
+    /// Hex encoded private key
+    PrivateKey(String),
+    /// BIP-39 mnemonic, the key being derived at `m/44'/60'/0'/0/{index}`
+    Mnemonic { phrase: String, index: u32 },
+    /// Encrypted JSON keystore, decrypted with the content of `password_file`
+    Keystore {
+        path: PathBuf,
+        password_file: PathBuf,
+    },
+}
+
+impl KeySource {
+    pub fn load(&self, chain_id: u64) -> anyhow::Result<LocalWallet> {
+        let wallet = match self {
+            KeySource::PrivateKey(key) => LocalWallet::from_str(key)?,
+            KeySource::Mnemonic { phrase, index } => MnemonicBuilder::<English>::default()
+                .phrase(phrase.as_str())
+                .index(*index)?
+                .build()?,
+            KeySource::Keystore {
+                path,
+                password_file,
+            } => {
+                let password = std::fs::read_to_string(password_file).with_context(|| {
+                    format!("Failed to read password file {}", password_file.display())
+                })?;
+                LocalWallet::decrypt_keystore(path, password.trim_end_matches(['\r', '\n']))
+                    .with_context(|| format!("Failed to decrypt keystore {}", path.display()))?
+            }
+        };
+        Ok(wallet.with_chain_id(chain_id))
+    }
+}
+
+/// Signer holding the key out of process, reached over the Web3Signer
+/// `eth_signTransaction` JSON-RPC API
+#[derive(Clone, Debug)]
+pub struct RemoteSigner {
+    client: HttpClient,
+    address: Address,
+}
+
+impl RemoteSigner {
+    /// Signs as `address`, or the first account of the signer if not set
+    pub async fn connect(url: &str, address: Option<Address>) -> anyhow::Result<Self> {
+        let client = HttpClientBuilder::default()
+            .request_timeout(REMOTE_SIGNER_TIMEOUT)
+            .build(url)?;
+        let address = match address {
+            Some(address) => address,
+            None => {
+                let accounts: Vec<Address> = client.request("eth_accounts", rpc_params![]).await?;
+                *accounts
+                    .first()
+                    .ok_or_else(|| anyhow::anyhow!("Remote signer {url} has no account"))?
+            }
+        };
+        Ok(Self { client, address })
+    }
+}
+
+#[async_trait]
+impl BundlerSigner for RemoteSigner {
+    fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Signs `tx` with the remote key, checking that the signer returned
+    /// `tx` itself signed by `address`
+    async fn sign_transaction(&self, tx: &TypedTransaction) -> anyhow::Result<Bytes> {
+        let mut tx = tx.clone();
+        tx.set_from(self.address);
+        let raw_tx: Bytes = self
+            .client
+            .request("eth_signTransaction", rpc_params![&tx])
+            .await?;
+
+        let (signed, signature) = TypedTransaction::decode_signed(&Rlp::new(&raw_tx))
+            .context("Remote signer returned an invalid transaction")?;
+        // the sighash covers every signed field of the transaction
+        if signed.sighash() != tx.sighash() {
+            anyhow::bail!("Remote signer returned another transaction than the one requested");
+        }
+        let signer = signature.recover(signed.sighash())?;
+        if signer != self.address {
+            anyhow::bail!(
+                "Remote signer signed with {:?} instead of {:?}",
+                signer,
+                self.address
+            );
+        }
+        Ok(raw_tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BundlerSigner, KeySource, RemoteSigner};
+    use ethers::{
+        core::rand,
+        prelude::LocalWallet,
+        signers::Signer,
+        types::{
+            transaction::eip2718::TypedTransaction, Address, Bytes, Eip1559TransactionRequest,
+        },
+    };
+    use jsonrpsee::{
+        core::{Error as RpcError, RpcResult},
+        proc_macros::rpc,
+        server::{ServerBuilder, ServerHandle},
+    };
+
+    const PHRASE: &str = "test test test test test test test test test test test junk";
+
+    #[rpc(server, namespace = "eth")]
+    trait Web3Signer {
+        #[method(name = "accounts")]
+        fn accounts(&self) -> RpcResult<Vec<Address>>;
+        #[method(name = "signTransaction")]
+        async fn sign_transaction(&self, tx: TypedTransaction) -> RpcResult<Bytes>;
+    }
+
+    /// Local stand-in for Web3Signer holding a single key, signing another
+    /// nonce than the one requested if `tampering`
+    struct MockSigner {
+        wallet: LocalWallet,
+        tampering: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl Web3SignerServer for MockSigner {
+        fn accounts(&self) -> RpcResult<Vec<Address>> {
+            Ok(vec![self.wallet.address()])
+        }
+
+        async fn sign_transaction(&self, mut tx: TypedTransaction) -> RpcResult<Bytes> {
+            if self.tampering {
+                let nonce = tx.nonce().copied().unwrap_or_default();
+                tx.set_nonce(nonce + 1);
+            }
+            BundlerSigner::sign_transaction(&self.wallet, &tx)
+                .await
+                .map_err(|err| RpcError::Custom(err.to_string()))
+        }
+    }
+
+    fn transaction() -> TypedTransaction {
+        Eip1559TransactionRequest::new()
+            .to(Address::repeat_byte(0x11))
+            .value(1_000)
+            .nonce(7)
+            .gas(21_000)
+            .max_fee_per_gas(2_000_000_000u64)
+            .max_priority_fee_per_gas(1_000_000_000u64)
+            .chain_id(80001)
+            .into()
+    }
+
+    #[test]
+    fn derives_mnemonic_index() -> anyhow::Result<()> {
+        let first = KeySource::Mnemonic {
+            phrase: PHRASE.to_string(),
+            index: 0,
+        }
+        .load(80001)?;
+        let second = KeySource::Mnemonic {
+            phrase: PHRASE.to_string(),
+            index: 1,
+        }
+        .load(80001)?;
+
+        assert_eq!(
+            Signer::address(&first),
+            "0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266".parse::<Address>()?
+        );
+        assert_ne!(Signer::address(&first), Signer::address(&second));
+        assert_eq!(first.chain_id(), 80001);
+        Ok(())
+    }
+
+    #[test]
+    fn decrypts_keystore_with_trailing_newline() -> anyhow::Result<()> {
+        let dir =
+            std::env::temp_dir().join(format!("baby_bundler_keystore_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let (wallet, name) =
+            LocalWallet::new_keystore(&dir, &mut rand::thread_rng(), "hunter2", None)?;
+        let password_file = dir.join("password");
+        let source = KeySource::Keystore {
+            path: dir.join(name),
+            password_file: password_file.clone(),
+        };
+
+        std::fs::write(&password_file, "hunter2\n")?;
+        let loaded = source.load(80001);
+        std::fs::write(&password_file, "hunter3\n")?;
+        let wrong_password = source.load(80001);
+        std::fs::remove_dir_all(&dir)?;
+
+        let loaded = loaded?;
+        assert_eq!(Signer::address(&loaded), Signer::address(&wallet));
+        assert_eq!(loaded.chain_id(), 80001);
+        assert!(wrong_password.is_err());
+        Ok(())
+    }
+
+    /// Starts a mock Web3Signer holding the first key of the test mnemonic
+    async fn start_mock_signer(
+        tampering: bool,
+    ) -> anyhow::Result<(String, LocalWallet, ServerHandle)> {
+        let wallet = KeySource::Mnemonic {
+            phrase: PHRASE.to_string(),
+            index: 0,
+        }
+        .load(80001)?;
+        let server = ServerBuilder::default().build("127.0.0.1:0").await?;
+        let url = format!("http://{}", server.local_addr()?);
+        let signer = MockSigner {
+            wallet: wallet.clone(),
+            tampering,
+        };
+        Ok((url, wallet, server.start(signer.into_rpc())?))
+    }
+
+    #[tokio::test]
+    async fn remote_signer_matches_local_key() -> anyhow::Result<()> {
+        let (url, wallet, _handle) = start_mock_signer(false).await?;
+
+        let remote = RemoteSigner::connect(&url, None).await?;
+        assert_eq!(remote.address(), Signer::address(&wallet));
+
+        let mut tx = transaction();
+        tx.set_from(Signer::address(&wallet));
+        assert_eq!(
+            remote.sign_transaction(&tx).await?,
+            BundlerSigner::sign_transaction(&wallet, &tx).await?
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn remote_signer_rejects_another_transaction() -> anyhow::Result<()> {
+        let (url, _wallet, _handle) = start_mock_signer(true).await?;
+        let remote = RemoteSigner::connect(&url, None).await?;
+
+        let err = remote.sign_transaction(&transaction()).await.unwrap_err();
+        assert!(
+            err.to_string().contains("another transaction"),
+            "unexpected error {err:?}"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+use crate::bundler::signer::BundlerSigner;
+use ethers::{
+    providers::Middleware,
+    types::{Address, U256},
+};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Nonce and load of a key of the pool
+#[derive(Debug, Default)]
+struct KeyState {
+    /// Nonce of the next transaction, read from the chain if not known
+    next_nonce: Option<U256>,
+    /// Bundles signed by the key and not settled yet
+    in_flight: usize,
+    /// Last balance seen
+    balance: U256,
+}
+
+#[derive(Debug)]
+struct PooledSigner {
+    signer: Arc<dyn BundlerSigner>,
+    state: Mutex<KeyState>,
+}
+
+/// Key picked to sign a bundle along with the nonce reserved for it
+#[derive(Clone, Debug)]
+pub struct SignerLease {
+    pub signer: Arc<dyn BundlerSigner>,
+    pub nonce: U256,
+}
+
+impl SignerLease {
+    pub fn address(&self) -> Address {
+        self.signer.address()
+    }
+}
+
+/// Keys the bundles are spread over, each with its own nonce sequence so that
+/// a bundle stuck on one key does not hold back the others
+#[derive(Debug)]
+pub struct SignerPool {
+    signers: Vec<PooledSigner>,
+}
+
+impl SignerPool {
+    pub fn new(signers: Vec<Arc<dyn BundlerSigner>>) -> anyhow::Result<Self> {
+        if signers.is_empty() {
+            anyhow::bail!("Signer pool has no key");
+        }
+        Ok(Self {
+            signers: signers
+                .into_iter()
+                .map(|signer| PooledSigner {
+                    signer,
+                    state: Mutex::default(),
+                })
+                .collect(),
+        })
+    }
+
+    /// First key of the pool, the default beneficiary
+    pub fn primary(&self) -> Arc<dyn BundlerSigner> {
+        self.signers[0].signer.clone()
+    }
+
+    pub fn addresses(&self) -> Vec<Address> {
+        self.signers
+            .iter()
+            .map(|pooled| pooled.signer.address())
+            .collect()
+    }
+
+    pub fn signers(&self) -> Vec<Arc<dyn BundlerSigner>> {
+        self.signers
+            .iter()
+            .map(|pooled| pooled.signer.clone())
+            .collect()
+    }
+
+    /// Picks the key with the fewest bundles in flight among those holding
+    /// at least `min_balance`, any key if none does, and reserves its next nonce
+    pub async fn acquire<M: Middleware>(
+        &self,
+        eth_provider: &M,
+        min_balance: U256,
+    ) -> anyhow::Result<SignerLease> {
+        let mut best = None;
+        for (index, pooled) in self.signers.iter().enumerate() {
+            let state = pooled.state.lock().await;
+            let load = (state.balance < min_balance, state.in_flight);
+            match best {
+                Some((_, best_load)) if best_load <= load => {}
+                _ => best = Some((index, load)),
+            }
+        }
+        let (index, _) = best.ok_or_else(|| anyhow::anyhow!("Signer pool has no key"))?;
+        self.acquire_key(eth_provider, index).await
+    }
+
+    /// Reserves the next nonce of the key of `address` unless one of its
+    /// bundles is in flight, both under the lock of the key so that no bundle
+    /// takes it in between
+    pub async fn acquire_idle<M: Middleware>(
+        &self,
+        eth_provider: &M,
+        address: Address,
+    ) -> anyhow::Result<Option<SignerLease>> {
+        let index = self
+            .index(address)
+            .ok_or_else(|| anyhow::anyhow!("{address:?} is not in the signer pool"))?;
+        let pooled = &self.signers[index];
+        let mut state = pooled.state.lock().await;
+        if state.in_flight > 0 {
+            return Ok(None);
+        }
+        reserve_nonce(eth_provider, pooled, &mut state)
+            .await
+            .map(Some)
+    }
+
+    async fn acquire_key<M: Middleware>(
+        &self,
+        eth_provider: &M,
+        index: usize,
+    ) -> anyhow::Result<SignerLease> {
+        let pooled = &self.signers[index];
+        let mut state = pooled.state.lock().await;
+        reserve_nonce(eth_provider, pooled, &mut state).await
+    }
+
+    /// Gives back a lease whose transaction was never sent
+    pub async fn release(&self, lease: &SignerLease) {
+        self.settle(lease.address(), false).await;
+    }
+
+    /// Records the outcome of a transaction of the key of `address`. The nonce
+    /// is read from the chain again after a transaction that did not land.
+    pub async fn settle(&self, address: Address, included: bool) {
+        let Some(index) = self.index(address) else {
+            return;
+        };
+        let mut state = self.signers[index].state.lock().await;
+        state.in_flight = state.in_flight.saturating_sub(1);
+        if !included {
+            state.next_nonce = None;
+        }
+    }
+
+    /// Bundles of the key of `address` not settled yet
+    pub async fn in_flight(&self, address: Address) -> usize {
+        match self.index(address) {
+            Some(index) => self.signers[index].state.lock().await.in_flight,
+            None => 0,
+        }
+    }
+
+    /// Reads the balance of every key, returning them in the order of the pool
+    pub async fn refresh_balances<M: Middleware>(
+        &self,
+        eth_provider: &M,
+    ) -> anyhow::Result<Vec<(Address, U256)>> {
+        let mut balances = vec![];
+        for pooled in self.signers.iter() {
+            let address = pooled.signer.address();
+            let balance = eth_provider
+                .get_balance(address, None)
+                .await
+                .map_err(|err| anyhow::anyhow!("Failed to fetch balance: {err}"))?;
+            pooled.state.lock().await.balance = balance;
+            balances.push((address, balance));
+        }
+        Ok(balances)
+    }
+
+    fn index(&self, address: Address) -> Option<usize> {
+        self.signers
+            .iter()
+            .position(|pooled| pooled.signer.address() == address)
+    }
+}
+
+async fn reserve_nonce<M: Middleware>(
+    eth_provider: &M,
+    pooled: &PooledSigner,
+    state: &mut KeyState,
+) -> anyhow::Result<SignerLease> {
+    let on_chain = eth_provider
+        .get_transaction_count(pooled.signer.address(), None)
+        .await
+        .map_err(|err| anyhow::anyhow!("Failed to fetch nonce: {err}"))?;
+    // bundles sent privately are not in the public mempool, keep counting locally
+    let nonce = match state.next_nonce {
+        Some(next_nonce) => next_nonce.max(on_chain),
+        None => on_chain,
+    };
+    state.next_nonce = Some(nonce + 1);
+    state.in_flight += 1;
+    Ok(SignerLease {
+        signer: pooled.signer.clone(),
+        nonce,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SignerPool;
+    use crate::bundler::signer::{BundlerSigner, KeySource};
+    use ethers::{providers::Provider, types::U256};
+    use std::sync::Arc;
+
+    fn key(index: u32) -> anyhow::Result<Arc<dyn BundlerSigner>> {
+        let source = KeySource::Mnemonic {
+            phrase: "test test test test test test test test test test test junk".to_string(),
+            index,
+        };
+        Ok(Arc::new(source.load(80001)?))
+    }
+
+    #[tokio::test]
+    async fn spreads_bundles_over_least_loaded_keys() -> anyhow::Result<()> {
+        let (provider, mock) = Provider::mocked();
+        let pool = SignerPool::new(vec![key(0)?, key(1)?])?;
+
+        mock.push(U256::from(5))?;
+        let first = pool.acquire(&provider, U256::zero()).await?;
+        mock.push(U256::from(9))?;
+        let second = pool.acquire(&provider, U256::zero()).await?;
+        assert_ne!(first.address(), second.address());
+        assert_eq!((first.nonce, second.nonce), (5.into(), 9.into()));
+
+        // the bundle of the second key is stuck, the first key keeps going
+        pool.settle(first.address(), true).await;
+        mock.push(U256::from(5))?;
+        let third = pool.acquire(&provider, U256::zero()).await?;
+        assert_eq!(third.address(), first.address());
+        assert_eq!(third.nonce, 6.into());
+
+        // the stuck bundle timed out, its nonce is read from the chain again
+        pool.settle(second.address(), false).await;
+        assert_eq!(pool.in_flight(second.address()).await, 0);
+        mock.push(U256::from(9))?;
+        let fourth = pool.acquire(&provider, U256::zero()).await?;
+        assert_eq!(fourth.address(), second.address());
+        assert_eq!(fourth.nonce, 9.into());
+        Ok(())
+    }
+}
@@ -0,0 +1,377 @@
+use crate::bundler::{mempool::MempoolEntry, reputation::ReputationEntry, schedule::Schedule};
+use aa_bundler_primitives::UserOperationHash;
+use ethers::types::{Address, H256};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sled::{transaction::TransactionError, Transactional};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Bundles kept to skip their user operations on restore, the oldest are
+/// dropped first. Their user operations have long been included or expired.
+const MAX_STORED_BUNDLES: usize = 1_000;
+
+/// A bundle sent to the relay, kept so that its user operations are not bundled
+/// again after a restart
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubmittedBundle {
+    pub bundle_hash: H256,
+    pub user_operations: Vec<UserOperationHash>,
+    /// Unix time at which the bundle was sent
+    pub submitted_at: u64,
+}
+
+/// Records changed since the last save, `None` for the removed ones
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub entries: Vec<(UserOperationHash, Option<MempoolEntry>)>,
+    pub cancellations: Vec<(UserOperationHash, Option<u64>)>,
+    pub schedules: Vec<Schedule>,
+    pub reputation: Vec<(Address, Option<ReputationEntry>)>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+            && self.cancellations.is_empty()
+            && self.schedules.is_empty()
+            && self.reputation.is_empty()
+    }
+}
+
+/// Embedded key-value store holding the state of the bundler across restarts,
+/// one tree per kind of record with JSON encoded values. Writes reach the disk
+/// with the periodic flush of sled, or with `flush` on shutdown.
+pub struct Store {
+    db: sled::Db,
+    mempool: sled::Tree,
+    schedules: sled::Tree,
+    /// Keyed by submission time then bundle hash, the oldest come first
+    bundles: sled::Tree,
+    bundle_count: AtomicUsize,
+    reputation: sled::Tree,
+    cancelled: sled::Tree,
+}
+
+impl Store {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Self::from_db(sled::open(path)?)
+    }
+
+    /// Opens a store that is deleted once dropped
+    #[cfg(test)]
+    pub(crate) fn temporary() -> anyhow::Result<Self> {
+        Self::from_db(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn from_db(db: sled::Db) -> anyhow::Result<Self> {
+        let bundles = db.open_tree("bundles")?;
+        Ok(Self {
+            mempool: db.open_tree("mempool")?,
+            schedules: db.open_tree("schedules")?,
+            bundle_count: AtomicUsize::new(bundles.len()),
+            bundles,
+            reputation: db.open_tree("reputation")?,
+            cancelled: db.open_tree("cancelled")?,
+            db,
+        })
+    }
+
+    /// Writes the changed mempool entries, cancellations, schedules and
+    /// reputation in one transaction, so a crash never leaves a mix of two saves
+    pub fn save(&self, changes: &Changes) -> anyhow::Result<()> {
+        let mempool = batch(
+            changes
+                .entries
+                .iter()
+                .map(|(hash, entry)| (hash.0.as_bytes(), entry.as_ref())),
+        )?;
+        let cancelled = batch(
+            changes
+                .cancellations
+                .iter()
+                .map(|(hash, timestamp)| (hash.0.as_bytes(), timestamp.as_ref())),
+        )?;
+        let schedules = batch(
+            changes
+                .schedules
+                .iter()
+                .map(|schedule| (schedule.id.as_bytes(), Some(schedule))),
+        )?;
+        let reputation = batch(
+            changes
+                .reputation
+                .iter()
+                .map(|(address, entry)| (address.as_bytes(), entry.as_ref())),
+        )?;
+
+        (
+            &self.mempool,
+            &self.cancelled,
+            &self.schedules,
+            &self.reputation,
+        )
+            .transaction(|(tx_mempool, tx_cancelled, tx_schedules, tx_reputation)| {
+                tx_mempool.apply_batch(&mempool)?;
+                tx_cancelled.apply_batch(&cancelled)?;
+                tx_schedules.apply_batch(&schedules)?;
+                tx_reputation.apply_batch(&reputation)?;
+                Ok(())
+            })
+            .map_err(|err: TransactionError| {
+                anyhow::anyhow!("Failed to save the state: {err:?}")
+            })?;
+        Ok(())
+    }
+
+    /// Removes the mempool entries, cancellations, schedules and reputation, the
+    /// bundles are kept
+    pub fn clear(&self) -> anyhow::Result<()> {
+        for tree in [
+            &self.mempool,
+            &self.cancelled,
+            &self.schedules,
+            &self.reputation,
+        ] {
+            tree.clear()?;
+        }
+        Ok(())
+    }
+
+    /// Writes everything saved so far to disk
+    pub fn flush(&self) -> anyhow::Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    pub fn mempool(&self) -> anyhow::Result<Vec<(UserOperationHash, MempoolEntry)>> {
+        Ok(load(&self.mempool)?
+            .into_iter()
+            .map(|(key, entry)| (UserOperationHash(H256::from_slice(&key)), entry))
+            .collect())
+    }
+
+    pub fn schedules(&self) -> anyhow::Result<Vec<Schedule>> {
+        Ok(load(&self.schedules)?
+            .into_iter()
+            .map(|(_, schedule)| schedule)
+            .collect())
+    }
+
+    pub fn reputation(&self) -> anyhow::Result<Vec<ReputationEntry>> {
+        Ok(load(&self.reputation)?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect())
+    }
+
+    pub fn cancellations(&self) -> anyhow::Result<Vec<(UserOperationHash, u64)>> {
+        Ok(load(&self.cancelled)?
+            .into_iter()
+            .map(|(key, timestamp)| (UserOperationHash(H256::from_slice(&key)), timestamp))
+            .collect())
+    }
+
+    /// Records a bundle sent to the relay, dropping the oldest ones past
+    /// `MAX_STORED_BUNDLES`
+    pub fn insert_bundle(&self, bundle: &SubmittedBundle) -> anyhow::Result<()> {
+        let mut key = bundle.submitted_at.to_be_bytes().to_vec();
+        key.extend_from_slice(bundle.bundle_hash.as_bytes());
+        if self
+            .bundles
+            .insert(key, serde_json::to_vec(bundle)?)?
+            .is_none()
+        {
+            self.bundle_count.fetch_add(1, Ordering::SeqCst);
+        }
+
+        while self.bundle_count.load(Ordering::SeqCst) > MAX_STORED_BUNDLES {
+            if self.bundles.pop_min()?.is_none() {
+                break;
+            }
+            self.bundle_count.fetch_sub(1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    pub fn bundles(&self) -> anyhow::Result<Vec<SubmittedBundle>> {
+        Ok(load(&self.bundles)?
+            .into_iter()
+            .map(|(_, bundle)| bundle)
+            .collect())
+    }
+}
+
+/// Batch writing the given records and removing the ones without a value
+fn batch<'a, V: Serialize + 'a>(
+    records: impl Iterator<Item = (&'a [u8], Option<&'a V>)>,
+) -> anyhow::Result<sled::Batch> {
+    let mut batch = sled::Batch::default();
+    for (key, value) in records {
+        match value {
+            Some(value) => batch.insert(key, serde_json::to_vec(value)?),
+            None => batch.remove(key),
+        }
+    }
+    Ok(batch)
+}
+
+fn load<V: DeserializeOwned>(tree: &sled::Tree) -> anyhow::Result<Vec<(Vec<u8>, V)>> {
+    tree.iter()
+        .map(|record| {
+            let (key, value) = record?;
+            Ok((key.to_vec(), serde_json::from_slice(&value)?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundler::{
+        bundler::tests::{block, mocked_bundler, validation_result},
+        mempool::Mempool,
+        reputation::{Reputation, ReputationStatus},
+        schedule::{FailurePolicy, Schedules},
+    };
+    use crate::sdk::gat_tx;
+    use aa_bundler_primitives::UserOperation;
+    use ethers::types::{Address, Bytes, U256};
+
+    fn entry(nonce: u64) -> MempoolEntry {
+        MempoolEntry {
+            user_operation: UserOperation::default().nonce(U256::from(nonce)),
+            entry_point: Address::zero(),
+            good_after: u64::MAX,
+            valid_until: None,
+            condition: None,
+            condition_met: false,
+            failed_attempts: 0,
+        }
+    }
+
+    fn hash(byte: u8) -> UserOperationHash {
+        UserOperationHash(H256::repeat_byte(byte))
+    }
+
+    #[tokio::test]
+    async fn restores_persisted_state() -> anyhow::Result<()> {
+        let (bundler, mock) = mocked_bundler()?;
+        let bundler = bundler.with_store(Store::temporary()?);
+        let store = bundler.store.clone().expect("store set");
+
+        let reputation = ReputationEntry {
+            address: Address::repeat_byte(1),
+            ops_seen: 2,
+            ops_included: 1,
+            status: ReputationStatus::Throttled,
+        };
+        let schedule = Schedule::new(
+            vec![UserOperation::default()
+                .nonce(U256::from(3))
+                .call_data(gat_tx::encode(Bytes::default(), U256::from(500)))],
+            bundler.entry_point,
+            U256::from(80001),
+            FailurePolicy::Halt,
+        )
+        .map_err(anyhow::Error::msg)?;
+        {
+            let mut mempool = bundler.mempool.lock().await;
+            mempool.park(hash(1), entry(1));
+            // already sent before the restart, it is not bundled again
+            mempool.park(hash(2), entry(2));
+            mempool.park(hash(3), entry(3));
+            mempool.cancel(&hash(3), 100);
+        }
+        bundler
+            .reputation
+            .lock()
+            .await
+            .set(vec![reputation.clone()]);
+        bundler.schedules.lock().await.insert(schedule.clone());
+        store.insert_bundle(&SubmittedBundle {
+            bundle_hash: H256::repeat_byte(9),
+            user_operations: vec![hash(2)],
+            submitted_at: 100,
+        })?;
+        bundler.persist().await;
+
+        *bundler.mempool.lock().await = Mempool::default();
+        *bundler.schedules.lock().await = Schedules::default();
+        *bundler.reputation.lock().await = Reputation::default();
+
+        // latest block, then the parked op and the schedule are validated again
+        mock.push_response(validation_result(0, 0));
+        mock.push_response(validation_result(0, 0));
+        mock.push(block(10, 1_000))?;
+        bundler.restore().await?;
+
+        let mempool = bundler.mempool.lock().await;
+        assert!(mempool.get(&hash(1)).is_some());
+        assert!(mempool.get(&hash(2)).is_none());
+        assert!(mempool.get(&hash(3)).is_none());
+        assert!(mempool.is_cancelled(&hash(3)));
+        assert_eq!(bundler.reputation.lock().await.dump(), vec![reputation]);
+        let schedules = bundler.schedules.lock().await;
+        let restored = schedules.get(&schedule.id).expect("schedule restored");
+        assert_eq!(restored.operations[0].hash, schedule.operations[0].hash);
+        assert_eq!(restored.next, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn writes_changed_records_only() -> anyhow::Result<()> {
+        let store = Store::temporary()?;
+        let mut mempool = Mempool::default();
+        let save = |mempool: &mut Mempool| {
+            let (entries, cancellations) = mempool.take_changes();
+            let written = entries.len() + cancellations.len();
+            store.save(&Changes {
+                entries,
+                cancellations,
+                ..Default::default()
+            })?;
+            anyhow::Ok(written)
+        };
+
+        mempool.park(hash(1), entry(1));
+        mempool.park(hash(2), entry(2));
+        assert_eq!(save(&mut mempool)?, 2);
+        assert_eq!(save(&mut mempool)?, 0);
+
+        // the cancelled entry is removed and its cancellation written
+        mempool.cancel(&hash(1), 100);
+        assert_eq!(save(&mut mempool)?, 2);
+        mempool.expire_cancellations(50);
+        assert_eq!(save(&mut mempool)?, 0);
+        let stored = store.mempool()?;
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].0, hash(2));
+        assert_eq!(store.cancellations()?, vec![(hash(1), 100)]);
+
+        mempool.expire_cancellations(101);
+        assert_eq!(save(&mut mempool)?, 1);
+        assert!(store.cancellations()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_latest_bundles() -> anyhow::Result<()> {
+        let store = Store::temporary()?;
+        for index in 0..MAX_STORED_BUNDLES as u64 + 2 {
+            store.insert_bundle(&SubmittedBundle {
+                bundle_hash: H256::from_low_u64_be(index + 1),
+                user_operations: vec![],
+                submitted_at: index,
+            })?;
+        }
+
+        let bundles = store.bundles()?;
+        assert_eq!(bundles.len(), MAX_STORED_BUNDLES);
+        assert_eq!(
+            bundles.iter().map(|bundle| bundle.submitted_at).min(),
+            Some(2)
+        );
+        Ok(())
+    }
+}
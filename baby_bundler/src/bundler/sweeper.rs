@@ -0,0 +1,413 @@
+use crate::bundler::{
+    bundler::BabyBundler,
+    operator::{send_transaction, sign_transaction},
+    signer::BundlerSigner,
+};
+use ethers::{
+    providers::Middleware,
+    types::{transaction::eip2718::TypedTransaction, Address, TransactionRequest, U256},
+    utils::format_ether,
+};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Balances each key of the signer pool, the hot wallets, is kept within
+#[derive(Clone, Debug)]
+pub struct SweeperConfig {
+    /// Cold storage the balance above `sweep_threshold` is moved to, no sweep if not set
+    pub cold_wallet: Option<Address>,
+    pub sweep_threshold: U256,
+    /// Wallet the hot wallet is topped up from when below `floor`, no top-up if not set
+    pub funding_wallet: Option<Arc<dyn BundlerSigner>>,
+    pub floor: U256,
+    /// Balance the hot wallet is brought back to by a sweep or a top-up
+    pub target: U256,
+    /// Balance below which the hot wallet is about to run dry and an alert is raised
+    pub alert_threshold: U256,
+    pub interval: Duration,
+}
+
+impl SweeperConfig {
+    /// Keeps the hot wallet around `target`, alerting below `alert_threshold`.
+    /// `floor` <= `target` <= `sweep_threshold` is required.
+    pub fn new(
+        floor: U256,
+        target: U256,
+        sweep_threshold: U256,
+        alert_threshold: U256,
+    ) -> anyhow::Result<Self> {
+        if floor > target || target > sweep_threshold {
+            anyhow::bail!("Sweeper floor, target and sweep threshold must be increasing");
+        }
+        Ok(Self {
+            cold_wallet: None,
+            sweep_threshold,
+            funding_wallet: None,
+            floor,
+            target,
+            alert_threshold,
+            interval: Duration::from_secs(60),
+        })
+    }
+
+    pub fn with_cold_wallet(mut self, cold_wallet: Address) -> Self {
+        self.cold_wallet = Some(cold_wallet);
+        self
+    }
+
+    pub fn with_funding_wallet(mut self, funding_wallet: Arc<dyn BundlerSigner>) -> Self {
+        self.funding_wallet = Some(funding_wallet);
+        self
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+}
+
+/// Moves the revenue piling up in the hot wallets to cold storage and tops
+/// them up from the funding wallet before they run dry
+pub struct Sweeper<M: Middleware> {
+    bundler: BabyBundler<M>,
+    config: SweeperConfig,
+}
+
+impl<M> Sweeper<M>
+where
+    M: Middleware + 'static,
+    M::Provider: Send + Sync + 'static,
+{
+    pub fn new(bundler: BabyBundler<M>, config: SweeperConfig) -> Self {
+        Self { bundler, config }
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.config.interval);
+            loop {
+                interval.tick().await;
+                if let Err(err) = self.run_once().await {
+                    log::warn!("Failed to sweep the bundler wallet: {:?}", err);
+                }
+            }
+        })
+    }
+
+    /// Sweeps or tops up every hot wallet depending on its balance, going on
+    /// with the others when one of them fails
+    pub async fn run_once(&self) -> anyhow::Result<()> {
+        let hot_wallets = self.bundler.signers.addresses();
+        let mut failed = 0;
+        for hot_wallet in hot_wallets.iter() {
+            if let Err(err) = self.balance_wallet(*hot_wallet).await {
+                failed += 1;
+                log::warn!(
+                    "Failed to balance bundler wallet {:?}: {:?}",
+                    hot_wallet,
+                    err
+                );
+            }
+        }
+        if failed > 0 {
+            anyhow::bail!(
+                "Failed to balance {failed} of {} wallets",
+                hot_wallets.len()
+            );
+        }
+        Ok(())
+    }
+
+    async fn balance_wallet(&self, hot_wallet: Address) -> anyhow::Result<()> {
+        let balance = self.balance(hot_wallet).await?;
+
+        if balance < self.config.alert_threshold {
+            self.bundler.metrics.balance_alerts.inc();
+            log::error!(
+                "Bundler wallet {:?} is running dry with {} ETH left",
+                hot_wallet,
+                format_ether(balance)
+            );
+        }
+
+        let signers = &self.bundler.signers;
+        if balance > self.config.sweep_threshold {
+            if let Some(cold_wallet) = self.config.cold_wallet {
+                // the hot wallet pays the gas of the sweep out of what is above the target
+                let (mut tx, cost) = self.transfer_request(hot_wallet, cold_wallet).await?;
+                let excess = balance - self.config.target;
+                if excess <= cost {
+                    return Ok(());
+                }
+                let amount = excess - cost;
+
+                // a sweep shares the nonces of the bundles, wait until none is in flight
+                let provider = self.bundler.eth_provider.as_ref();
+                let Some(lease) = signers.acquire_idle(provider, hot_wallet).await? else {
+                    log::debug!("Sweep of {:?} deferred, bundles in flight", hot_wallet);
+                    return Ok(());
+                };
+                tx.set_value(amount).set_nonce(lease.nonce);
+                let res = self.transfer(lease.signer.as_ref(), tx).await;
+                signers.settle(hot_wallet, res.is_ok()).await;
+                res?;
+                self.bundler
+                    .metrics
+                    .sweeps
+                    .with_label_values(&["sweep"])
+                    .inc();
+                log::info!(
+                    "Swept {} ETH to cold wallet {:?}",
+                    format_ether(amount),
+                    cold_wallet
+                );
+            }
+        }
+
+        if balance < self.config.floor {
+            let Some(funding_wallet) = &self.config.funding_wallet else {
+                return Ok(());
+            };
+            let amount = self.config.target - balance;
+            let (mut tx, cost) = self
+                .transfer_request(funding_wallet.address(), hot_wallet)
+                .await?;
+            let funds = self.balance(funding_wallet.address()).await?;
+            if funds < amount + cost {
+                self.bundler.metrics.balance_alerts.inc();
+                log::error!(
+                    "Funding wallet {:?} holds {} ETH, short of the {} ETH top-up",
+                    funding_wallet.address(),
+                    format_ether(funds),
+                    format_ether(amount)
+                );
+                return Ok(());
+            }
+            tx.set_value(amount);
+            self.transfer(funding_wallet.as_ref(), tx).await?;
+            self.bundler
+                .metrics
+                .sweeps
+                .with_label_values(&["top_up"])
+                .inc();
+            log::info!(
+                "Topped up bundler wallet {:?} with {} ETH",
+                hot_wallet,
+                format_ether(amount)
+            );
+        }
+        Ok(())
+    }
+
+    async fn balance(&self, account: Address) -> anyhow::Result<U256> {
+        self.bundler
+            .eth_provider
+            .get_balance(account, None)
+            .await
+            .map_err(|err| anyhow::anyhow!("Failed to fetch balance of {account:?}: {err}"))
+    }
+
+    /// Transfer from `from` to `to` with its gas and gas price set, along
+    /// with the most it costs in gas
+    async fn transfer_request(
+        &self,
+        from: Address,
+        to: Address,
+    ) -> anyhow::Result<(TypedTransaction, U256)> {
+        let provider = self.bundler.eth_provider.as_ref();
+        let mut tx: TypedTransaction = TransactionRequest::new().from(from).to(to).into();
+        let gas = provider
+            .estimate_gas(&tx, None)
+            .await
+            .map_err(|err| anyhow::anyhow!("Failed to estimate transfer gas: {err}"))?;
+        let gas_price = provider
+            .get_gas_price()
+            .await
+            .map_err(|err| anyhow::anyhow!("Failed to fetch gas price: {err}"))?;
+        tx.set_gas(gas).set_gas_price(gas_price);
+        Ok((tx, gas * gas_price))
+    }
+
+    async fn transfer(
+        &self,
+        from: &dyn BundlerSigner,
+        mut tx: TypedTransaction,
+    ) -> anyhow::Result<()> {
+        let provider = self.bundler.eth_provider.as_ref();
+        let raw_tx = sign_transaction(provider, from, &mut tx).await?;
+        match send_transaction(provider, raw_tx).await? {
+            Some(receipt) if receipt.status == Some(1.into()) => Ok(()),
+            Some(receipt) => anyhow::bail!("Transfer {:?} reverted", receipt.transaction_hash),
+            None => anyhow::bail!("Transfer dropped from the mempool"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundler::{
+        bundler::tests::mocked_bundler, signer::KeySource, signer_pool::SignerPool,
+    };
+    use ethers::{
+        providers::{JsonRpcError, MockProvider, MockResponse},
+        types::{Transaction, TransactionReceipt, H256, U64},
+    };
+
+    const TRANSFER_GAS: u64 = 21_000;
+    const GAS_PRICE: u64 = 1_000_000_000;
+
+    fn ether(amount: u64) -> U256 {
+        U256::exp10(18) * amount
+    }
+
+    fn key(index: u32) -> anyhow::Result<Arc<dyn BundlerSigner>> {
+        let source = KeySource::Mnemonic {
+            phrase: "test test test test test test test test test test test junk".to_string(),
+            index,
+        };
+        Ok(Arc::new(source.load(80001)?))
+    }
+
+    /// Alerts below 1 ETH, tops up below 2 ETH and sweeps above 5 ETH, back to 3 ETH
+    fn config() -> anyhow::Result<SweeperConfig> {
+        Ok(SweeperConfig::new(ether(2), ether(3), ether(5), ether(1))?
+            .with_cold_wallet(Address::repeat_byte(0xc0))
+            .with_funding_wallet(key(1)?))
+    }
+
+    /// Responses to a transfer being signed and sent, then landing
+    fn push_transfer(mock: &MockProvider) -> anyhow::Result<()> {
+        mock.push(TransactionReceipt {
+            transaction_hash: H256::repeat_byte(1),
+            status: Some(U64::from(1)),
+            ..Default::default()
+        })?;
+        mock.push(Transaction {
+            hash: H256::repeat_byte(1),
+            block_number: Some(U64::from(1)),
+            ..Default::default()
+        })?;
+        mock.push(H256::repeat_byte(1))?;
+        mock.push(U256::from(80001))?;
+        Ok(())
+    }
+
+    /// Responses to a transfer request being priced
+    fn push_transfer_cost(mock: &MockProvider) -> anyhow::Result<()> {
+        mock.push(U256::from(GAS_PRICE))?;
+        mock.push(U256::from(TRANSFER_GAS))?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sweeps_excess_less_transfer_gas() -> anyhow::Result<()> {
+        let (bundler, mock) = mocked_bundler()?;
+        let hot_wallet = bundler.signers.addresses()[0];
+        let sweeper = Sweeper::new(bundler.clone(), config()?);
+
+        push_transfer(&mock)?;
+        mock.push(U256::from(7))?;
+        push_transfer_cost(&mock)?;
+        mock.push(ether(10))?;
+        sweeper.run_once().await?;
+
+        let sweeps = &bundler.metrics.sweeps;
+        assert_eq!(sweeps.with_label_values(&["sweep"]).get(), 1);
+        assert_eq!(sweeps.with_label_values(&["top_up"]).get(), 0);
+        assert_eq!(bundler.metrics.balance_alerts.get(), 0);
+        // the nonce is released for the bundles
+        assert_eq!(bundler.signers.in_flight(hot_wallet).await, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn skips_sweep_eaten_by_gas_or_with_bundles_in_flight() -> anyhow::Result<()> {
+        let (bundler, mock) = mocked_bundler()?;
+        let config = SweeperConfig::new(ether(2), ether(3), ether(3), ether(1))?
+            .with_cold_wallet(Address::repeat_byte(0xc0));
+        let sweeper = Sweeper::new(bundler.clone(), config);
+
+        // the excess does not cover the gas of the transfer
+        push_transfer_cost(&mock)?;
+        mock.push(ether(3) + TRANSFER_GAS * GAS_PRICE - 1)?;
+        sweeper.run_once().await?;
+
+        // a bundle holds the next nonce of the key
+        mock.push(U256::from(7))?;
+        let lease = bundler
+            .signers
+            .acquire(bundler.eth_provider.as_ref(), U256::zero())
+            .await?;
+        push_transfer_cost(&mock)?;
+        mock.push(ether(10))?;
+        sweeper.run_once().await?;
+
+        let sweeps = &bundler.metrics.sweeps;
+        assert_eq!(sweeps.with_label_values(&["sweep"]).get(), 0);
+        assert_eq!(bundler.signers.in_flight(lease.address()).await, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tops_up_and_alerts_below_floor() -> anyhow::Result<()> {
+        let (bundler, mock) = mocked_bundler()?;
+        let sweeper = Sweeper::new(bundler.clone(), config()?);
+
+        // the nonce of the funding wallet is read from the chain
+        push_transfer(&mock)?;
+        mock.push(U256::from(3))?;
+        mock.push(ether(10))?;
+        push_transfer_cost(&mock)?;
+        mock.push(U256::exp10(17))?;
+        sweeper.run_once().await?;
+
+        let sweeps = &bundler.metrics.sweeps;
+        assert_eq!(sweeps.with_label_values(&["top_up"]).get(), 1);
+        assert_eq!(sweeps.with_label_values(&["sweep"]).get(), 0);
+        assert_eq!(bundler.metrics.balance_alerts.get(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn alerts_when_funding_wallet_runs_short() -> anyhow::Result<()> {
+        let (bundler, mock) = mocked_bundler()?;
+        let sweeper = Sweeper::new(bundler.clone(), config()?);
+
+        // the funding wallet holds the 1.5 ETH top-up but not its gas
+        mock.push(ether(3) / 2)?;
+        push_transfer_cost(&mock)?;
+        // above the alert threshold, below the floor
+        mock.push(ether(3) / 2)?;
+        sweeper.run_once().await?;
+
+        let sweeps = &bundler.metrics.sweeps;
+        assert_eq!(sweeps.with_label_values(&["top_up"]).get(), 0);
+        assert_eq!(bundler.metrics.balance_alerts.get(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn goes_on_with_other_wallets_after_failure() -> anyhow::Result<()> {
+        let (mut bundler, mock) = mocked_bundler()?;
+        bundler.signers = Arc::new(SignerPool::new(vec![key(0)?, key(2)?])?);
+        let sweeper = Sweeper::new(bundler.clone(), config()?);
+
+        // the balance of the first wallet cannot be read, the second one is swept
+        push_transfer(&mock)?;
+        mock.push(U256::from(7))?;
+        push_transfer_cost(&mock)?;
+        mock.push(ether(10))?;
+        mock.push_response(MockResponse::Error(JsonRpcError {
+            code: -32000,
+            message: "header not found".to_string(),
+            data: None,
+        }));
+        assert!(sweeper.run_once().await.is_err());
+
+        let sweeps = &bundler.metrics.sweeps;
+        assert_eq!(sweeps.with_label_values(&["sweep"]).get(), 1);
+        Ok(())
+    }
+}
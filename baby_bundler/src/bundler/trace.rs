@@ -0,0 +1,96 @@
+use crate::bundler::server::parse_calls;
+use ethers::core::rand;
+use hyper::{http::HeaderValue, Body, Method, Request, Response};
+use std::error::Error;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+use tracing::{field, Instrument};
+
+/// Header carrying the request id, set by the client or generated by the bundler
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Runs every request in an `rpc` span with a request id, taken from the
+/// `X-Request-Id` header if the client sent one, and returns the id in the
+/// same header. The logs of the bundler carry the span of the request that
+/// led to them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TraceLayer;
+
+impl<S> Layer<S> for TraceLayer {
+    type Service = TraceRequest<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TraceRequest { inner }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TraceRequest<S> {
+    inner: S,
+}
+
+impl<S> Service<Request<Body>> for TraceRequest<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + 'static,
+    S::Error: Into<Box<dyn Error + Send + Sync>> + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = Box<dyn Error + Send + Sync + 'static>;
+    type Future =
+        Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let request_id = request_id(&req);
+        let span = tracing::info_span!(
+            "rpc",
+            request_id = %request_id,
+            methods = field::Empty
+        );
+
+        Box::pin(
+            async move {
+                let mut res = if req.method() == Method::POST {
+                    // the body was capped by the `BodyLimitLayer` in front of this one
+                    let (req_h, req_b) = req.into_parts();
+                    let req_bb = hyper::body::to_bytes(req_b).await?;
+                    let methods: Vec<String> = parse_calls(&req_bb)
+                        .iter()
+                        .filter_map(|call| call["method"].as_str().map(str::to_string))
+                        .collect();
+                    tracing::Span::current().record("methods", methods.join(",").as_str());
+
+                    inner
+                        .call(Request::from_parts(req_h, Body::from(req_bb)))
+                        .await
+                        .map_err(Into::into)?
+                } else {
+                    inner.call(req).await.map_err(Into::into)?
+                };
+
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    res.headers_mut().insert(REQUEST_ID_HEADER, value);
+                }
+                Ok(res)
+            }
+            .instrument(span),
+        )
+    }
+}
+
+fn request_id(req: &Request<Body>) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()))
+}
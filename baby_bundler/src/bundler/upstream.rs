@@ -0,0 +1,488 @@
+use crate::bundler::{auth::METHOD_NOT_SUPPORTED_CODE, cache::ResponseCache};
+use ethers::types::U64;
+use hyper::{Body, Response};
+use jsonrpsee::{
+    core::{client::ClientT, params::BatchRequestBuilder, traits::ToRpcParams, Error as RpcError},
+    http_client::{HttpClient, HttpClientBuilder},
+    types::error::{CallError, ErrorCode, ErrorObject},
+    ws_client::{WsClient, WsClientBuilder},
+};
+use serde_json::{json, value::RawValue, Value};
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{sync::Mutex, task::JoinHandle};
+
+type BoxError = Box<dyn Error + Send + Sync + 'static>;
+
+/// Outcome of a call answered by the upstream, the result or the error object
+type CallOutcome = Result<Value, Value>;
+
+/// Node the proxy forwards the methods the bundler does not serve to
+#[derive(Clone, Debug)]
+pub enum Upstream {
+    /// HTTP(S) endpoint
+    Http { url: Arc<str>, client: HttpClient },
+    /// WebSocket endpoint, one connection is opened on first use and shared by
+    /// every request, it is opened again after a transport error
+    Ws {
+        url: Arc<str>,
+        client: Arc<Mutex<Option<Arc<WsClient>>>>,
+    },
+}
+
+impl Upstream {
+    /// Picks the transport from the URL scheme
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        if url.starts_with("ws://") || url.starts_with("wss://") {
+            Ok(Upstream::Ws {
+                url: Arc::from(url),
+                client: Arc::new(Mutex::new(None)),
+            })
+        } else {
+            Ok(Upstream::Http {
+                url: Arc::from(url),
+                client: HttpClientBuilder::default().build(url)?,
+            })
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        match self {
+            Upstream::Http { url, .. } | Upstream::Ws { url, .. } => url,
+        }
+    }
+
+    /// Sends a single call, failing only if the upstream could not answer it
+    async fn request(&self, method: &str, params: Value) -> Result<CallOutcome, BoxError> {
+        match self {
+            Upstream::Http { client, .. } => request(client, method, params).await,
+            Upstream::Ws { url, client } => {
+                let ws = connect(url, client).await?;
+                let outcome = request(ws.as_ref(), method, params).await;
+                if outcome.is_err() {
+                    *client.lock().await = None;
+                }
+                outcome
+            }
+        }
+    }
+
+    /// Sends the calls in a single batch, failing only if the upstream could
+    /// not answer it, the outcomes being in the order of the calls
+    async fn batch_request(&self, calls: &[(String, Value)]) -> Result<Vec<CallOutcome>, BoxError> {
+        match self {
+            Upstream::Http { client, .. } => batch_request(client, calls).await,
+            Upstream::Ws { url, client } => {
+                let ws = connect(url, client).await?;
+                let outcomes = batch_request(ws.as_ref(), calls).await;
+                if outcomes.is_err() {
+                    *client.lock().await = None;
+                }
+                outcomes
+            }
+        }
+    }
+}
+
+/// Shared connection of a WebSocket upstream, opened if there is none or it dropped
+async fn connect(
+    url: &str,
+    client: &Mutex<Option<Arc<WsClient>>>,
+) -> Result<Arc<WsClient>, BoxError> {
+    let mut connection = client.lock().await;
+    match connection.as_ref() {
+        Some(ws) if ws.is_connected() => Ok(ws.clone()),
+        _ => {
+            let ws = Arc::new(WsClientBuilder::default().build(url).await?);
+            *connection = Some(ws.clone());
+            Ok(ws)
+        }
+    }
+}
+
+/// Params of a proxied call, forwarded as the client sent them
+struct RawParams(Value);
+
+impl ToRpcParams for RawParams {
+    fn to_rpc_params(self) -> Result<Option<Box<RawValue>>, serde_json::Error> {
+        serde_json::value::to_raw_value(&self.0).map(Some)
+    }
+}
+
+async fn request<C: ClientT>(
+    client: &C,
+    method: &str,
+    params: Value,
+) -> Result<CallOutcome, BoxError> {
+    match client.request::<Value, _>(method, RawParams(params)).await {
+        Ok(result) => Ok(Ok(result)),
+        Err(RpcError::Call(CallError::Custom(error))) => Ok(Err(error_value(&error))),
+        Err(err) => Err(Box::new(err)),
+    }
+}
+
+async fn batch_request<C: ClientT>(
+    client: &C,
+    calls: &[(String, Value)],
+) -> Result<Vec<CallOutcome>, BoxError> {
+    let mut batch = BatchRequestBuilder::new();
+    for (method, params) in calls {
+        batch.insert(method, RawParams(params.clone()))?;
+    }
+    // the responses are matched to the calls on their ids by the client
+    let responses = client.batch_request::<Value>(batch).await?;
+    Ok(responses
+        .into_iter()
+        .map(|response| response.map_err(|error| error_value(&error)))
+        .collect())
+}
+
+fn error_value(error: &ErrorObject) -> Value {
+    json!({ "code": error.code(), "message": error.message(), "data": error.data() })
+}
+
+/// How the proxy picks the upstream a call is sent to first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelectionStrategy {
+    /// Always the first healthy upstream in the configured order
+    Failover,
+    /// Each healthy upstream in turn
+    RoundRobin,
+}
+
+#[derive(Clone, Debug)]
+pub struct ProxyConfig {
+    pub upstreams: Vec<String>,
+    pub strategy: SelectionStrategy,
+    /// Methods that may be proxied, any method not denied if not set. A pattern
+    /// ending with `*` matches every method with that prefix.
+    pub allowed_methods: Option<Vec<String>>,
+    /// Methods that are never proxied, same patterns as `allowed_methods`
+    pub denied_methods: Vec<String>,
+    /// Time an upstream has to answer before the next one is tried
+    pub timeout: Duration,
+    /// Largest result or error, in bytes, forwarded to the client
+    pub max_response_size: usize,
+    pub health_check_interval: Duration,
+    /// Cache of the chain constants and block scoped results, none if not set
+    pub cache: Option<Arc<ResponseCache>>,
+}
+
+impl ProxyConfig {
+    /// Fails over between `upstreams` and denies the namespaces that manage the
+    /// node or its accounts
+    pub fn new(upstreams: Vec<String>) -> Self {
+        Self {
+            upstreams,
+            strategy: SelectionStrategy::Failover,
+            allowed_methods: None,
+            denied_methods: ["personal_*", "admin_*", "debug_*", "miner_*", "clique_*"]
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+            timeout: Duration::from_secs(10),
+            max_response_size: 10 * 1024 * 1024,
+            health_check_interval: Duration::from_secs(15),
+            cache: None,
+        }
+    }
+
+    pub fn with_strategy(mut self, strategy: SelectionStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    pub fn with_allowed_methods(mut self, methods: Vec<String>) -> Self {
+        self.allowed_methods = Some(methods);
+        self
+    }
+
+    pub fn with_denied_methods(mut self, methods: Vec<String>) -> Self {
+        self.denied_methods = methods;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_max_response_size(mut self, max_response_size: usize) -> Self {
+        self.max_response_size = max_response_size;
+        self
+    }
+
+    pub fn with_cache(mut self, cache: Arc<ResponseCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Whether `method` may be forwarded to the upstreams
+    pub fn is_allowed(&self, method: &str) -> bool {
+        let matches = |pattern: &String| match pattern.strip_suffix('*') {
+            Some(prefix) => method.starts_with(prefix),
+            None => method == pattern,
+        };
+        let allowed = match &self.allowed_methods {
+            Some(allowed) => allowed.iter().any(matches),
+            None => true,
+        };
+        allowed && !self.denied_methods.iter().any(matches)
+    }
+}
+
+/// Upstream nodes the proxy spreads calls over, skipping the unhealthy ones
+#[derive(Debug)]
+pub struct UpstreamPool {
+    config: ProxyConfig,
+    upstreams: Vec<Upstream>,
+    healthy: Vec<AtomicBool>,
+    next: AtomicUsize,
+}
+
+impl UpstreamPool {
+    pub fn new(config: ProxyConfig) -> anyhow::Result<Self> {
+        let upstreams = config
+            .upstreams
+            .iter()
+            .map(|url| Upstream::new(url))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if upstreams.is_empty() {
+            anyhow::bail!("Proxy has no upstream");
+        }
+
+        Ok(Self {
+            healthy: upstreams.iter().map(|_| AtomicBool::new(true)).collect(),
+            upstreams,
+            config,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Spawns the task that probes every upstream with `eth_blockNumber`
+    pub fn spawn_health_checks(self: &Arc<Self>) -> JoinHandle<()> {
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(pool.config.health_check_interval);
+            loop {
+                interval.tick().await;
+                for (index, upstream) in pool.upstreams.iter().enumerate() {
+                    let probe = upstream.request("eth_blockNumber", json!([]));
+                    let healthy = matches!(
+                        tokio::time::timeout(pool.config.timeout, probe).await,
+                        Ok(Ok(Ok(_)))
+                    );
+                    pool.set_healthy(index, healthy);
+                }
+            }
+        })
+    }
+
+    fn set_healthy(&self, index: usize, healthy: bool) {
+        if self.healthy[index].swap(healthy, Ordering::Relaxed) != healthy {
+            log::info!(
+                "Upstream {} is {}",
+                self.upstreams[index].url(),
+                if healthy { "healthy" } else { "unhealthy" }
+            );
+        }
+    }
+
+    /// Indexes of the upstreams in the order they are tried, healthy ones first
+    fn candidates(&self) -> Vec<usize> {
+        let count = self.upstreams.len();
+        let start = match self.config.strategy {
+            SelectionStrategy::Failover => 0,
+            SelectionStrategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % count,
+        };
+        let mut order: Vec<usize> = (0..count).map(|offset| (start + offset) % count).collect();
+        order.sort_by_key(|index| !self.healthy[*index].load(Ordering::Relaxed));
+        order
+    }
+
+    /// Forwards a JSON-RPC request, single or batch, and returns the response
+    /// of the upstreams with the ids of the original calls
+    pub async fn forward(&self, body: &[u8]) -> Result<Response<Body>, BoxError> {
+        let request: Value = serde_json::from_slice(body)?;
+        let body = match request {
+            Value::Array(calls) => Value::Array(self.forward_calls(&calls).await),
+            call => self.forward_call(&call).await,
+        };
+
+        Ok(Response::builder()
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))?)
+    }
+
+    /// Forwards the calls of a batch, returning the responses of those that
+    /// are not notifications in the same order. The calls that are neither
+    /// denied nor answered from the cache go to the upstream in a single batch.
+    pub async fn forward_calls(&self, calls: &[Value]) -> Vec<Value> {
+        let mut outcomes: Vec<Option<CallOutcome>> = vec![None; calls.len()];
+        let mut misses = vec![];
+        for (index, call) in calls.iter().enumerate() {
+            let method = call["method"].as_str().unwrap_or_default();
+            let params = call.get("params").cloned().unwrap_or(json!([]));
+            if !self.config.is_allowed(method) {
+                outcomes[index] = Some(Err(not_available(method)));
+                continue;
+            }
+            match self.cached(method, &params) {
+                Ok(result) => outcomes[index] = Some(Ok(result)),
+                Err(head) => misses.push((index, method.to_string(), params, head)),
+            }
+        }
+
+        let requests: Vec<(String, Value)> = misses
+            .iter()
+            .map(|(_, method, params, _)| (method.clone(), params.clone()))
+            .collect();
+        let answers = self.batch_request(&requests).await;
+        for ((index, method, params, head), outcome) in misses.into_iter().zip(answers) {
+            if let (Ok(result), Some(cache)) = (&outcome, &self.config.cache) {
+                if cache.is_cached(&method, &params) {
+                    cache.insert(&method, &params, result.clone(), head);
+                }
+            }
+            outcomes[index] = Some(outcome);
+        }
+
+        calls
+            .iter()
+            .zip(outcomes)
+            .filter(|(call, _)| call.get("id").is_some())
+            .map(|(call, outcome)| response(call, outcome.unwrap_or_else(|| Err(no_upstream()))))
+            .collect()
+    }
+
+    async fn forward_call(&self, call: &Value) -> Value {
+        let method = call["method"].as_str().unwrap_or_default();
+        let params = call.get("params").cloned().unwrap_or(json!([]));
+
+        let outcome = if self.config.is_allowed(method) {
+            self.cached_request(method, params).await
+        } else {
+            Err(not_available(method))
+        };
+        response(call, outcome)
+    }
+
+    /// Returns the cached result of a call, or the head to cache it at on a miss
+    fn cached(&self, method: &str, params: &Value) -> Result<Value, Option<U64>> {
+        match &self.config.cache {
+            Some(cache) if cache.is_cached(method, params) => match cache.get(method, params) {
+                (Some(result), _) => Ok(result),
+                (None, head) => Err(head),
+            },
+            _ => Err(None),
+        }
+    }
+
+    /// Answers the call from the cache if it holds its result
+    async fn cached_request(&self, method: &str, params: Value) -> CallOutcome {
+        let head = match self.cached(method, &params) {
+            Ok(result) => return Ok(result),
+            Err(head) => head,
+        };
+        let outcome = self.request(method, params.clone()).await;
+        if let (Ok(result), Some(cache)) = (&outcome, &self.config.cache) {
+            if cache.is_cached(method, &params) {
+                cache.insert(method, &params, result.clone(), head);
+            }
+        }
+        outcome
+    }
+
+    /// Tries the upstreams in turn until one answers within the timeout
+    async fn request(&self, method: &str, params: Value) -> CallOutcome {
+        for index in self.candidates() {
+            let upstream = &self.upstreams[index];
+            let call = upstream.request(method, params.clone());
+            match tokio::time::timeout(self.config.timeout, call).await {
+                Ok(Ok(outcome)) => return self.limit_size(outcome),
+                Ok(Err(err)) => {
+                    log::warn!("Upstream {} failed: {:?}", upstream.url(), err);
+                }
+                Err(_) => {
+                    log::warn!("Upstream {} timed out on {}", upstream.url(), method);
+                }
+            }
+            self.set_healthy(index, false);
+        }
+
+        Err(no_upstream())
+    }
+
+    /// Tries the upstreams in turn until one answers the whole batch within
+    /// the timeout
+    async fn batch_request(&self, calls: &[(String, Value)]) -> Vec<CallOutcome> {
+        if calls.is_empty() {
+            return vec![];
+        }
+
+        for index in self.candidates() {
+            let upstream = &self.upstreams[index];
+            let batch = upstream.batch_request(calls);
+            match tokio::time::timeout(self.config.timeout, batch).await {
+                Ok(Ok(outcomes)) => {
+                    return outcomes
+                        .into_iter()
+                        .map(|outcome| self.limit_size(outcome))
+                        .collect();
+                }
+                Ok(Err(err)) => {
+                    log::warn!("Upstream {} failed on a batch: {:?}", upstream.url(), err);
+                }
+                Err(_) => {
+                    log::warn!("Upstream {} timed out on a batch", upstream.url());
+                }
+            }
+            self.set_healthy(index, false);
+        }
+
+        calls.iter().map(|_| Err(no_upstream())).collect()
+    }
+
+    /// Replaces an outcome larger than the configured size by an error
+    fn limit_size(&self, outcome: CallOutcome) -> CallOutcome {
+        let size = match &outcome {
+            Ok(value) | Err(value) => value.to_string().len(),
+        };
+        if size > self.config.max_response_size {
+            return Err(error_object(
+                ErrorCode::InternalError.code(),
+                format!("Response of {size} bytes exceeds the proxy limit"),
+            ));
+        }
+        outcome
+    }
+}
+
+fn response(call: &Value, outcome: CallOutcome) -> Value {
+    let mut response = json!({ "jsonrpc": "2.0", "id": call["id"].clone() });
+    match outcome {
+        Ok(result) => response["result"] = result,
+        Err(error) => response["error"] = error,
+    }
+    response
+}
+
+fn not_available(method: &str) -> Value {
+    error_object(
+        METHOD_NOT_SUPPORTED_CODE,
+        format!("Method {method} is not available"),
+    )
+}
+
+fn no_upstream() -> Value {
+    error_object(
+        ErrorCode::InternalError.code(),
+        "No upstream available".to_string(),
+    )
+}
+
+fn error_object(code: i32, message: String) -> Value {
+    json!({ "code": code, "message": message })
+}
@@ -1,25 +1,234 @@
-mod bindings;
-mod bundler;
-
-use crate::bundler::{
-    bundler::{BabyBundler, EthApiServer},
+use anyhow::{Context, Result};
+use baby_bundler::bundler::{
+    auth::{AdminApiServer, ApiKeys},
+    bundler::{BabyBundler, BundlerApiServer, EthApiServer, ENTRY_POINT_ADDRESS},
+    cache::ResponseCache,
+    debug::DebugApiServer,
+    events::UserOperationEventsApiServer,
+    operator::{Operator, OperatorAction, OperatorOutcome},
+    rate_limit::{BucketConfig, RateLimitConfig},
     server::JsonRpcServer,
+    signer::{BundlerSigner, KeySource, RemoteSigner},
+    signer_pool::SignerPool,
+    store::Store,
+    sweeper::{Sweeper, SweeperConfig},
+    upstream::ProxyConfig,
 };
-use anyhow::Result;
+use clap::{Parser, Subcommand};
 use dotenv::dotenv;
-use env_logger::Env;
 use ethers::{
-    providers::{Provider, Ws},
-    types::U256,
+    prelude::LocalWallet,
+    providers::{Middleware, Provider, Ws},
+    types::{Address, U256},
+    utils::format_ether,
 };
+use std::env;
 use std::sync::Arc;
-use std::{env, future::pending};
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
+
+/// Time in-flight bundles are given to be submitted on shutdown
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Parser)]
+#[command(about = "ERC-4337 bundler serving the bundler RPC, or managing its entry point deposit")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Print the signed transaction instead of sending it
+    #[arg(long, global = true)]
+    dry_run: bool,
+}
+
+/// Operator commands, the RPC is served if none is given
+#[derive(Subcommand)]
+enum Command {
+    /// Add to the entry point deposit of an account, the bundler by default
+    Deposit {
+        /// Amount in wei
+        #[arg(long, value_parser = parse_wei)]
+        amount: U256,
+        #[arg(long)]
+        account: Option<Address>,
+    },
+    /// Withdraw from the entry point deposit of the bundler
+    Withdraw {
+        /// Amount in wei
+        #[arg(long, value_parser = parse_wei)]
+        amount: U256,
+        /// Recipient, the bundler by default
+        #[arg(long)]
+        to: Option<Address>,
+    },
+    /// Stake in the entry point
+    Stake {
+        /// Amount in wei
+        #[arg(long, value_parser = parse_wei)]
+        amount: U256,
+        #[arg(long, default_value_t = 86_400)]
+        unstake_delay_sec: u32,
+    },
+    /// Unlock the stake, which can be withdrawn after the unstake delay
+    Unlock,
+    /// Withdraw the unlocked stake
+    WithdrawStake {
+        /// Recipient, the bundler by default
+        #[arg(long)]
+        to: Option<Address>,
+    },
+    /// Show the entry point deposit and stake of an account, the bundler by default
+    Status {
+        #[arg(long)]
+        account: Option<Address>,
+    },
+}
+
+/// Key of the bundler EOA, held by a remote signer at `REMOTE_SIGNER_URL`, in
+/// the keystore at `KEYSTORE_PATH` or derived from `PHRASE` at `HD_INDEX`
+async fn bundler_signer(chain_id: u64) -> Result<Arc<dyn BundlerSigner>> {
+    if let Ok(url) = env::var("REMOTE_SIGNER_URL") {
+        let address = match env::var("REMOTE_SIGNER_ADDRESS") {
+            Ok(address) => Some(address.parse()?),
+            Err(_) => None,
+        };
+        return Ok(Arc::new(RemoteSigner::connect(&url, address).await?));
+    }
+
+    let source = match env::var("KEYSTORE_PATH") {
+        Ok(path) => KeySource::Keystore {
+            path: path.into(),
+            password_file: env::var("KEYSTORE_PASSWORD_FILE")
+                .context("KEYSTORE_PATH requires KEYSTORE_PASSWORD_FILE")?
+                .into(),
+        },
+        Err(_) => KeySource::Mnemonic {
+            phrase: env::var("PHRASE").context("Please set the PHRASE environment variable")?,
+            index: env::var("HD_INDEX")
+                .map(|index| index.parse())
+                .unwrap_or(Ok(0))?,
+        },
+    };
+    Ok(Arc::new(source.load(chain_id)?))
+}
+
+/// Keys the bundles are spread over: the bundler EOA, the `SIGNER_POOL_SIZE - 1`
+/// indexes of `PHRASE` following `HD_INDEX` and the comma separated `POOL_PRIVATE_KEYS`
+fn signer_pool(primary: Arc<dyn BundlerSigner>, chain_id: u64) -> Result<SignerPool> {
+    let mut signers = vec![primary];
+    let size: u32 = env::var("SIGNER_POOL_SIZE")
+        .map(|size| size.parse())
+        .unwrap_or(Ok(1))?;
+    if size > 1 {
+        let phrase = env::var("PHRASE").context("SIGNER_POOL_SIZE requires PHRASE")?;
+        let first: u32 = env::var("HD_INDEX")
+            .map(|index| index.parse())
+            .unwrap_or(Ok(0))?;
+        for index in first + 1..first + size {
+            let source = KeySource::Mnemonic {
+                phrase: phrase.clone(),
+                index,
+            };
+            signers.push(Arc::new(source.load(chain_id)?));
+        }
+    }
+    if let Ok(keys) = env::var("POOL_PRIVATE_KEYS") {
+        for key in keys.split(',') {
+            let source = KeySource::PrivateKey(key.trim().to_string());
+            signers.push(Arc::new(source.load(chain_id)?));
+        }
+    }
+    SignerPool::new(signers)
+}
+
+/// Key authenticating bundles with Flashbots, from the keystore at
+/// `FLASHBOTS_KEYSTORE_PATH` or the `FLASHBOTS_IDENTIFIER` private key
+fn flashbots_identity(chain_id: u64) -> Result<Option<LocalWallet>> {
+    let source = match (
+        env::var("FLASHBOTS_KEYSTORE_PATH"),
+        env::var("FLASHBOTS_IDENTIFIER"),
+    ) {
+        (Ok(path), _) => KeySource::Keystore {
+            path: path.into(),
+            password_file: env::var("FLASHBOTS_KEYSTORE_PASSWORD_FILE")
+                .context("FLASHBOTS_KEYSTORE_PATH requires FLASHBOTS_KEYSTORE_PASSWORD_FILE")?
+                .into(),
+        },
+        (_, Ok(key)) => KeySource::PrivateKey(key),
+        _ => return Ok(None),
+    };
+    Ok(Some(source.load(chain_id)?))
+}
+
+/// Reads an amount of wei from the environment, none if unset
+fn wei_var(name: &str) -> Result<Option<U256>> {
+    match env::var(name) {
+        Ok(wei) => Ok(Some(
+            U256::from_dec_str(&wei).with_context(|| format!("Invalid {name} {wei:?}"))?,
+        )),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Reads a token bucket from the environment as `<capacity>/<refill per second>`, none if unset
+fn bucket_var(name: &str) -> Result<Option<BucketConfig>> {
+    match env::var(name) {
+        Ok(bucket) => {
+            let invalid = || format!("Invalid {name} {bucket:?}");
+            let (capacity, refill_per_second) = bucket.split_once('/').with_context(invalid)?;
+            Ok(Some(BucketConfig {
+                capacity: capacity.trim().parse().with_context(invalid)?,
+                refill_per_second: refill_per_second.trim().parse().with_context(invalid)?,
+            }))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Rate limits of `RATE_LIMIT_IP` and `RATE_LIMIT_SENDER`, with the comma
+/// separated `<method>=<cost>` of `RATE_LIMIT_METHOD_COSTS`. The client IP is
+/// read from `X-Forwarded-For` when the peer is one of the comma separated
+/// `TRUSTED_PROXIES`
+fn rate_limit_config() -> Result<RateLimitConfig> {
+    let mut config = RateLimitConfig::default();
+    if let Some(ip) = bucket_var("RATE_LIMIT_IP")? {
+        config = config.with_ip(ip);
+    }
+    if let Some(sender) = bucket_var("RATE_LIMIT_SENDER")? {
+        config = config.with_sender(sender);
+    }
+    if let Ok(costs) = env::var("RATE_LIMIT_METHOD_COSTS") {
+        for method_cost in costs.split(',') {
+            let invalid = || format!("Invalid RATE_LIMIT_METHOD_COSTS {costs:?}");
+            let (method, cost) = method_cost.split_once('=').with_context(invalid)?;
+            config =
+                config.with_method_cost(method.trim(), cost.trim().parse().with_context(invalid)?);
+        }
+    }
+    if let Ok(proxies) = env::var("TRUSTED_PROXIES") {
+        let proxies = proxies
+            .split(',')
+            .map(|proxy| proxy.trim().parse())
+            .collect::<Result<_, _>>()
+            .with_context(|| format!("Invalid TRUSTED_PROXIES {proxies:?}"))?;
+        config = config.with_trusted_proxies(proxies);
+    }
+    Ok(config)
+}
 
-use aa_bundler_primitives::Wallet;
+fn parse_wei(amount: &str) -> Result<U256, String> {
+    U256::from_dec_str(amount).map_err(|err| err.to_string())
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let cli = Cli::parse();
+
+    // log records of the bundler and its dependencies go through the same subscriber
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
 
     dotenv().ok();
     let goerli_url = env::var("WSS_RPC").expect("WSS_RPC not set");
@@ -31,24 +240,187 @@ async fn main() -> Result<()> {
             .ok_or(anyhow::anyhow!("Error connecting to Goerli"))
             .unwrap(),
     );
+    // keys sign for the chain of the node, read once here
+    let chain_id = goerli_provider
+        .get_chainid()
+        .await
+        .context("Failed to fetch the chain id")?
+        .as_u64();
+    log::info!("Chain id {chain_id}");
+
+    let signer = bundler_signer(chain_id).await?;
+    log::info!("Bundler EOA {:?}", signer.address());
 
-    let phrase = env::var("PHRASE").expect("Please set the PHRASe environment variable");
-    let wallet = Wallet::from_phrase(&phrase, &U256::from(80001)).unwrap();
-    log::info!("{:?}", wallet.signer);
+    if let Some(command) = cli.command {
+        let operator = Operator::new(goerli_provider, ENTRY_POINT_ADDRESS.parse()?, signer);
+        return run_operator(&operator, command, cli.dry_run).await;
+    }
+
+    let signers = signer_pool(signer, chain_id)?;
+    log::info!("Signer pool {:?}", signers.addresses());
 
+    let min_balance = wei_var("MIN_BALANCE_WEI")?.unwrap_or_default();
+    let store_path = env::var("STORE_PATH").unwrap_or_else(|_| "baby_bundler.db".to_string());
     let baby_bundler = BabyBundler::new(
         goerli_provider.clone(),
         U256::max_value(),
         U256::max_value(),
-        wallet,
-    );
+        signers,
+    )
+    .with_chain_id(chain_id.into())
+    .with_min_balance(min_balance)
+    .with_store(Store::open(store_path)?);
+    let baby_bundler = match flashbots_identity(chain_id)? {
+        Some(identity) => baby_bundler.with_flashbots_identity(identity),
+        None => baby_bundler,
+    };
+    let baby_bundler = match env::var("BENEFICIARY") {
+        Ok(beneficiary) => baby_bundler.with_beneficiary(beneficiary.parse()?),
+        Err(_) => baby_bundler,
+    };
+    baby_bundler.restore().await?;
+    baby_bundler.spawn_scheduler();
+
+    // keeps each bundler EOA between BALANCE_FLOOR_WEI and SWEEP_THRESHOLD_WEI
+    if let Some(target) = wei_var("BALANCE_TARGET_WEI")? {
+        let floor = wei_var("BALANCE_FLOOR_WEI")?.unwrap_or_default();
+        let mut config = SweeperConfig::new(
+            floor,
+            target,
+            wei_var("SWEEP_THRESHOLD_WEI")?.unwrap_or(U256::MAX),
+            wei_var("BALANCE_ALERT_WEI")?.unwrap_or(floor),
+        )?;
+        if let Ok(cold_wallet) = env::var("COLD_WALLET") {
+            config = config.with_cold_wallet(cold_wallet.parse()?);
+        }
+        if let Ok(key) = env::var("FUNDING_PRIVATE_KEY") {
+            config =
+                config.with_funding_wallet(Arc::new(KeySource::PrivateKey(key).load(chain_id)?));
+        }
+        Sweeper::new(baby_bundler.clone(), config).spawn();
+    }
+
+    // nodes the methods the bundler does not serve are proxied to, in failover order
+    let upstreams = env::var("PROXY_UPSTREAMS")
+        .map(|urls| urls.split(',').map(|url| url.trim().to_string()).collect())
+        .unwrap_or_else(|_| vec![goerli_url.clone()]);
 
-    let server = JsonRpcServer::new("127.0.0.1:3000".to_string())
-        .with_proxy(goerli_url.clone())
+    let proxy_cache = Arc::new(ResponseCache::new().with_metrics(baby_bundler.metrics.clone()));
+    proxy_cache.spawn_invalidation(goerli_provider.clone());
+
+    let mut server = JsonRpcServer::new("127.0.0.1:3000".to_string())
+        .with_health(Arc::new(baby_bundler.clone()))
+        .with_metrics(baby_bundler.metrics.clone())
+        .with_rate_limit(rate_limit_config()?)
+        .with_proxy_config(ProxyConfig::new(upstreams).with_cache(proxy_cache))
         .with_cors(vec!["*".to_string()]);
 
-    let _handle = server.start(baby_bundler.into_rpc()).await?;
-    let _ = pending::<Result<()>>().await;
+    let mut methods = EthApiServer::into_rpc(baby_bundler.clone());
+    methods.merge(BundlerApiServer::into_rpc(baby_bundler.clone()))?;
+    methods.merge(UserOperationEventsApiServer::into_rpc(baby_bundler.clone()))?;
+
+    // partner API keys, the RPC is open to anyone if not set
+    if let Ok(path) = env::var("API_KEYS_PATH") {
+        let api_keys = ApiKeys::from_file(path)?;
+        server = server.with_auth(api_keys.clone());
+        methods.merge(AdminApiServer::into_rpc(api_keys))?;
+    }
+
+    // debug_bundler_* methods for the ERC-4337 bundler spec tests, never enable in production
+    if env::var("DEBUG_RPC").as_deref() == Ok("true") {
+        log::warn!("Debug RPC namespace enabled");
+        methods.merge(DebugApiServer::into_rpc(baby_bundler.clone()))?;
+    }
+
+    let handle = server.start(methods).await?;
+    shutdown_signal().await?;
+
+    log::info!("Shutting down, no longer accepting requests");
+    handle.stop()?;
+    handle.stopped().await;
+    let summary = baby_bundler.shutdown(DRAIN_TIMEOUT).await;
+    if summary.drained {
+        log::info!("Shut down: {:?}", summary);
+    } else {
+        log::warn!("Shut down with in-flight bundles abandoned: {:?}", summary);
+    }
+    Ok(())
+}
+
+async fn run_operator(
+    operator: &Operator<Provider<Ws>>,
+    command: Command,
+    dry_run: bool,
+) -> Result<()> {
+    let bundler = operator.address();
+    let action = match command {
+        Command::Deposit { amount, account } => OperatorAction::Deposit {
+            account: account.unwrap_or(bundler),
+            amount,
+        },
+        Command::Withdraw { amount, to } => OperatorAction::Withdraw {
+            to: to.unwrap_or(bundler),
+            amount,
+        },
+        Command::Stake {
+            amount,
+            unstake_delay_sec,
+        } => OperatorAction::Stake {
+            unstake_delay_sec,
+            amount,
+        },
+        Command::Unlock => OperatorAction::Unlock,
+        Command::WithdrawStake { to } => OperatorAction::WithdrawStake {
+            to: to.unwrap_or(bundler),
+        },
+        Command::Status { account } => {
+            let account = account.unwrap_or(bundler);
+            let info = operator.deposit_info(account).await?;
+            println!("Account:        {:?}", account);
+            println!("Deposit:        {} ETH", format_ether(info.deposit));
+            println!("Staked:         {}", info.staked);
+            println!("Stake:          {} ETH", format_ether(info.stake));
+            println!("Unstake delay:  {} s", info.unstake_delay_sec);
+            println!("Withdraw time:  {}", info.withdraw_time);
+            return Ok(());
+        }
+    };
+
+    match operator.execute(action, dry_run).await? {
+        OperatorOutcome::DryRun { tx, raw_tx } => {
+            println!("{}", serde_json::to_string_pretty(&tx)?);
+            println!("{raw_tx}");
+        }
+        OperatorOutcome::Sent {
+            receipt: Some(receipt),
+        } => {
+            println!(
+                "Transaction {:?} included in block {:?} with status {:?}",
+                receipt.transaction_hash,
+                receipt.block_number.unwrap_or_default(),
+                receipt.status.unwrap_or_default()
+            );
+        }
+        OperatorOutcome::Sent { receipt: None } => {
+            println!("Transaction dropped from the mempool");
+        }
+    }
+    Ok(())
+}
+
+/// Resolves on SIGINT, or SIGTERM on Unix
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
     Ok(())
 }
 
@@ -76,6 +448,7 @@ mod test {
     }
 
     #[tokio::test]
+    #[ignore = "needs the bundler on 127.0.0.1:3000 and WSS_RPC, ACCOUNT_ADDRESS and UO_SIGNATURE"]
     async fn test() -> anyhow::Result<()> {
         fmt::Subscriber::builder()
             .with_max_level(tracing::Level::INFO)
@@ -0,0 +1,171 @@
+//! Encoding and decoding of the GatTx plugin calldata trailer.
+//!
+//! The trailer is appended to the wallet `execute` calldata and is ignored by the
+//! call itself. It holds the `keccak256("ENABLE_GAT")` key followed by the
+//! good-after timestamp as the last two 32-byte words of the data.
+//!
+//! The deployed `GatTx` does not read these words back: `preHook` slices the
+//! data from `calldatasize() - 56`, past its end, and both `preHook` and
+//! `checkData` compute the end of the data without skipping its length word.
+//! The tests below port those offsets so the mismatch stays visible.
+use ethers::{
+    types::{Bytes, U256},
+    utils::keccak256,
+};
+
+/// Length of the trailer: the `ENABLE_GAT` key followed by the timestamp
+pub const TRAILER_LENGTH: usize = 64;
+
+/// The key that enables the GatTx pre-hook, `keccak256("ENABLE_GAT")`
+pub fn enable_gat_key() -> [u8; 32] {
+    keccak256("ENABLE_GAT")
+}
+
+/// Appends the `ENABLE_GAT` key and the good-after timestamp to wallet calldata
+pub fn encode(call_data: impl AsRef<[u8]>, timestamp: U256) -> Bytes {
+    let call_data = call_data.as_ref();
+    let mut encoded = Vec::with_capacity(call_data.len() + TRAILER_LENGTH);
+    encoded.extend_from_slice(call_data);
+    encoded.extend_from_slice(&enable_gat_key());
+
+    let mut word = [0u8; 32];
+    timestamp.to_big_endian(&mut word);
+    encoded.extend_from_slice(&word);
+
+    encoded.into()
+}
+
+/// Reads the trailer back from wallet calldata, returning `(timestamp, enabled)`.
+/// Data shorter than the trailer is never enabled.
+pub fn decode(data: impl AsRef<[u8]>) -> (U256, bool) {
+    let data = data.as_ref();
+    if data.len() < TRAILER_LENGTH {
+        return (U256::zero(), false);
+    }
+
+    let trailer = &data[data.len() - TRAILER_LENGTH..];
+    let timestamp = U256::from_big_endian(&trailer[32..]);
+    let enabled = trailer[..32] == enable_gat_key();
+
+    (timestamp, enabled)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Memory offset of the first `bytes memory` allocated by solc, the word
+    /// before it being the zero slot
+    const FREE_MEMORY_START: usize = 0x80;
+
+    /// Memory of a fresh call frame after `bytes memory data_ = data`: the
+    /// length word at the free memory pointer followed by the bytes
+    fn to_memory(data: &[u8]) -> Vec<u8> {
+        let mut memory = vec![0u8; FREE_MEMORY_START];
+        let mut length = [0u8; 32];
+        U256::from(data.len()).to_big_endian(&mut length);
+        memory.extend_from_slice(&length);
+        memory.extend_from_slice(data);
+        memory
+    }
+
+    /// `mload(offset)`, memory past the end reads as zero
+    fn mload(memory: &[u8], offset: usize) -> [u8; 32] {
+        let mut word = [0u8; 32];
+        for (index, byte) in word.iter_mut().enumerate() {
+            *byte = memory.get(offset + index).copied().unwrap_or_default();
+        }
+        word
+    }
+
+    /// Port of `GatTx.preHook` called through the ABI with `data`, returning the
+    /// key and timestamp it reads, or the reason it reverts
+    fn solidity_pre_hook(data: &[u8]) -> Result<Option<([u8; 32], U256)>, &'static str> {
+        // selector, `target`, `value`, offset and length words, then the padded bytes
+        let calldata_size = 4 + 4 * 32 + data.len().div_ceil(32) * 32;
+        // size := sub(calldatasize(), 56)
+        let size = calldata_size - 56;
+        // data[size:] reverts when the slice starts past the end
+        let data_ = data.get(size..).ok_or("slice out of bounds")?;
+        if data_.len() < 64 {
+            return Ok(None);
+        }
+
+        let memory = to_memory(data_);
+        // dataEnd := add(data_, mload(data_))
+        let data_end = FREE_MEMORY_START + data_.len();
+        let timestamp_ = U256::from_big_endian(&mload(&memory, data_end - 32));
+        let key_ = mload(&memory, data_end - 64);
+        Ok(Some((key_, timestamp_)))
+    }
+
+    /// Port of `GatTx.checkData`, returning `(timestamp_, enabled_)`
+    fn solidity_check_data(data: &[u8]) -> (U256, bool) {
+        if data.len() < 64 {
+            return (U256::zero(), false);
+        }
+
+        let memory = to_memory(data);
+        let data_end = FREE_MEMORY_START + data.len();
+        // timestamp_ := mload(sub(data_, 32))
+        let timestamp_ = U256::from_big_endian(&mload(&memory, FREE_MEMORY_START - 32));
+        let key_ = mload(&memory, data_end - 64);
+        (timestamp_, key_ == enable_gat_key())
+    }
+
+    fn u256() -> impl Strategy<Value = U256> {
+        any::<[u8; 32]>().prop_map(|word| U256::from_big_endian(&word))
+    }
+
+    #[test]
+    fn decode_rejects_trailer_off_by_8() {
+        let timestamp = U256::from(1_700_000_000u64);
+        let encoded = encode([0xab; 100], timestamp);
+
+        // the last 56 bytes the pre-hook slices, and the trailer followed by 8 bytes
+        let short = &encoded[encoded.len() - 56..];
+        let mut long = encoded.to_vec();
+        long.extend_from_slice(&[0u8; 8]);
+
+        assert_eq!(decode(&encoded), (timestamp, true));
+        assert_eq!(decode(short), (U256::zero(), false));
+        assert!(!decode(&long).1);
+        assert!(!decode(&encoded[..encoded.len() - 8]).1);
+    }
+
+    proptest! {
+        #[test]
+        fn decode_inverts_encode(call_data in prop::collection::vec(any::<u8>(), 0..512), timestamp in u256()) {
+            let encoded = encode(&call_data, timestamp);
+            prop_assert_eq!(&encoded[..call_data.len()], &call_data[..]);
+            prop_assert_eq!(decode(&encoded), (timestamp, true));
+        }
+
+        #[test]
+        fn decode_reads_last_two_words(data in prop::collection::vec(any::<u8>(), 64..512)) {
+            let (timestamp, enabled) = decode(&data);
+            let end = data.len();
+            prop_assert_eq!(enabled, data[end - 64..end - 32] == enable_gat_key());
+            prop_assert_eq!(timestamp, U256::from_big_endian(&data[end - 32..]));
+        }
+
+        #[test]
+        fn pre_hook_slice_starts_past_the_data(call_data in prop::collection::vec(any::<u8>(), 0..512), timestamp in u256()) {
+            // calldatasize() covers the whole ABI encoded call, so `size` is always
+            // beyond `data.length` and the slice reverts before the key is read
+            let encoded = encode(&call_data, timestamp);
+            prop_assert_eq!(solidity_pre_hook(&encoded), Err("slice out of bounds"));
+        }
+
+        #[test]
+        fn check_data_misses_encoded_trailer(call_data in prop::collection::vec(any::<u8>(), 0..512), timestamp in u256()) {
+            // dataEnd points 32 bytes before the end of the bytes, so the key is read
+            // from the word before the trailer and the timestamp from the zero slot
+            let encoded = encode(&call_data, timestamp);
+            let (timestamp_, enabled_) = solidity_check_data(&encoded);
+            prop_assert_eq!(timestamp_, U256::zero());
+            prop_assert_eq!(enabled_, call_data.len() >= 32 && call_data[call_data.len() - 32..] == enable_gat_key());
+        }
+    }
+}
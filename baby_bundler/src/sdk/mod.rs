@@ -0,0 +1 @@
+pub mod gat_tx;
@@ -0,0 +1,94 @@
+//! End-to-end tests of the bundler against a local anvil node, failing
+//! when `anvil` or `solc` is not on the `PATH` unless `SKIP_ANVIL_TESTS` is set
+mod harness;
+
+use aa_bundler_primitives::{UserOperation, UserOperationHash, UserOperationReceipt};
+use baby_bundler::bindings::{uniswap_v2_router_1::SwapExactETHForTokensCall, weth::weth};
+use ethers::{
+    abi::AbiEncode, core::rand, prelude::LocalWallet, providers::Middleware, signers::Signer,
+    types::U256, utils::parse_ether,
+};
+use harness::{ExecuteCall, Harness, CHAIN_ID, SWAP_RATE};
+use jsonrpsee::{core::client::ClientT, rpc_params};
+
+#[tokio::test]
+async fn bundles_user_operation_deploying_account_and_swapping() -> anyhow::Result<()> {
+    if !harness::should_run()? {
+        return Ok(());
+    }
+    let harness = Harness::start().await?;
+    assert_eq!(harness.provider.get_chainid().await?, U256::from(CHAIN_ID));
+
+    let owner = LocalWallet::new(&mut rand::thread_rng());
+    let sender = harness
+        .account_address(owner.address(), U256::zero())
+        .await?;
+    harness.fund(sender, parse_ether(1)?).await?;
+
+    // the account is deployed by its first user operation, which swaps ETH for USDT
+    let amount = parse_ether("0.1")?;
+    let swap = SwapExactETHForTokensCall {
+        amount_out_min: amount * SWAP_RATE,
+        path: vec![harness.weth, harness.token],
+        to: sender,
+        deadline: U256::MAX,
+    };
+    let call_data = ExecuteCall {
+        dest: harness.router,
+        value: amount,
+        func: swap.encode().into(),
+    };
+    let user_operation = UserOperation::default()
+        .sender(sender)
+        .nonce(U256::zero())
+        .init_code(harness.init_code(owner.address(), U256::zero()))
+        .call_data(call_data.encode().into())
+        .call_gas_limit(200_000.into())
+        .verification_gas_limit(1_000_000.into())
+        .pre_verification_gas(50_000.into())
+        .max_fee_per_gas(10_000_000_000u64.into())
+        .max_priority_fee_per_gas(1_000_000_000.into());
+    let user_operation = harness.sign(user_operation, &owner).await?;
+    let user_operation_hash = user_operation.hash(&harness.entry_point, &U256::from(CHAIN_ID));
+    let beneficiary_balance = harness
+        .provider
+        .get_balance(harness.beneficiary, None)
+        .await?;
+
+    // bundled right away, anvil mining handleOps on submission
+    let hash: UserOperationHash = harness
+        .client
+        .request(
+            "eth_sendUserOperation",
+            rpc_params![user_operation, harness.entry_point],
+        )
+        .await?;
+    assert_eq!(hash, user_operation_hash);
+
+    let receipt: Option<UserOperationReceipt> = harness
+        .client
+        .request("eth_getUserOperationReceipt", rpc_params![hash])
+        .await?;
+    let receipt = receipt.expect("handleOps emits UserOperationEvent");
+    assert_eq!(receipt.user_operation_hash, user_operation_hash);
+    assert_eq!(receipt.sender, sender);
+    assert!(receipt.success);
+    assert_eq!(receipt.tx_receipt.status, Some(1.into()));
+    assert_eq!(
+        receipt.tx_receipt.from,
+        harness.bundler.signers.addresses()[0]
+    );
+
+    // the account exists, holds the tokens bought and paid the beneficiary
+    assert!(!harness.provider.get_code(sender, None).await?.is_empty());
+    let token = weth::new(harness.token, harness.provider.clone());
+    assert_eq!(token.balance_of(sender).call().await?, amount * SWAP_RATE);
+    assert!(
+        harness
+            .provider
+            .get_balance(harness.beneficiary, None)
+            .await?
+            > beneficiary_balance
+    );
+    Ok(())
+}
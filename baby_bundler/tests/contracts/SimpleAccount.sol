@@ -0,0 +1,91 @@
+// SPDX-License-Identifier: MIT
+pragma solidity ^0.8.12;
+
+/// User operation of the v0.6 entry point
+struct UserOperation {
+    address sender;
+    uint256 nonce;
+    bytes initCode;
+    bytes callData;
+    uint256 callGasLimit;
+    uint256 verificationGasLimit;
+    uint256 preVerificationGas;
+    uint256 maxFeePerGas;
+    uint256 maxPriorityFeePerGas;
+    bytes paymasterAndData;
+    bytes signature;
+}
+
+/// Stripped down SimpleAccount: a single ECDSA owner signing the user
+/// operation hash as an Ethereum signed message, calls made by the entry
+/// point only
+contract SimpleAccount {
+    address public immutable entryPoint;
+    address public immutable owner;
+
+    constructor(address entryPoint_, address owner_) {
+        entryPoint = entryPoint_;
+        owner = owner_;
+    }
+
+    receive() external payable {}
+
+    function validateUserOp(UserOperation calldata userOp, bytes32 userOpHash, uint256 missingAccountFunds)
+        external
+        returns (uint256 validationData)
+    {
+        require(msg.sender == entryPoint, "account: not from entry point");
+        bytes32 hash = keccak256(abi.encodePacked("\x19Ethereum Signed Message:\n32", userOpHash));
+        if (recover(hash, userOp.signature) != owner) {
+            validationData = 1;
+        }
+        if (missingAccountFunds > 0) {
+            (bool success,) = payable(msg.sender).call{value: missingAccountFunds}("");
+            (success);
+        }
+    }
+
+    function execute(address dest, uint256 value, bytes calldata func) external {
+        require(msg.sender == entryPoint, "account: not from entry point");
+        (bool success, bytes memory result) = dest.call{value: value}(func);
+        if (!success) {
+            assembly {
+                revert(add(result, 32), mload(result))
+            }
+        }
+    }
+
+    function recover(bytes32 hash, bytes calldata signature) internal pure returns (address) {
+        if (signature.length != 65) {
+            return address(0);
+        }
+        bytes32 r = bytes32(signature[0:32]);
+        bytes32 s = bytes32(signature[32:64]);
+        uint8 v = uint8(signature[64]);
+        return ecrecover(hash, v, r, s);
+    }
+}
+
+/// Deploys SimpleAccounts with CREATE2 from the `initCode` of their first
+/// user operation
+contract SimpleAccountFactory {
+    address public immutable entryPoint;
+
+    constructor(address entryPoint_) {
+        entryPoint = entryPoint_;
+    }
+
+    function createAccount(address owner, uint256 salt) external returns (SimpleAccount) {
+        address account = getAddress(owner, salt);
+        if (account.code.length > 0) {
+            return SimpleAccount(payable(account));
+        }
+        return new SimpleAccount{salt: bytes32(salt)}(entryPoint, owner);
+    }
+
+    function getAddress(address owner, uint256 salt) public view returns (address) {
+        bytes32 codeHash =
+            keccak256(abi.encodePacked(type(SimpleAccount).creationCode, abi.encode(entryPoint, owner)));
+        return address(uint160(uint256(keccak256(abi.encodePacked(bytes1(0xff), address(this), bytes32(salt), codeHash)))));
+    }
+}
@@ -0,0 +1,81 @@
+// SPDX-License-Identifier: MIT
+pragma solidity ^0.8.12;
+
+/// ERC-20 anyone can mint, standing in for the tokens bought on Uniswap
+contract MockERC20 {
+    string public name;
+    string public symbol;
+    uint8 public constant decimals = 18;
+    uint256 public totalSupply;
+    mapping(address => uint256) public balanceOf;
+
+    event Transfer(address indexed src, address indexed dst, uint256 wad);
+
+    constructor(string memory name_, string memory symbol_) {
+        name = name_;
+        symbol = symbol_;
+    }
+
+    function mint(address dst, uint256 wad) external {
+        _mint(dst, wad);
+    }
+
+    function transfer(address dst, uint256 wad) external returns (bool) {
+        require(balanceOf[msg.sender] >= wad, "token: balance too low");
+        balanceOf[msg.sender] -= wad;
+        balanceOf[dst] += wad;
+        emit Transfer(msg.sender, dst, wad);
+        return true;
+    }
+
+    function _mint(address dst, uint256 wad) internal {
+        totalSupply += wad;
+        balanceOf[dst] += wad;
+        emit Transfer(address(0), dst, wad);
+    }
+}
+
+/// WETH9 without allowances
+contract MockWETH9 is MockERC20("Wrapped Ether", "WETH") {
+    function deposit() public payable {
+        _mint(msg.sender, msg.value);
+    }
+
+    function withdraw(uint256 wad) external {
+        require(balanceOf[msg.sender] >= wad, "weth: balance too low");
+        balanceOf[msg.sender] -= wad;
+        totalSupply -= wad;
+        payable(msg.sender).transfer(wad);
+    }
+
+    receive() external payable {
+        deposit();
+    }
+}
+
+/// UniswapV2Router02 selling any token for ETH at a fixed rate, the token
+/// being minted to the buyer
+contract MockUniswapV2Router {
+    address public immutable WETH;
+    uint256 public immutable rate;
+
+    constructor(address weth_, uint256 rate_) {
+        WETH = weth_;
+        rate = rate_;
+    }
+
+    function swapExactETHForTokens(uint256 amountOutMin, address[] calldata path, address to, uint256 deadline)
+        external
+        payable
+        returns (uint256[] memory amounts)
+    {
+        require(deadline >= block.timestamp, "router: expired");
+        require(path.length == 2 && path[0] == WETH, "router: invalid path");
+        amounts = new uint256[](2);
+        amounts[0] = msg.value;
+        amounts[1] = msg.value * rate;
+        require(amounts[1] >= amountOutMin, "router: insufficient output amount");
+        MockWETH9(payable(WETH)).deposit{value: msg.value}();
+        MockERC20(path[1]).mint(to, amounts[1]);
+    }
+}
@@ -0,0 +1,243 @@
+//! Local chain for the end-to-end tests: an anvil node with the entry point,
+//! a SimpleAccount factory and Uniswap mocks deployed, and the bundler
+//! serving JSON-RPC in-process on top of it, sending its bundles to anvil.
+//!
+//! Needs `anvil` and `solc` on the `PATH`, or `SOLC_PATH` pointing at solc.
+use aa_bundler_primitives::UserOperation;
+use baby_bundler::bindings::entrypointgoerli::entrypointgoerli;
+use baby_bundler::bundler::{
+    bundler::{BabyBundler, BundlerApiServer, EthApiServer, Relay},
+    server::JsonRpcServer,
+    signer::BundlerSigner,
+    signer_pool::SignerPool,
+};
+use ethers::{
+    abi::{AbiEncode, Tokenize},
+    contract::{abigen, ContractFactory},
+    middleware::SignerMiddleware,
+    prelude::LocalWallet,
+    providers::{Http, Middleware, Provider},
+    signers::Signer,
+    types::{Address, Bytes, TransactionRequest, U256},
+    utils::{Anvil, AnvilInstance},
+};
+use ethers_solc::{CompilerOutput, Solc};
+use jsonrpsee::{
+    http_client::{HttpClient, HttpClientBuilder},
+    server::ServerHandle,
+};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Chain the bundler signs for, anvil is started with the same id
+pub const CHAIN_ID: u64 = 80001;
+
+/// Tokens `MockUniswapV2Router` gives for one wei
+pub const SWAP_RATE: u64 = 2_000;
+
+abigen!(
+    SimpleAccountFactory,
+    r#"[
+        function createAccount(address owner, uint256 salt) returns (address)
+        function getAddress(address owner, uint256 salt) view returns (address)
+    ]"#
+);
+
+abigen!(
+    SimpleAccount,
+    r#"[
+        function execute(address dest, uint256 value, bytes func)
+    ]"#
+);
+
+/// Anvil account signing the deployments and funding the test accounts
+pub type Deployer = SignerMiddleware<Provider<Http>, LocalWallet>;
+
+pub struct Harness {
+    pub provider: Arc<Provider<Http>>,
+    pub deployer: Arc<Deployer>,
+    pub entry_point: Address,
+    pub factory: SimpleAccountFactory<Deployer>,
+    pub weth: Address,
+    pub token: Address,
+    pub router: Address,
+    /// Account the bundle fees are paid to
+    pub beneficiary: Address,
+    pub bundler: BabyBundler<Provider<Http>>,
+    /// Client of the bundler JSON-RPC server
+    pub client: HttpClient,
+    _server: ServerHandle,
+    _anvil: AnvilInstance,
+}
+
+impl Harness {
+    /// Starts anvil, deploys the contracts and boots the bundler. The first
+    /// anvil account deploys, the second one is the bundler EOA and the third
+    /// one the beneficiary.
+    pub async fn start() -> anyhow::Result<Self> {
+        let anvil = Anvil::new().chain_id(CHAIN_ID).spawn();
+        let provider =
+            Provider::<Http>::try_from(anvil.endpoint())?.interval(Duration::from_millis(10));
+        // the keys and the bundler sign for the chain the node reports
+        let chain_id = provider.get_chainid().await?.as_u64();
+        let wallet =
+            |index: usize| LocalWallet::from(anvil.keys()[index].clone()).with_chain_id(chain_id);
+        let deployer = Arc::new(SignerMiddleware::new(provider.clone(), wallet(0)));
+
+        let entry_point = entrypointgoerli::entrypointgoerli::deploy(deployer.clone(), ())?
+            .send()
+            .await?
+            .address();
+        let contracts = compile_contracts()?;
+        let factory = deploy(
+            &contracts,
+            "SimpleAccountFactory",
+            deployer.clone(),
+            entry_point,
+        )
+        .await?;
+        let weth = deploy(&contracts, "MockWETH9", deployer.clone(), ()).await?;
+        let token = deploy(
+            &contracts,
+            "MockERC20",
+            deployer.clone(),
+            ("Tether USD".to_string(), "USDT".to_string()),
+        )
+        .await?;
+        let router = deploy(
+            &contracts,
+            "MockUniswapV2Router",
+            deployer.clone(),
+            (weth, U256::from(SWAP_RATE)),
+        )
+        .await?;
+
+        let provider = Arc::new(provider);
+        let beneficiary = anvil.addresses()[2];
+        let signer: Arc<dyn BundlerSigner> = Arc::new(wallet(1));
+        let bundler = BabyBundler::new(
+            provider.clone(),
+            U256::max_value(),
+            U256::max_value(),
+            SignerPool::new(vec![signer])?,
+        )
+        .with_chain_id(chain_id.into())
+        .with_entry_point(entry_point)
+        .with_relay(Relay::Node)
+        .with_beneficiary(beneficiary);
+
+        let address = std::net::TcpListener::bind("127.0.0.1:0")?
+            .local_addr()?
+            .to_string();
+        let mut methods = EthApiServer::into_rpc(bundler.clone());
+        methods.merge(BundlerApiServer::into_rpc(bundler.clone()))?;
+        let server = JsonRpcServer::new(address.clone()).start(methods).await?;
+        let client = HttpClientBuilder::default().build(format!("http://{address}"))?;
+
+        Ok(Self {
+            provider,
+            entry_point,
+            factory: SimpleAccountFactory::new(factory, deployer.clone()),
+            deployer,
+            weth,
+            token,
+            router,
+            beneficiary,
+            bundler,
+            client,
+            _server: server,
+            _anvil: anvil,
+        })
+    }
+
+    /// Counterfactual address of the SimpleAccount of `owner`
+    pub async fn account_address(&self, owner: Address, salt: U256) -> anyhow::Result<Address> {
+        Ok(self.factory.get_address(owner, salt).call().await?)
+    }
+
+    /// `initCode` deploying the SimpleAccount of `owner` with the first user operation
+    pub fn init_code(&self, owner: Address, salt: U256) -> Bytes {
+        let mut init_code = self.factory.address().as_bytes().to_vec();
+        init_code.extend(CreateAccountCall { owner, salt }.encode());
+        init_code.into()
+    }
+
+    /// Sends `amount` wei from the deployer to `to`
+    pub async fn fund(&self, to: Address, amount: U256) -> anyhow::Result<()> {
+        self.deployer
+            .send_transaction(TransactionRequest::new().to(to).value(amount), None)
+            .await?
+            .await?;
+        Ok(())
+    }
+
+    /// Signs `user_operation` for the entry point with the key of the account owner
+    pub async fn sign(
+        &self,
+        mut user_operation: UserOperation,
+        owner: &LocalWallet,
+    ) -> anyhow::Result<UserOperation> {
+        let chain_id = U256::from(self.bundler.eth_chain_id.as_u64());
+        let hash = user_operation.hash(&self.entry_point, &chain_id);
+        let signature = owner.sign_message(hash.0.as_bytes()).await?;
+        user_operation.signature = signature.to_vec().into();
+        Ok(user_operation)
+    }
+}
+
+/// Environment variable skipping the end-to-end tests when `anvil` or `solc`
+/// is missing, which fail otherwise
+pub const SKIP_ENV: &str = "SKIP_ANVIL_TESTS";
+
+/// Whether the end-to-end tests run: `anvil` and `solc` must be on the
+/// `PATH` unless `SKIP_ANVIL_TESTS` is set, in which case they are skipped
+pub fn should_run() -> anyhow::Result<bool> {
+    if tools_available() {
+        return Ok(true);
+    }
+    if std::env::var_os(SKIP_ENV).is_some() {
+        eprintln!("Skipping the end-to-end test, anvil or solc is not on the PATH");
+        return Ok(false);
+    }
+    anyhow::bail!("anvil or solc is not on the PATH, set {SKIP_ENV} to skip the end-to-end tests")
+}
+
+/// Whether `anvil` and `solc` can be run
+fn tools_available() -> bool {
+    let runs = |program: &str| {
+        std::process::Command::new(program)
+            .arg("--version")
+            .output()
+            .is_ok_and(|output| output.status.success())
+    };
+    let solc = std::env::var("SOLC_PATH").unwrap_or_else(|_| "solc".to_string());
+    runs("anvil") && runs(&solc)
+}
+
+/// Compiles the SimpleAccount and Uniswap mocks of `tests/contracts`
+fn compile_contracts() -> anyhow::Result<CompilerOutput> {
+    let sources = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/contracts");
+    let output = Solc::default().compile_source(sources)?;
+    if output.has_error() {
+        anyhow::bail!("Failed to compile the contracts: {:?}", output.errors);
+    }
+    Ok(output)
+}
+
+async fn deploy<T: Tokenize>(
+    contracts: &CompilerOutput,
+    name: &str,
+    deployer: Arc<Deployer>,
+    constructor_args: T,
+) -> anyhow::Result<Address> {
+    let (abi, bytecode, _) = contracts
+        .find(name)
+        .ok_or_else(|| anyhow::anyhow!("Contract {name} not compiled"))?
+        .into_parts_or_default();
+    let contract = ContractFactory::new(abi, bytecode, deployer)
+        .deploy(constructor_args)?
+        .send()
+        .await?;
+    Ok(contract.address())
+}